@@ -0,0 +1,683 @@
+use crate::MoonlightError;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+/// Electron hashes files in blocks of this size for integrity checks.
+pub const ASAR_BLOCK_SIZE: usize = 4 * 1024 * 1024;
+/// How many links we follow before assuming they loop.
+const MAX_LINK_DEPTH: usize = 32;
+
+/// An entry in an asar's header. Directories hold more entries, files point
+/// into the data after the header (or into `<archive>.unpacked`).
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(untagged)]
+pub enum AsarNode {
+    Directory {
+        files: BTreeMap<String, AsarNode>,
+    },
+    File(AsarFile),
+    /// Relative to the root of the archive
+    Link {
+        link: String,
+    },
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct AsarFile {
+    pub size: u64,
+    /// Relative to the end of the header. Electron stores it as a string
+    /// because JS numbers can't hold all of a u64.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub offset: Option<String>,
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub unpacked: bool,
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub executable: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub integrity: Option<AsarIntegrity>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub struct AsarIntegrity {
+    pub algorithm: String,
+    pub hash: String,
+    pub block_size: u64,
+    pub blocks: Vec<String>,
+}
+
+impl AsarIntegrity {
+    #[must_use]
+    pub fn new(contents: &[u8]) -> Self {
+        let mut blocks: Vec<String> = contents.chunks(ASAR_BLOCK_SIZE).map(sha256_hex).collect();
+        if blocks.is_empty() {
+            blocks.push(sha256_hex(&[]));
+        }
+
+        Self {
+            algorithm: String::from("SHA256"),
+            hash: sha256_hex(contents),
+            block_size: ASAR_BLOCK_SIZE as u64,
+            blocks,
+        }
+    }
+}
+
+#[must_use]
+pub fn sha256_hex(contents: &[u8]) -> String {
+    Sha256::digest(contents)
+        .iter()
+        .map(|b| format!("{b:02x}"))
+        .collect()
+}
+
+/// Like [`sha256_hex`], without reading the whole file into memory.
+pub fn sha256_file(path: &Path) -> crate::Result<String> {
+    let mut hasher = Sha256::new();
+    std::io::copy(&mut File::open(path)?, &mut hasher)?;
+    Ok(hasher
+        .finalize()
+        .iter()
+        .map(|b| format!("{b:02x}"))
+        .collect())
+}
+
+/// Where the files an archive keeps outside of itself live.
+#[must_use]
+pub fn get_unpacked_dir(archive: &Path) -> PathBuf {
+    let mut name = archive.as_os_str().to_owned();
+    name.push(".unpacked");
+    PathBuf::from(name)
+}
+
+/// An asar archive opened for reading. Only the header is read up front.
+#[derive(Debug, Clone)]
+pub struct Asar {
+    path: PathBuf,
+    root: BTreeMap<String, AsarNode>,
+    data_offset: u64,
+}
+
+impl Asar {
+    pub fn open(path: &Path) -> crate::Result<Self> {
+        let invalid = |reason: &str| {
+            MoonlightError::Unknown(format!("invalid asar {}: {reason}", path.display()))
+        };
+
+        let mut file = File::open(path)?;
+        let len = file.metadata()?.len();
+
+        // A pickle holding the header pickle's size, then the header pickle
+        // holding its payload size and the header JSON as a string
+        let mut prefix = [0; 16];
+        file.read_exact(&mut prefix)
+            .map_err(|_| invalid("too short"))?;
+        let [size_len, header_size, _, json_len] = std::array::from_fn(|i| {
+            u32::from_le_bytes(prefix[i * 4..i * 4 + 4].try_into().unwrap())
+        });
+
+        if size_len != 4 {
+            return Err(invalid("bad size pickle"));
+        }
+        let data_offset = 8 + u64::from(header_size);
+        if u64::from(json_len) + 16 > data_offset || data_offset > len {
+            return Err(invalid("header doesn't fit"));
+        }
+
+        let mut json = vec![0; json_len as usize];
+        file.read_exact(&mut json)?;
+        let root = match serde_json::from_slice(&json) {
+            Ok(AsarNode::Directory { files }) => files,
+            Ok(_) => return Err(invalid("root isn't a directory")),
+            Err(e) => return Err(invalid(&e.to_string())),
+        };
+
+        Ok(Self {
+            path: path.to_path_buf(),
+            root,
+            data_offset,
+        })
+    }
+
+    #[must_use]
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// `path` is relative to the root of the archive, separated by `/`.
+    #[must_use]
+    pub fn get(&self, path: &str) -> Option<&AsarNode> {
+        let mut parts = split_path(path);
+        let mut node = self.root.get(parts.next()?)?;
+        for part in parts {
+            match node {
+                AsarNode::Directory { files } => node = files.get(part)?,
+                _ => return None,
+            }
+        }
+        Some(node)
+    }
+
+    #[must_use]
+    pub fn contains(&self, path: &str) -> bool {
+        self.get(path).is_some()
+    }
+
+    /// Every entry in the archive with its full path, parents first.
+    #[must_use]
+    pub fn entries(&self) -> Vec<(String, &AsarNode)> {
+        fn walk<'a>(
+            prefix: &str,
+            files: &'a BTreeMap<String, AsarNode>,
+            out: &mut Vec<(String, &'a AsarNode)>,
+        ) {
+            for (name, node) in files {
+                let path = if prefix.is_empty() {
+                    name.clone()
+                } else {
+                    format!("{prefix}/{name}")
+                };
+                out.push((path.clone(), node));
+                if let AsarNode::Directory { files } = node {
+                    walk(&path, files, out);
+                }
+            }
+        }
+
+        let mut entries = Vec::new();
+        walk("", &self.root, &mut entries);
+        entries
+    }
+
+    /// Reads a file out of the archive, following links.
+    pub fn read_file(&self, path: &str) -> crate::Result<Vec<u8>> {
+        let mut path = path.to_string();
+        for _ in 0..MAX_LINK_DEPTH {
+            match self.get(&path) {
+                Some(AsarNode::File(file)) => return self.read_entry(&path, file),
+                Some(AsarNode::Link { link }) => path.clone_from(link),
+                Some(AsarNode::Directory { .. }) => {
+                    return Err(MoonlightError::Unknown(format!("{path} is a directory")))
+                }
+                None => {
+                    return Err(MoonlightError::Unknown(format!(
+                        "{path} isn't in {}",
+                        self.path.display()
+                    )))
+                }
+            }
+        }
+
+        Err(MoonlightError::Unknown(format!("too many links at {path}")))
+    }
+
+    pub fn read_to_string(&self, path: &str) -> crate::Result<String> {
+        String::from_utf8(self.read_file(path)?)
+            .map_err(|_| MoonlightError::Unknown(format!("{path} isn't valid UTF-8")))
+    }
+
+    fn read_entry(&self, path: &str, file: &AsarFile) -> crate::Result<Vec<u8>> {
+        if file.unpacked {
+            let unpacked = safe_join(&get_unpacked_dir(&self.path), path)?;
+            return Ok(std::fs::read(unpacked)?);
+        }
+
+        let offset = file
+            .offset
+            .as_deref()
+            .and_then(|offset| offset.parse::<u64>().ok())
+            .ok_or_else(|| MoonlightError::Unknown(format!("{path} has no offset")))?;
+
+        // The header is whatever the archive says, so check it before
+        // allocating for it
+        let mut archive = File::open(&self.path)?;
+        let start = self.data_offset.checked_add(offset);
+        let fits = start
+            .and_then(|start| start.checked_add(file.size))
+            .is_some_and(|end| end <= archive.metadata().map_or(0, |m| m.len()));
+        let size = usize::try_from(file.size).ok().filter(|_| fits);
+        let (Some(start), Some(size)) = (start, size) else {
+            return Err(MoonlightError::Unknown(format!(
+                "{path} runs past the end of {}",
+                self.path.display()
+            )));
+        };
+
+        archive.seek(SeekFrom::Start(start))?;
+        let mut contents = vec![0; size];
+        archive.read_exact(&mut contents)?;
+        Ok(contents)
+    }
+
+    /// Writes everything in the archive out into `to`, which is created if
+    /// it doesn't exist yet.
+    pub fn extract(&self, to: &Path) -> crate::Result<()> {
+        std::fs::create_dir_all(to)?;
+
+        for (path, node) in self.entries() {
+            let dest = safe_join(to, &path)?;
+            match node {
+                AsarNode::Directory { .. } => std::fs::create_dir_all(&dest)?,
+                AsarNode::File(file) => {
+                    std::fs::write(&dest, self.read_entry(&path, file)?)?;
+                    #[cfg(unix)]
+                    if file.executable {
+                        use std::os::unix::fs::PermissionsExt;
+                        std::fs::set_permissions(&dest, std::fs::Permissions::from_mode(0o755))?;
+                    }
+                }
+                // Symlinks need special permissions on Windows, so copy there
+                AsarNode::Link { link } => {
+                    safe_join(to, link)?;
+                    #[cfg(unix)]
+                    {
+                        let depth = split_path(&path).count() - 1;
+                        let target = "../".repeat(depth) + link;
+                        std::os::unix::fs::symlink(target, &dest)?;
+                    }
+                    #[cfg(not(unix))]
+                    std::fs::write(&dest, self.read_file(link)?)?;
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+enum WriterNode {
+    Directory(BTreeMap<String, WriterNode>),
+    File {
+        contents: Vec<u8>,
+        executable: bool,
+        unpacked: bool,
+    },
+    Link(String),
+}
+
+/// Builds a new asar archive in memory, see [`AsarWriter::write`].
+#[derive(Default)]
+pub struct AsarWriter {
+    root: BTreeMap<String, WriterNode>,
+}
+
+impl AsarWriter {
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Starts from everything in `dir`, like `asar pack` would.
+    pub fn from_dir(dir: &Path) -> crate::Result<Self> {
+        let mut writer = Self::new();
+        writer.add_dir("", dir)?;
+        Ok(writer)
+    }
+
+    /// Copies `dir` into the archive under `prefix`.
+    pub fn add_dir(&mut self, prefix: &str, dir: &Path) -> crate::Result<()> {
+        self.insert_dir(prefix)?;
+
+        for entry in std::fs::read_dir(dir)? {
+            let entry = entry?;
+            let name = entry.file_name().to_string_lossy().into_owned();
+            let path = if prefix.is_empty() {
+                name
+            } else {
+                format!("{prefix}/{name}")
+            };
+
+            if entry.path().is_dir() {
+                self.add_dir(&path, &entry.path())?;
+            } else {
+                #[cfg(unix)]
+                let executable = {
+                    use std::os::unix::fs::PermissionsExt;
+                    entry.metadata()?.permissions().mode() & 0o111 != 0
+                };
+                #[cfg(not(unix))]
+                let executable = false;
+
+                // Links get flattened, they could point anywhere
+                self.insert_file(&path, std::fs::read(entry.path())?, executable)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    pub fn insert_dir(&mut self, path: &str) -> crate::Result<()> {
+        let mut files = &mut self.root;
+        for part in split_path(path) {
+            validate_name(part)?;
+            let node = files
+                .entry(part.to_string())
+                .or_insert_with(|| WriterNode::Directory(BTreeMap::new()));
+            match node {
+                WriterNode::Directory(children) => files = children,
+                _ => {
+                    return Err(MoonlightError::Unknown(format!(
+                        "{path} goes through something that isn't a directory"
+                    )))
+                }
+            }
+        }
+        Ok(())
+    }
+
+    pub fn insert_file(
+        &mut self,
+        path: &str,
+        contents: impl Into<Vec<u8>>,
+        executable: bool,
+    ) -> crate::Result<()> {
+        self.insert(
+            path,
+            WriterNode::File {
+                contents: contents.into(),
+                executable,
+                unpacked: false,
+            },
+        )
+    }
+
+    /// Puts the file in `<archive>.unpacked` instead, for things like native
+    /// modules that have to exist on disk.
+    pub fn insert_unpacked(
+        &mut self,
+        path: &str,
+        contents: impl Into<Vec<u8>>,
+        executable: bool,
+    ) -> crate::Result<()> {
+        self.insert(
+            path,
+            WriterNode::File {
+                contents: contents.into(),
+                executable,
+                unpacked: true,
+            },
+        )
+    }
+
+    /// `target` is relative to the root of the archive.
+    pub fn insert_link(&mut self, path: &str, target: &str) -> crate::Result<()> {
+        for part in split_path(target) {
+            validate_name(part)?;
+        }
+        self.insert(path, WriterNode::Link(target.to_string()))
+    }
+
+    fn insert(&mut self, path: &str, node: WriterNode) -> crate::Result<()> {
+        let path = path.trim_matches('/');
+        let (parent, name) = path.rsplit_once('/').unwrap_or(("", path));
+        validate_name(name)?;
+        self.insert_dir(parent)?;
+
+        let mut files = &mut self.root;
+        for part in split_path(parent) {
+            match files.get_mut(part) {
+                Some(WriterNode::Directory(children)) => files = children,
+                _ => unreachable!("insert_dir made all the parents"),
+            }
+        }
+        files.insert(name.to_string(), node);
+        Ok(())
+    }
+
+    /// Writes the archive to `path`, and any unpacked files next to it.
+    pub fn write(&self, path: &Path) -> crate::Result<()> {
+        let mut data = Vec::new();
+        let unpacked_dir = get_unpacked_dir(path);
+        let root = build_header(&self.root, &unpacked_dir, &mut data)?;
+
+        let json = serde_json::to_vec(&AsarNode::Directory { files: root })
+            .expect("serialization to succeed");
+        let padding = (4 - json.len() % 4) % 4;
+        let json_len = u32::try_from(json.len())
+            .map_err(|_| MoonlightError::Unknown(String::from("asar header too large")))?;
+        let payload_size = 4 + json_len + padding as u32;
+
+        let mut file = std::io::BufWriter::new(File::create(path)?);
+        for n in [4, 4 + payload_size, payload_size, json_len] {
+            file.write_all(&n.to_le_bytes())?;
+        }
+        file.write_all(&json)?;
+        file.write_all(&[0; 3][..padding])?;
+        file.write_all(&data)?;
+        file.flush()?;
+        Ok(())
+    }
+}
+
+fn build_header(
+    files: &BTreeMap<String, WriterNode>,
+    unpacked_dir: &Path,
+    data: &mut Vec<u8>,
+) -> crate::Result<BTreeMap<String, AsarNode>> {
+    let mut header = BTreeMap::new();
+
+    for (name, node) in files {
+        let node = match node {
+            WriterNode::Directory(children) => AsarNode::Directory {
+                files: build_header(children, &unpacked_dir.join(name), data)?,
+            },
+            WriterNode::File {
+                contents,
+                executable,
+                unpacked,
+            } => {
+                let offset = if *unpacked {
+                    std::fs::create_dir_all(unpacked_dir)?;
+                    std::fs::write(unpacked_dir.join(name), contents)?;
+                    None
+                } else {
+                    let offset = data.len();
+                    data.extend_from_slice(contents);
+                    Some(offset.to_string())
+                };
+
+                AsarNode::File(AsarFile {
+                    size: contents.len() as u64,
+                    offset,
+                    unpacked: *unpacked,
+                    executable: *executable,
+                    integrity: Some(AsarIntegrity::new(contents)),
+                })
+            }
+            WriterNode::Link(link) => AsarNode::Link { link: link.clone() },
+        };
+        header.insert(name.clone(), node);
+    }
+
+    Ok(header)
+}
+
+fn split_path(path: &str) -> impl Iterator<Item = &str> {
+    path.split(['/', '\\']).filter(|part| !part.is_empty())
+}
+
+// Entry names come from the archive, don't let them escape where we extract to
+fn validate_name(name: &str) -> crate::Result<()> {
+    if name.is_empty() || name == "." || name == ".." || name.contains(['/', '\\', '\0']) {
+        return Err(MoonlightError::Unknown(format!(
+            "invalid asar entry name {name:?}"
+        )));
+    }
+    Ok(())
+}
+
+fn safe_join(base: &Path, path: &str) -> crate::Result<PathBuf> {
+    split_path(path).try_fold(base.to_path_buf(), |dest, part| {
+        validate_name(part)?;
+        Ok(dest.join(part))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    fn sample() -> AsarWriter {
+        let mut asar = AsarWriter::new();
+        asar.insert_file("package.json", r#"{"main":"index.js"}"#, false)
+            .unwrap();
+        asar.insert_file("lib/index.js", "require('./util');", false)
+            .unwrap();
+        asar.insert_file("lib/run.sh", "#!/bin/sh\n", true).unwrap();
+        asar.insert_unpacked("lib/native.node", vec![0, 1, 2, 3], false)
+            .unwrap();
+        asar.insert_link("index.js", "lib/index.js").unwrap();
+        asar.insert_dir("empty").unwrap();
+        asar
+    }
+
+    // Just the header, for archives the writer wouldn't make
+    fn write_raw(path: &Path, header: &serde_json::Value, data: &[u8]) {
+        let json = serde_json::to_vec(header).unwrap();
+        let padding = (4 - json.len() % 4) % 4;
+        let payload_size = (4 + json.len() + padding) as u32;
+        let mut bytes = Vec::new();
+        for n in [4, 4 + payload_size, payload_size, json.len() as u32] {
+            bytes.extend_from_slice(&n.to_le_bytes());
+        }
+        bytes.extend_from_slice(&json);
+        bytes.extend_from_slice(&[0; 3][..padding]);
+        bytes.extend_from_slice(data);
+        std::fs::write(path, bytes).unwrap();
+    }
+
+    #[test]
+    fn round_trips() {
+        let tmp = TempDir::new().unwrap();
+        let path = tmp.path().join("app.asar");
+        sample().write(&path).unwrap();
+
+        let asar = Asar::open(&path).unwrap();
+        assert_eq!(
+            asar.read_to_string("package.json").unwrap(),
+            r#"{"main":"index.js"}"#
+        );
+        assert_eq!(
+            asar.read_to_string("index.js").unwrap(),
+            "require('./util');"
+        );
+        assert_eq!(asar.read_file("lib/native.node").unwrap(), [0, 1, 2, 3]);
+        assert!(asar.contains("empty"));
+        assert!(asar.read_file("lib").is_err());
+        assert!(asar.read_file("missing.js").is_err());
+
+        let paths: Vec<_> = asar.entries().into_iter().map(|(path, _)| path).collect();
+        assert_eq!(
+            paths,
+            [
+                "empty",
+                "index.js",
+                "lib",
+                "lib/index.js",
+                "lib/native.node",
+                "lib/run.sh",
+                "package.json"
+            ]
+        );
+
+        let out = tmp.path().join("out");
+        asar.extract(&out).unwrap();
+        assert_eq!(
+            std::fs::read_to_string(out.join("lib/index.js")).unwrap(),
+            "require('./util');"
+        );
+        assert_eq!(
+            std::fs::read_to_string(out.join("index.js")).unwrap(),
+            "require('./util');"
+        );
+        assert_eq!(
+            std::fs::read(out.join("lib/native.node")).unwrap(),
+            [0, 1, 2, 3]
+        );
+        assert!(out.join("empty").is_dir());
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = std::fs::metadata(out.join("lib/run.sh"))
+                .unwrap()
+                .permissions()
+                .mode();
+            assert_eq!(mode & 0o111, 0o111);
+            assert!(std::fs::symlink_metadata(out.join("index.js"))
+                .unwrap()
+                .is_symlink());
+        }
+    }
+
+    #[test]
+    fn records_integrity() {
+        let tmp = TempDir::new().unwrap();
+        let path = tmp.path().join("app.asar");
+        sample().write(&path).unwrap();
+
+        let asar = Asar::open(&path).unwrap();
+        for (path, node) in asar.entries() {
+            let AsarNode::File(file) = node else {
+                continue;
+            };
+            let contents = asar.read_file(&path).unwrap();
+            let integrity = file.integrity.as_ref().unwrap();
+            assert_eq!(integrity.hash, sha256_hex(&contents), "{path}");
+            assert_eq!(integrity.blocks, [sha256_hex(&contents)], "{path}");
+            assert_eq!(file.size, contents.len() as u64);
+        }
+
+        let big = vec![7; ASAR_BLOCK_SIZE + 1];
+        assert_eq!(AsarIntegrity::new(&big).blocks.len(), 2);
+        assert_eq!(AsarIntegrity::new(&[]).blocks, [sha256_hex(&[])]);
+    }
+
+    #[test]
+    fn rejects_entries_past_the_end() {
+        let tmp = TempDir::new().unwrap();
+        let path = tmp.path().join("app.asar");
+
+        for (size, offset) in [
+            (u64::MAX, "0"),
+            (1 << 40, "0"),
+            (4, "2"),
+            (1, "18446744073709551615"),
+        ] {
+            let header = serde_json::json!({
+                "files": { "a.js": { "size": size, "offset": offset } }
+            });
+            write_raw(&path, &header, b"abcd");
+            let asar = Asar::open(&path).unwrap();
+            assert!(asar.read_file("a.js").is_err(), "{size} at {offset}");
+            assert!(asar.extract(&tmp.path().join("out")).is_err());
+        }
+
+        let header = serde_json::json!({
+            "files": { "a.js": { "size": 2, "offset": "2" } }
+        });
+        write_raw(&path, &header, b"abcd");
+        assert_eq!(Asar::open(&path).unwrap().read_file("a.js").unwrap(), b"cd");
+    }
+
+    #[test]
+    fn rejects_escaping_names() {
+        let tmp = TempDir::new().unwrap();
+        let path = tmp.path().join("app.asar");
+        let header = serde_json::json!({
+            "files": { "..": { "files": { "evil.js": { "size": 1, "offset": "0" } } } }
+        });
+        write_raw(&path, &header, b"x");
+
+        let asar = Asar::open(&path).unwrap();
+        assert!(asar.extract(&tmp.path().join("out")).is_err());
+        assert!(!tmp.path().join("evil.js").exists());
+        assert!(AsarWriter::new()
+            .insert_file("../evil.js", "x", false)
+            .is_err());
+    }
+}
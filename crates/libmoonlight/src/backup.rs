@@ -0,0 +1,66 @@
+use crate::types::InstallId;
+use crate::{get_moonlight_dir, sha256_file};
+use std::path::{Path, PathBuf};
+
+/// Copies of Discord's original app.asar, in the moonlight dir.
+pub const ASAR_BACKUPS: &str = "backups";
+
+/// Copies of the original app.asar of patched installs, one folder per
+/// install holding `<sha256>.asar`, so a copy can always be checked.
+pub struct AsarBackups;
+
+impl AsarBackups {
+    #[must_use]
+    pub fn dir(id: &InstallId) -> PathBuf {
+        get_moonlight_dir().join(ASAR_BACKUPS).join(id.to_string())
+    }
+
+    #[must_use]
+    pub fn path(id: &InstallId, sha256: &str) -> PathBuf {
+        Self::dir(id).join(format!("{sha256}.asar"))
+    }
+
+    /// Whether anything was ever backed up for the install.
+    #[must_use]
+    pub fn exists(id: &InstallId) -> bool {
+        std::fs::read_dir(Self::dir(id)).is_ok_and(|mut entries| entries.next().is_some())
+    }
+
+    /// The backup with `sha256`, if there is one and it's still intact.
+    #[must_use]
+    pub fn find(id: &InstallId, sha256: &str) -> Option<PathBuf> {
+        let path = Self::path(id, sha256);
+        sha256_file(&path)
+            .is_ok_and(|hash| hash == sha256)
+            .then_some(path)
+    }
+
+    /// Copies `asar` in, replacing older backups for the install.
+    pub fn save(id: &InstallId, asar: &Path, sha256: &str) -> crate::Result<PathBuf> {
+        let dir = Self::dir(id);
+        let path = Self::path(id, sha256);
+        std::fs::create_dir_all(&dir)?;
+
+        if Self::find(id, sha256).is_none() {
+            // Don't leave a half copied backup under the real name
+            let partial = path.with_extension("partial");
+            std::fs::copy(asar, &partial)?;
+            if sha256_file(&partial)? != sha256 {
+                std::fs::remove_file(&partial).ok();
+                return Err(crate::MoonlightError::Unknown(format!(
+                    "{} changed while backing it up",
+                    asar.display()
+                )));
+            }
+            std::fs::rename(&partial, &path)?;
+        }
+
+        for entry in std::fs::read_dir(&dir)?.filter_map(Result::ok) {
+            if entry.path() != path {
+                std::fs::remove_file(entry.path()).ok();
+            }
+        }
+
+        Ok(path)
+    }
+}
@@ -0,0 +1,471 @@
+use crate::types::{Branch, DetectedInstall};
+use std::collections::HashMap;
+use std::ffi::OsString;
+use std::fs::DirEntry;
+use std::path::{Path, PathBuf};
+
+/// Everything a detector is allowed to look at. The default comes from the
+/// running system, tests can point it at a temporary directory instead.
+#[derive(Debug, Clone)]
+pub struct DetectorEnv {
+    /// Prepended to absolute system paths like `/Applications`
+    pub root: PathBuf,
+    pub home: PathBuf,
+    pub vars: HashMap<String, OsString>,
+}
+
+impl DetectorEnv {
+    #[must_use]
+    pub fn current() -> Self {
+        #[cfg(unix)]
+        let home = crate::get_home_dir();
+        #[cfg(not(unix))]
+        let home = std::env::home_dir().unwrap_or_default();
+
+        let mut vars: HashMap<String, OsString> = std::env::vars_os()
+            .filter_map(|(k, v)| Some((k.into_string().ok()?, v)))
+            .collect();
+        // A sandboxed installer's own XDG dirs aren't where Discord is
+        for key in ["XDG_CONFIG_HOME", "XDG_DATA_HOME"] {
+            match crate::get_host_var(key) {
+                Some(value) => vars.insert(key.to_owned(), value),
+                None => vars.remove(key),
+            };
+        }
+
+        Self {
+            root: PathBuf::from("/"),
+            home,
+            vars,
+        }
+    }
+
+    #[must_use]
+    pub fn new(root: PathBuf, home: PathBuf) -> Self {
+        Self {
+            root,
+            home,
+            vars: HashMap::new(),
+        }
+    }
+
+    #[must_use]
+    pub fn with_var(mut self, key: &str, value: impl Into<OsString>) -> Self {
+        self.vars.insert(key.to_owned(), value.into());
+        self
+    }
+
+    pub fn var(&self, key: &str) -> Option<PathBuf> {
+        // Windows env vars are case insensitive
+        self.vars
+            .get(key)
+            .or_else(|| {
+                self.vars
+                    .iter()
+                    .find(|(k, _)| k.eq_ignore_ascii_case(key))
+                    .map(|(_, v)| v)
+            })
+            .map(PathBuf::from)
+    }
+
+    pub fn rooted(&self, path: &Path) -> PathBuf {
+        self.root.join(path.strip_prefix("/").unwrap_or(path))
+    }
+
+    pub fn local_share(&self) -> PathBuf {
+        self.var("MOONLIGHT_DISCORD_SHARE_LINUX")
+            .or_else(|| self.var("XDG_DATA_HOME"))
+            .unwrap_or_else(|| self.local_share_workaround())
+    }
+
+    // https://github.com/flatpak/flatpak/pull/6084
+    pub fn local_share_workaround(&self) -> PathBuf {
+        self.home.join(".local").join("share")
+    }
+
+    pub fn dot_config(&self) -> PathBuf {
+        self.var("XDG_CONFIG_HOME")
+            .unwrap_or_else(|| self.home.join(".config"))
+    }
+}
+
+pub trait InstallDetector {
+    fn detect(&self, env: &DetectorEnv) -> crate::Result<Vec<DetectedInstall>>;
+}
+
+/// The detector for the platform we're running on.
+#[must_use]
+pub fn platform_detector() -> Box<dyn InstallDetector> {
+    match std::env::consts::OS {
+        "windows" => Box::new(WindowsDetector),
+        "macos" => Box::new(MacOSDetector),
+        "linux" => Box::new(LinuxDetector),
+        _ => Box::new(NoopDetector),
+    }
+}
+
+pub struct WindowsDetector;
+
+impl InstallDetector for WindowsDetector {
+    fn detect(&self, env: &DetectorEnv) -> crate::Result<Vec<DetectedInstall>> {
+        let Some(appdata) = env.var("LocalAppData") else {
+            return Ok(Vec::new());
+        };
+        let dirs = [
+            ("Discord", Branch::Stable),
+            ("DiscordPTB", Branch::PTB),
+            ("DiscordCanary", Branch::Canary),
+            ("DiscordDevelopment", Branch::Development),
+        ];
+        let mut installs = vec![];
+
+        for (dir, branch) in dirs {
+            let path = appdata.join(dir);
+            if let Some(most_recent_install) = most_recent_app_dir(&path)? {
+                installs.push(DetectedInstall::new(branch, most_recent_install, None));
+            }
+        }
+
+        Ok(installs)
+    }
+}
+
+pub struct MacOSDetector;
+
+impl InstallDetector for MacOSDetector {
+    fn detect(&self, env: &DetectorEnv) -> crate::Result<Vec<DetectedInstall>> {
+        let apps_dirs = vec![
+            env.rooted(Path::new("/Applications")),
+            env.home.join("Applications"),
+        ];
+
+        let branches = [
+            ("Discord", Branch::Stable),
+            ("Discord PTB", Branch::PTB),
+            ("Discord Canary", Branch::Canary),
+            ("Discord Development", Branch::Development),
+        ];
+
+        let mut installs = vec![];
+
+        for apps_dir in apps_dirs {
+            for (branch_name, branch) in branches {
+                let macos_app_dir = apps_dir.join(format!("{branch_name}.app"));
+
+                if !macos_app_dir.exists() {
+                    continue;
+                }
+
+                let app_dir = macos_app_dir.join("Contents/Resources");
+
+                installs.push(DetectedInstall::new(branch, app_dir, None));
+            }
+        }
+
+        Ok(installs)
+    }
+}
+
+pub struct LinuxDetector;
+
+impl InstallDetector for LinuxDetector {
+    fn detect(&self, env: &DetectorEnv) -> crate::Result<Vec<DetectedInstall>> {
+        // this is a crime but it has to be done...
+        // please merge pr flatpak devs
+        let local_shares = [env.local_share(), env.local_share_workaround()];
+        let local_share_dirs = [
+            ("Discord", Branch::Stable, None),
+            ("DiscordPTB", Branch::PTB, None),
+            ("DiscordCanary", Branch::Canary, None),
+            ("DiscordDevelopment", Branch::Development, None),
+            // flatpak user installations
+            (
+                "flatpak/app/com.discordapp.Discord/current/active/files/discord",
+                Branch::Stable,
+                Some("com.discordapp.Discord"),
+            ),
+            (
+                "flatpak/app/com.discordapp.DiscordCanary/current/active/files/discord-canary",
+                Branch::Canary,
+                Some("com.discordapp.DiscordCanary"),
+            ),
+        ];
+
+        let mut installs = vec![];
+        for (dir, branch, id) in local_share_dirs {
+            for local_share in &local_shares {
+                let path = local_share.join(dir);
+                if path.join(branch.name()).exists() && path.join("resources").exists() {
+                    installs.push(DetectedInstall::new(branch, path, id.map(Into::into)));
+                    break;
+                }
+            }
+        }
+
+        // Handle the new updater, which lives in ~/.config
+        for (path, branch, flatpak_id) in Self::updater_dirs(env) {
+            if let Some(most_recent_install) = most_recent_app_dir(&path)? {
+                installs.push(DetectedInstall::new(
+                    branch,
+                    most_recent_install,
+                    flatpak_id.map(ToOwned::to_owned),
+                ));
+            }
+        }
+
+        Ok(installs)
+    }
+}
+
+impl LinuxDetector {
+    /// Where the new updater keeps its app-(version) folders, whether or not
+    /// they exist yet.
+    #[must_use]
+    pub fn updater_dirs(env: &DetectorEnv) -> Vec<(PathBuf, Branch, Option<&'static str>)> {
+        let dot_configs = [
+            (env.dot_config(), None),
+            (
+                env.home.join(".var/app/com.discordapp.Discord/config"),
+                Some("com.discordapp.Discord"),
+            ),
+            (
+                env.home
+                    .join(".var/app/com.discordapp.DiscordCanary/config"),
+                Some("com.discordapp.DiscordCanary"),
+            ),
+        ];
+        let dot_config_dirs = [
+            ("discord", Branch::Stable),
+            ("discordptb", Branch::PTB),
+            ("discordcanary", Branch::Canary),
+            ("discorddevelopment", Branch::Development),
+        ];
+
+        let mut dirs = vec![];
+        for (dir, branch) in dot_config_dirs {
+            for (dot_config, flatpak_id) in &dot_configs {
+                dirs.push((dot_config.join(dir), branch, *flatpak_id));
+            }
+        }
+        dirs
+    }
+}
+
+pub struct NoopDetector;
+
+impl InstallDetector for NoopDetector {
+    fn detect(&self, _env: &DetectorEnv) -> crate::Result<Vec<DetectedInstall>> {
+        Ok(Vec::new())
+    }
+}
+
+// app-(version)
+fn most_recent_app_dir(path: &Path) -> crate::Result<Option<PathBuf>> {
+    if !path.exists() {
+        return Ok(None);
+    }
+
+    let mut app_dirs: Vec<_> = std::fs::read_dir(path)?
+        .filter_map(Result::ok)
+        .filter(|x| x.file_name().to_string_lossy().starts_with("app-"))
+        .collect();
+
+    app_dirs.sort_by_key(DirEntry::file_name);
+
+    Ok(app_dirs.last().map(DirEntry::path))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{detect_install, detect_install_type, get_app_dir, BUILD_INFO};
+    use tempfile::TempDir;
+
+    fn touch(path: &Path) {
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        std::fs::write(path, "").unwrap();
+    }
+
+    fn paths(installs: &[DetectedInstall]) -> Vec<(Branch, PathBuf, Option<&str>)> {
+        installs
+            .iter()
+            .map(|i| (i.branch, i.path.clone(), i.flatpak_id.as_deref()))
+            .collect()
+    }
+
+    #[test]
+    fn windows_picks_newest_app_dir() {
+        let tmp = TempDir::new().unwrap();
+        let appdata = tmp.path().join("AppData/Local");
+        std::fs::create_dir_all(appdata.join("Discord/app-1.0.9001")).unwrap();
+        std::fs::create_dir_all(appdata.join("Discord/app-1.0.9010")).unwrap();
+        std::fs::create_dir_all(appdata.join("Discord/packages")).unwrap();
+        std::fs::create_dir_all(appdata.join("DiscordCanary/app-1.0.50")).unwrap();
+        // No app-* folders yet, so nothing to patch
+        std::fs::create_dir_all(appdata.join("DiscordPTB")).unwrap();
+
+        let env = DetectorEnv::new(tmp.path().to_path_buf(), tmp.path().join("home"))
+            .with_var("LOCALAPPDATA", &appdata);
+        let installs = WindowsDetector.detect(&env).unwrap();
+
+        assert_eq!(
+            paths(&installs),
+            [
+                (Branch::Stable, appdata.join("Discord/app-1.0.9010"), None),
+                (
+                    Branch::Canary,
+                    appdata.join("DiscordCanary/app-1.0.50"),
+                    None
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn windows_without_local_appdata() {
+        let tmp = TempDir::new().unwrap();
+        let env = DetectorEnv::new(tmp.path().to_path_buf(), tmp.path().to_path_buf());
+        assert!(WindowsDetector.detect(&env).unwrap().is_empty());
+    }
+
+    #[test]
+    fn macos_system_and_user_applications() {
+        let tmp = TempDir::new().unwrap();
+        let home = tmp.path().join("Users/me");
+        std::fs::create_dir_all(tmp.path().join("Applications/Discord.app")).unwrap();
+        std::fs::create_dir_all(home.join("Applications/Discord PTB.app")).unwrap();
+
+        let env = DetectorEnv::new(tmp.path().to_path_buf(), home.clone());
+        let installs = MacOSDetector.detect(&env).unwrap();
+
+        assert_eq!(
+            paths(&installs),
+            [
+                (
+                    Branch::Stable,
+                    tmp.path()
+                        .join("Applications/Discord.app/Contents/Resources"),
+                    None
+                ),
+                (
+                    Branch::PTB,
+                    home.join("Applications/Discord PTB.app/Contents/Resources"),
+                    None
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn linux_local_share_and_flatpak() {
+        let tmp = TempDir::new().unwrap();
+        let home = tmp.path().join("home");
+        let data = tmp.path().join("data");
+        touch(&data.join("DiscordPTB/DiscordPTB"));
+        std::fs::create_dir_all(data.join("DiscordPTB/resources")).unwrap();
+        // Missing its resources, so not an install
+        touch(&data.join("DiscordCanary/DiscordCanary"));
+        // Only found through the workaround, XDG_DATA_HOME points elsewhere
+        let flatpak = home
+            .join(".local/share/flatpak/app/com.discordapp.Discord/current/active/files/discord");
+        touch(&flatpak.join("Discord"));
+        std::fs::create_dir_all(flatpak.join("resources")).unwrap();
+
+        let env = DetectorEnv::new(tmp.path().to_path_buf(), home).with_var("XDG_DATA_HOME", &data);
+        let installs = LinuxDetector.detect(&env).unwrap();
+
+        assert_eq!(
+            paths(&installs),
+            [
+                (Branch::PTB, data.join("DiscordPTB"), None),
+                (Branch::Stable, flatpak, Some("com.discordapp.Discord")),
+            ]
+        );
+    }
+
+    #[test]
+    fn linux_updater_layouts() {
+        let tmp = TempDir::new().unwrap();
+        let home = tmp.path().join("home");
+        std::fs::create_dir_all(home.join(".config/discord/app-0.0.89")).unwrap();
+        std::fs::create_dir_all(home.join(".config/discord/app-0.0.90")).unwrap();
+        let flatpak_canary =
+            home.join(".var/app/com.discordapp.DiscordCanary/config/discordcanary");
+        std::fs::create_dir_all(flatpak_canary.join("app-0.0.600")).unwrap();
+
+        let env = DetectorEnv::new(tmp.path().to_path_buf(), home.clone());
+        let installs = LinuxDetector.detect(&env).unwrap();
+
+        assert_eq!(
+            paths(&installs),
+            [
+                (
+                    Branch::Stable,
+                    home.join(".config/discord/app-0.0.90"),
+                    None
+                ),
+                (
+                    Branch::Canary,
+                    flatpak_canary.join("app-0.0.600"),
+                    Some("com.discordapp.DiscordCanary")
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn linux_updater_follows_xdg_config_home() {
+        let tmp = TempDir::new().unwrap();
+        let config = tmp.path().join("config");
+        std::fs::create_dir_all(config.join("discorddevelopment/app-0.0.1")).unwrap();
+        // Not looked at once XDG_CONFIG_HOME is set
+        std::fs::create_dir_all(tmp.path().join("home/.config/discord/app-0.0.90")).unwrap();
+
+        let env = DetectorEnv::new(tmp.path().to_path_buf(), tmp.path().join("home"))
+            .with_var("XDG_CONFIG_HOME", &config);
+        let installs = LinuxDetector.detect(&env).unwrap();
+
+        assert_eq!(
+            paths(&installs),
+            [(
+                Branch::Development,
+                config.join("discorddevelopment/app-0.0.1"),
+                None
+            )]
+        );
+    }
+
+    #[test]
+    fn build_info_beats_the_file_name() {
+        let tmp = TempDir::new().unwrap();
+        let exe = tmp.path().join("DiscordPTB/DiscordPTB");
+        let app_dir = get_app_dir(exe.parent().unwrap()).unwrap();
+        touch(&exe);
+        touch(&app_dir.join("app.asar"));
+        std::fs::write(
+            app_dir.join(BUILD_INFO),
+            r#"{"releaseChannel":"canary","version":"0.0.600"}"#,
+        )
+        .unwrap();
+
+        assert_eq!(detect_install_type(&exe), Some(Branch::Canary));
+        let info = detect_install(&exe).unwrap();
+        assert_eq!(info.install.branch, Branch::Canary);
+        assert_eq!(info.discord_version.as_deref(), Some("0.0.600"));
+
+        // Without it, the name's all there is to go on
+        std::fs::remove_file(app_dir.join(BUILD_INFO)).unwrap();
+        assert_eq!(detect_install_type(&exe), Some(Branch::PTB));
+        assert_eq!(detect_install(&exe).unwrap().discord_version, None);
+    }
+
+    #[test]
+    fn empty_fixtures_find_nothing() {
+        let tmp = TempDir::new().unwrap();
+        let env = DetectorEnv::new(tmp.path().to_path_buf(), tmp.path().to_path_buf());
+
+        assert!(LinuxDetector.detect(&env).unwrap().is_empty());
+        assert!(MacOSDetector.detect(&env).unwrap().is_empty());
+        assert!(NoopDetector.detect(&env).unwrap().is_empty());
+    }
+}
@@ -6,6 +6,14 @@ pub enum MoonlightError {
     MacOSNoPermission(String),
     #[error("network request failed: {0}")]
     NetworkFailed(String),
+    #[error("install is in a broken state: {0}")]
+    BrokenInstall(String),
+    #[error("permission denied: {0}")]
+    PermissionDenied(String),
+    #[error("the installer's Flatpak is missing permissions: {0}")]
+    SandboxPermission(String),
+    #[error("no install with id {0}")]
+    InstallNotFound(String),
     #[error("unknown error: {0}")]
     Unknown(String),
 }
@@ -17,6 +25,8 @@ impl From<std::io::Error> for MoonlightError {
         match (value.raw_os_error(), std::env::consts::OS) {
             (Some(32), "windows") => Self::WindowsFileLock(value.to_string()),
             (Some(1), "macos") => Self::MacOSNoPermission(value.to_string()),
+            // EPERM and EACCES, usually a system install only root can touch
+            (Some(1 | 13), "linux") => Self::PermissionDenied(value.to_string()),
             _ => Self::Unknown(value.to_string()),
         }
     }
@@ -0,0 +1,497 @@
+use crate::types::{DetectedInstall, PatchState, PatchStrategy};
+use crate::{
+    detect_patch_state, get_app_dir, get_self_command, host_command, Installer, JournalEntry,
+    MoonlightError, BUILD_INFO, PATCHED_ASAR,
+};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use std::io::{BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::process::{Child, ChildStdin, ChildStdout, Command, Stdio};
+
+/// Starts a binary as the privileged helper instead of whatever it usually is.
+pub const HELPER_ARG: &str = "--privileged-helper";
+/// Bump when requests or responses change shape.
+pub const HELPER_PROTOCOL_VERSION: u32 = 2;
+
+/// What the privileged helper will do. Nothing else can be asked of it.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(tag = "op", rename_all = "snake_case")]
+pub enum HelperOp {
+    /// Unpatches first if moonlight or Vencord is already there
+    Patch {
+        install: DetectedInstall,
+        dist: Option<PathBuf>,
+        strategy: PatchStrategy,
+    },
+    Unpatch {
+        install: DetectedInstall,
+    },
+    Repair {
+        install: DetectedInstall,
+        dist: Option<PathBuf>,
+    },
+    Refresh {
+        install: DetectedInstall,
+        dist: Option<PathBuf>,
+    },
+}
+
+impl HelperOp {
+    #[must_use]
+    pub const fn install(&self) -> &DetectedInstall {
+        match self {
+            Self::Patch { install, .. }
+            | Self::Unpatch { install }
+            | Self::Repair { install, .. }
+            | Self::Refresh { install, .. } => install,
+        }
+    }
+
+    /// Whether the install ends up patched, if that changes.
+    #[must_use]
+    pub const fn patched(&self) -> Option<bool> {
+        match self {
+            Self::Patch { .. } | Self::Repair { .. } => Some(true),
+            Self::Unpatch { .. } => Some(false),
+            Self::Refresh { .. } => None,
+        }
+    }
+
+    fn dist(&self) -> Option<&Path> {
+        match self {
+            Self::Patch { dist, .. } | Self::Repair { dist, .. } | Self::Refresh { dist, .. } => {
+                dist.as_deref()
+            }
+            Self::Unpatch { .. } => None,
+        }
+    }
+}
+
+/// One line of JSON on the helper's stdin. It answers each with a line
+/// holding a `Result<Vec<JournalEntry>, MoonlightError>`, what it did for
+/// the user's journal. It doesn't write into the user's moonlight dir.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct HelperRequest {
+    /// The user's `MOONLIGHT_DIR`, for the stub to record like it would
+    /// without the helper
+    pub custom_moonlight_dir: Option<PathBuf>,
+    pub op: HelperOp,
+}
+
+// The helper's first line, so a cancelled password prompt doesn't look
+// like a broken helper
+#[derive(Serialize, Deserialize, Debug)]
+struct HelperHello {
+    version: u32,
+}
+
+/// Talks to a running privileged helper. Dropping it lets the helper exit.
+pub struct PrivilegedHelper {
+    child: Child,
+    input: Option<ChildStdin>,
+    output: BufReader<ChildStdout>,
+}
+
+impl PrivilegedHelper {
+    /// Starts this binary as the helper through pkexec, or sudo outside of
+    /// a graphical session. `MOONLIGHT_ELEVATE` picks something else, and an
+    /// empty one runs the helper without any extra privileges. Either runs
+    /// on the host when we're sandboxed.
+    pub fn launch() -> crate::Result<Self> {
+        let elevate = std::env::var("MOONLIGHT_ELEVATE").unwrap_or_else(|_| default_elevate());
+        Self::spawn(launch_command(&elevate)?)
+    }
+
+    /// Starts `command`, which has to end up running [`serve_helper`].
+    pub fn spawn(mut command: Command) -> crate::Result<Self> {
+        let mut child = command
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn()?;
+        let input = child.stdin.take().expect("stdin to be piped");
+        let output = BufReader::new(child.stdout.take().expect("stdout to be piped"));
+
+        let mut helper = Self {
+            child,
+            input: Some(input),
+            output,
+        };
+        let hello: HelperHello = helper.read()?;
+        if hello.version != HELPER_PROTOCOL_VERSION {
+            return Err(MoonlightError::Unknown(format!(
+                "privileged helper speaks version {}, expected {HELPER_PROTOCOL_VERSION}",
+                hello.version
+            )));
+        }
+
+        Ok(helper)
+    }
+
+    pub fn run(&mut self, request: &HelperRequest) -> crate::Result<Vec<JournalEntry>> {
+        let input = self.input.as_mut().expect("stdin to be open until drop");
+        let json = serde_json::to_string(request).expect("serialization to succeed");
+        writeln!(input, "{json}")?;
+        input.flush()?;
+
+        self.read::<crate::Result<Vec<JournalEntry>>>()?
+    }
+
+    fn read<T: DeserializeOwned>(&mut self) -> crate::Result<T> {
+        let mut line = String::new();
+        if self.output.read_line(&mut line)? == 0 {
+            return Err(MoonlightError::Unknown(String::from(
+                "the privileged helper exited, was the password prompt cancelled?",
+            )));
+        }
+
+        serde_json::from_str(&line).map_err(|e| {
+            MoonlightError::Unknown(format!("bad response from the privileged helper: {e}"))
+        })
+    }
+}
+
+impl Drop for PrivilegedHelper {
+    fn drop(&mut self) {
+        // Closing stdin is what tells it to exit
+        self.input.take();
+        let _ = self.child.wait();
+    }
+}
+
+// This binary as the helper, run through `elevate` unless it's empty
+fn launch_command(elevate: &str) -> crate::Result<Command> {
+    let mut exe = get_self_command()?.into_iter();
+    let program = exe.next().expect("a program to run");
+
+    let mut command = if elevate.is_empty() {
+        host_command(program)
+    } else {
+        let mut command = host_command(elevate);
+        command.arg(program);
+        command
+    };
+    command.args(exe).arg(HELPER_ARG);
+    Ok(command)
+}
+
+fn default_elevate() -> String {
+    let graphical =
+        std::env::var_os("DISPLAY").is_some() || std::env::var_os("WAYLAND_DISPLAY").is_some();
+    let has_pkexec = std::env::var_os("PATH")
+        .is_some_and(|path| std::env::split_paths(&path).any(|dir| dir.join("pkexec").exists()));
+
+    if graphical && has_pkexec {
+        String::from("pkexec")
+    } else {
+        String::from("sudo")
+    }
+}
+
+/// Serves requests on stdin until it's closed. What the binaries do when
+/// started with [`HELPER_ARG`].
+pub fn serve_privileged_helper() -> crate::Result<()> {
+    serve_helper(std::io::stdin().lock(), std::io::stdout().lock())
+}
+
+pub fn serve_helper(input: impl BufRead, mut output: impl Write) -> crate::Result<()> {
+    let hello = HelperHello {
+        version: HELPER_PROTOCOL_VERSION,
+    };
+    writeln!(
+        output,
+        "{}",
+        serde_json::to_string(&hello).expect("serialization to succeed")
+    )?;
+    output.flush()?;
+
+    for line in input.lines() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+
+        let response = serde_json::from_str::<HelperRequest>(&line)
+            .map_err(|e| MoonlightError::Unknown(format!("bad request: {e}")))
+            .and_then(handle_request);
+        writeln!(
+            output,
+            "{}",
+            serde_json::to_string(&response).expect("serialization to succeed")
+        )?;
+        output.flush()?;
+    }
+
+    Ok(())
+}
+
+fn handle_request(request: HelperRequest) -> crate::Result<Vec<JournalEntry>> {
+    let install = validate_install(request.op.install())?;
+    if request.op.dist().is_some_and(|dist| !dist.is_absolute()) {
+        return Err(refuse(
+            &install.path,
+            "custom build paths have to be absolute",
+        ));
+    }
+    if request
+        .custom_moonlight_dir
+        .as_deref()
+        .is_some_and(|dir| !dir.is_absolute())
+    {
+        return Err(refuse(
+            &install.path,
+            "custom moonlight dirs have to be absolute",
+        ));
+    }
+
+    let installer = Installer::for_privileged_helper(request.custom_moonlight_dir);
+    run_op(&installer, &install, request.op)?;
+    Ok(installer.take_privileged_entries())
+}
+
+fn run_op(installer: &Installer, install: &DetectedInstall, op: HelperOp) -> crate::Result<()> {
+    match op {
+        HelperOp::Patch { dist, strategy, .. } => {
+            if matches!(
+                detect_patch_state(install)?,
+                PatchState::Moonlight | PatchState::Vencord
+            ) {
+                installer.replace_install(install, dist, strategy)
+            } else {
+                installer.patch_install(install, dist, strategy)
+            }
+        }
+        HelperOp::Unpatch { .. } => installer.unpatch_install(install),
+        HelperOp::Repair { dist, .. } => installer.repair_install(install, dist),
+        HelperOp::Refresh { dist, .. } => installer.refresh_stub(install, dist),
+    }
+}
+
+fn refuse(path: &Path, reason: &str) -> MoonlightError {
+    MoonlightError::Unknown(format!(
+        "privileged helper refused {}: {reason}",
+        path.display()
+    ))
+}
+
+/// Whether `install` is one the privileged helper would take, and one we
+/// can't change ourselves. Anything else failing for lack of permissions,
+/// like the moonlight dir, isn't root's to fix.
+#[must_use]
+pub fn needs_privileges(install: &DetectedInstall) -> bool {
+    validate_install(install).is_ok()
+        && get_app_dir(&install.path).is_ok_and(|app_dir| !is_writable(&app_dir))
+}
+
+#[cfg(unix)]
+fn is_writable(path: &Path) -> bool {
+    use nix::unistd::{access, AccessFlags};
+
+    access(path, AccessFlags::W_OK).is_ok()
+}
+
+#[cfg(not(unix))]
+fn is_writable(_path: &Path) -> bool {
+    true
+}
+
+// Rebuilt from the path, so the id can't point somewhere else
+fn validate_install(install: &DetectedInstall) -> crate::Result<DetectedInstall> {
+    let path = &install.path;
+    if install.flatpak_id.is_some() {
+        return Err(refuse(path, "Flatpak installs don't need root"));
+    }
+    if !path.is_absolute() || std::fs::canonicalize(path).ok().as_ref() != Some(path) {
+        return Err(refuse(path, "not a canonical absolute path"));
+    }
+
+    let app_dir = get_app_dir(path)?;
+    let has_asar = app_dir.join("app.asar").exists() || app_dir.join(PATCHED_ASAR).exists();
+    let is_discord =
+        app_dir.join(BUILD_INFO).is_file() || path.join(install.branch.name()).is_file();
+    if !has_asar || !is_discord {
+        return Err(refuse(path, "not a Discord install"));
+    }
+
+    Ok(DetectedInstall::new(install.branch, path.clone(), None))
+}
+
+#[cfg(all(test, target_os = "linux"))]
+mod tests {
+    use super::*;
+    use crate::types::Branch;
+    use crate::{AsarWriter, JournalStep, Stub, HELPER_BACKUPS};
+    use std::io::Cursor;
+    use tempfile::TempDir;
+
+    fn fixture(tmp: &TempDir) -> DetectedInstall {
+        let path = std::fs::canonicalize(tmp.path()).unwrap().join("Discord");
+        let resources = path.join("resources");
+        std::fs::create_dir_all(&resources).unwrap();
+        std::fs::write(path.join("Discord"), "").unwrap();
+
+        let mut asar = AsarWriter::new();
+        asar.insert_file(
+            "package.json",
+            r#"{"name":"discord","main":"app_bootstrap/index.js"}"#,
+            false,
+        )
+        .unwrap();
+        asar.write(&resources.join("app.asar")).unwrap();
+
+        DetectedInstall::new(Branch::Stable, path, None)
+    }
+
+    fn request(op: HelperOp) -> String {
+        let request = HelperRequest {
+            custom_moonlight_dir: Some(PathBuf::from("/home/user/moonlight")),
+            op,
+        };
+        serde_json::to_string(&request).unwrap() + "\n"
+    }
+
+    // The hello, then one response per request
+    fn serve(input: &str) -> (HelperHello, Vec<crate::Result<Vec<JournalEntry>>>) {
+        let mut output = Vec::new();
+        serve_helper(Cursor::new(input), &mut output).unwrap();
+
+        let output = String::from_utf8(output).unwrap();
+        let mut lines = output.lines();
+        let hello = serde_json::from_str(lines.next().unwrap()).unwrap();
+        let responses = lines.map(|line| serde_json::from_str(line).unwrap());
+        (hello, responses.collect())
+    }
+
+    fn sh(script: &str) -> Command {
+        let mut command = Command::new("sh");
+        command.arg("-c").arg(script);
+        command
+    }
+
+    #[test]
+    fn answers_each_request() {
+        let tmp = TempDir::new().unwrap();
+        let install = fixture(&tmp);
+        let mut flatpak = install.clone();
+        flatpak.flatpak_id = Some(String::from("com.discordapp.Discord"));
+        let not_discord =
+            DetectedInstall::new(Branch::Stable, install.path.join("resources"), None);
+
+        let input = [
+            String::from("not a request\n\n"),
+            request(HelperOp::Unpatch { install: flatpak }),
+            request(HelperOp::Unpatch {
+                install: not_discord,
+            }),
+        ]
+        .concat();
+        let (hello, responses) = serve(&input);
+
+        assert_eq!(hello.version, HELPER_PROTOCOL_VERSION);
+        let errors = responses
+            .into_iter()
+            .map(|response| response.unwrap_err().to_string())
+            .collect::<Vec<_>>();
+        assert_eq!(errors.len(), 3);
+        assert!(errors[0].contains("bad request"));
+        assert!(errors[1].contains("Flatpak installs don't need root"));
+        assert!(errors[2].contains("not a Discord install"));
+    }
+
+    #[test]
+    fn only_touches_the_install() {
+        let tmp = TempDir::new().unwrap();
+        let install = fixture(&tmp);
+        let app = install.path.join("resources/app");
+
+        let input = [
+            request(HelperOp::Patch {
+                install: install.clone(),
+                dist: None,
+                strategy: PatchStrategy::Folder,
+            }),
+            request(HelperOp::Unpatch {
+                install: install.clone(),
+            }),
+        ];
+
+        let (_, patched) = serve(&input[0]);
+        let entries = patched.into_iter().next().unwrap().unwrap();
+        assert_eq!(entries.len(), 1);
+        assert!(entries[0].privileged);
+        assert!(!entries[0]
+            .steps
+            .iter()
+            .any(|step| matches!(step, JournalStep::SetPatched { .. })));
+        assert_eq!(
+            Stub::read(&app).unwrap().moonlight_dir,
+            Some(PathBuf::from("/home/user/moonlight"))
+        );
+
+        let (_, unpatched) = serve(&input[1]);
+        assert!(unpatched.into_iter().next().unwrap().is_ok());
+        assert!(!app.exists());
+        assert!(!install.path.join(HELPER_BACKUPS).exists());
+    }
+
+    #[test]
+    fn leaves_installs_we_can_write_alone() {
+        let tmp = TempDir::new().unwrap();
+        let install = fixture(&tmp);
+        assert!(!needs_privileges(&install));
+
+        let result = Installer::new().or_privileged(
+            Err(MoonlightError::PermissionDenied(String::from(
+                "moonlight dir",
+            ))),
+            || HelperOp::Unpatch { install },
+        );
+        assert!(matches!(result, Err(MoonlightError::PermissionDenied(_))));
+    }
+
+    #[test]
+    fn rejects_other_versions() {
+        let err = PrivilegedHelper::spawn(sh(r#"echo '{"version":999}'"#))
+            .err()
+            .unwrap();
+        assert!(err.to_string().contains("version 999"));
+    }
+
+    #[test]
+    fn reports_a_closed_pipe() {
+        let err = PrivilegedHelper::spawn(sh("true")).err().unwrap();
+        assert!(err.to_string().contains("exited"));
+
+        let hello = format!(r#"echo '{{"version":{HELPER_PROTOCOL_VERSION}}}'; read line"#);
+        let mut helper = PrivilegedHelper::spawn(sh(&hello)).unwrap();
+        let tmp = TempDir::new().unwrap();
+        let err = helper
+            .run(&HelperRequest {
+                custom_moonlight_dir: None,
+                op: HelperOp::Unpatch {
+                    install: fixture(&tmp),
+                },
+            })
+            .unwrap_err();
+        assert!(err.to_string().contains("exited"));
+    }
+
+    #[test]
+    fn launches_ourselves_as_the_helper() {
+        let exe = std::env::current_exe().unwrap();
+        let args = |command: &Command| {
+            command
+                .get_args()
+                .map(ToOwned::to_owned)
+                .collect::<Vec<_>>()
+        };
+
+        let command = launch_command("").unwrap();
+        assert_eq!(command.get_program(), exe);
+        assert_eq!(args(&command), [HELPER_ARG]);
+
+        let command = launch_command("pkexec").unwrap();
+        assert_eq!(command.get_program(), "pkexec");
+        assert_eq!(args(&command), [exe.as_os_str(), HELPER_ARG.as_ref()]);
+    }
+}
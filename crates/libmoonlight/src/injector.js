@@ -1,27 +1,152 @@
+// moonlight-installer stub, see Stub in libmoonlight
 const os = require("node:os");
 const fs = require("node:fs");
 const path = require("node:path");
 const process = require("node:process");
 
-function getInjector(override) {
-  if (override !== null && fs.existsSync(override)) return override;
+// Everything install specific lives next to us, or next to app.asar when
+// we're wrapped in one, see Stub in libmoonlight
+const CONFIG = "moonlight-install.json";
+const configPath = fs.existsSync(path.join(__dirname, CONFIG))
+  ? path.join(__dirname, CONFIG)
+  : path.join(__dirname, "..", CONFIG);
+// Same as PATCHED_ASAR, for when there's no config to say otherwise
+const DEFAULT_PATCHED_ASAR = "_app.asar";
+
+function readConfig() {
+  try {
+    return JSON.parse(fs.readFileSync(configPath, "utf8"));
+  } catch (err) {
+    console.error("moonlight couldn't read its install config, starting Discord without it:", err);
+    return null;
+  }
+}
+
+const config = readConfig();
+
+// Same order as get_moonlight_dir on the Rust side
+function getMoonlightDir() {
+  if (process.env.MOONLIGHT_DIR != null) return process.env.MOONLIGHT_DIR;
+  if (config.moonlightDir != null) return config.moonlightDir;
 
-  // resolve default path
   switch (os.platform()) {
     case "win32":
-      return path.join(process.env.APPDATA, "moonlight-mod", DOWNLOAD_DIR, "injector.js");
+      return path.join(process.env.APPDATA, "moonlight-mod");
     case "darwin":
-      return path.join(os.homedir(), "Library", "Application Support", "moonlight-mod", DOWNLOAD_DIR, "injector.js");
+      return path.join(os.homedir(), "Library", "Application Support", "moonlight-mod");
     case "linux":
     default:
-      return path.join(
-        process.env.XDG_CONFIG_HOME ?? path.join(os.homedir(), ".config"),
-        "moonlight-mod",
-        DOWNLOAD_DIR,
-        "injector.js"
-      );
+      return path.join(process.env.XDG_CONFIG_HOME ?? path.join(os.homedir(), ".config"), "moonlight-mod");
+  }
+}
+
+function getInjector() {
+  if (config.dist != null) {
+    const override = path.join(config.dist, "injector.js");
+    if (fs.existsSync(override)) return override;
+  }
+
+  return path.join(getMoonlightDir(), config.downloadDir, "injector.js");
+}
+
+// See LaunchState in libmoonlight
+function getStatePath() {
+  return path.join(getMoonlightDir(), "launch-state", `${config.installId ?? "unknown"}.json`);
+}
+
+function readState(statePath) {
+  const initial = { crashes: 0, disabled: false, launching: false, lastError: null };
+  try {
+    return { ...initial, ...JSON.parse(fs.readFileSync(statePath, "utf8")) };
+  } catch {
+    return initial;
+  }
+}
+
+function writeState(statePath, state) {
+  try {
+    fs.mkdirSync(path.dirname(statePath), { recursive: true });
+    fs.writeFileSync(statePath, JSON.stringify(state));
+  } catch {
+    // Not being able to count crashes shouldn't stop Discord from starting
   }
 }
 
-const injector = getInjector(MOONLIGHT_INJECTOR);
-require(injector).inject(path.resolve(__dirname, `../${PATCHED_ASAR}`));
+function isSplash(url) {
+  return url.startsWith("file:") && url.includes("/splash/");
+}
+
+// Starts Discord without moonlight, the same way Electron would have
+function loadDiscord(asar) {
+  const { app } = require("electron");
+  const pkg = require(path.join(asar, "package.json"));
+  app.setAppPath?.(asar);
+  app.name = pkg.name;
+  require.main.filename = path.join(asar, pkg.main);
+  require(require.main.filename);
+}
+
+// A launch counts as a crash when moonlight throws while injecting, or when
+// Discord dies before its main window finishes loading. Quitting on purpose
+// before then doesn't, which covers handing off to an already running Discord
+// and restarting for an update.
+function start(asar) {
+  const { app } = require("electron");
+  const statePath = getStatePath();
+  const state = readState(statePath);
+
+  if (state.launching) {
+    state.crashes += 1;
+    state.launching = false;
+  }
+  if (!state.disabled && state.crashes >= config.maxCrashes) state.disabled = true;
+
+  if (state.disabled) {
+    writeState(statePath, state);
+    console.warn(`moonlight is disabled after ${state.crashes} crashes, starting Discord without it`);
+    loadDiscord(asar);
+    return;
+  }
+
+  state.launching = true;
+  writeState(statePath, state);
+
+  let ready = false;
+  app.on("browser-window-created", (_, window) => {
+    window.webContents.on("did-finish-load", () => {
+      // The splash/updater window loads first, before moonlight does much
+      if (ready || isSplash(window.webContents.getURL())) return;
+      ready = true;
+      state.crashes = 0;
+      state.launching = false;
+      state.lastError = null;
+      writeState(statePath, state);
+    });
+  });
+  app.once("will-quit", () => {
+    if (ready || !state.launching) return;
+    state.launching = false;
+    writeState(statePath, state);
+  });
+
+  // For moonlight to pick up the profile and flags
+  global.moonlightInstall = config;
+
+  try {
+    require(getInjector()).inject(asar);
+  } catch (err) {
+    console.error("moonlight failed to start:", err);
+    ready = true;
+    state.crashes += 1;
+    state.launching = false;
+    state.lastError = String(err?.stack ?? err);
+    writeState(statePath, state);
+    loadDiscord(asar);
+  }
+}
+
+if (config == null) {
+  loadDiscord(path.resolve(__dirname, "..", DEFAULT_PATCHED_ASAR));
+} else {
+  start(path.resolve(__dirname, "..", config.patchedAsar));
+}
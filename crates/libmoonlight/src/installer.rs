@@ -1,12 +1,20 @@
-use super::types::{Branch, DetectedInstall, GitHubRelease, InstallInfo, MoonlightBranch};
-use super::util::{get_download_dir, get_home_dir};
+use super::types::{
+    Branch, BrokenState, DetectedInstall, GitHubRelease, InstallId, InstallInfo, MoonlightBranch,
+    PatchState, PatchStrategy, RepatchAction, RepatchOutcome,
+};
+use super::util::get_download_dir;
 use crate::{
-    ensure_flatpak_overrides, get_app_dir, get_dot_config, get_local_share,
-    get_local_share_workaround, get_moonlight_dir, DOWNLOAD_DIR, PATCHED_ASAR,
+    check_sandbox_permissions, close_discord, detect_patch_state, ensure_moonlight_dir,
+    get_app_dir, get_custom_moonlight_dir, get_moonlight_dir, get_patch_strategy, get_stub_dir,
+    is_moonlight_asar, needs_privileges, platform_detector, sha256_file, to_host_path,
+    AddedFlatpakOverrides, AsarBackups, DetectorEnv, HelperOp, HelperRequest, InstallDetector,
+    InstallRegistry, Journal, JournalAction, JournalEntry, JournalLock, LaunchState, ManualInstall,
+    MoonlightError, PatchedInstalls, Plan, PlanStep, PrivilegedHelper, ProcessInspector, Stub,
+    Transaction, PATCHED_ASAR, STUB_CONFIG, STUB_VERSION,
 };
-use std::env::home_dir;
-use std::fs::DirEntry;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::Duration;
 
 const USER_AGENT: &str =
     "moonlight-installer (https://github.com/moonlight-mod/moonlight-installer)";
@@ -17,7 +25,16 @@ const ARTIFACT_NAME: &str = "dist.tar.gz";
 const NIGHTLY_REF_URL: &str = "https://moonlight-mod.github.io/moonlight/ref";
 const NIGHTLY_DIST_URL: &str = "https://moonlight-mod.github.io/moonlight/dist.tar.gz";
 
-pub struct Installer;
+pub struct Installer {
+    backup_asar: bool,
+    helper: Option<HelperRun>,
+}
+
+// Set when we're the privileged helper, see Installer::for_privileged_helper
+struct HelperRun {
+    custom_moonlight_dir: Option<PathBuf>,
+    entries: Mutex<Vec<JournalEntry>>,
+}
 
 impl Default for Installer {
     fn default() -> Self {
@@ -28,7 +45,80 @@ impl Default for Installer {
 impl Installer {
     #[must_use]
     pub const fn new() -> Self {
-        Self {}
+        Self {
+            backup_asar: false,
+            helper: None,
+        }
+    }
+
+    /// Only touches the installs it changes. What it did is for the user's
+    /// journal, see [`Installer::take_privileged_entries`].
+    /// `custom_moonlight_dir` is the user's `MOONLIGHT_DIR`, if they set one.
+    #[must_use]
+    pub const fn for_privileged_helper(custom_moonlight_dir: Option<PathBuf>) -> Self {
+        Self {
+            backup_asar: false,
+            helper: Some(HelperRun {
+                custom_moonlight_dir,
+                entries: Mutex::new(Vec::new()),
+            }),
+        }
+    }
+
+    /// What a privileged helper's installer did since this was last called.
+    pub fn take_privileged_entries(&self) -> Vec<JournalEntry> {
+        self.helper
+            .as_ref()
+            .map(|helper| std::mem::take(&mut *helper.entries.lock().unwrap()))
+            .unwrap_or_default()
+    }
+
+    /// Keep a copy of Discord's app.asar in the moonlight dir when patching,
+    /// see [`AsarBackups`]. Installs that have one keep getting them anyway.
+    #[must_use]
+    pub const fn with_asar_backups(mut self, backup_asar: bool) -> Self {
+        self.backup_asar = backup_asar;
+        self
+    }
+
+    /// Passes `result` through, unless it failed for lack of permissions on
+    /// an install only root can change, in which case the privileged helper
+    /// gets asked to do `op` instead.
+    pub fn or_privileged(
+        &self,
+        result: crate::Result<()>,
+        op: impl FnOnce() -> HelperOp,
+    ) -> crate::Result<()> {
+        let Err(MoonlightError::PermissionDenied(err)) = result else {
+            return result;
+        };
+        let op = op();
+        if !needs_privileges(op.install()) {
+            return Err(MoonlightError::PermissionDenied(err));
+        }
+
+        let entries = PrivilegedHelper::launch()?.run(&HelperRequest {
+            custom_moonlight_dir: get_custom_moonlight_dir(),
+            op: op.clone(),
+        })?;
+        self.record_privileged(&op, entries)
+    }
+
+    // Root only kept to the install, the rest is ours to keep track of
+    fn record_privileged(&self, op: &HelperOp, entries: Vec<JournalEntry>) -> crate::Result<()> {
+        let install = op.install();
+        let last = entries.len().saturating_sub(1);
+
+        for (i, entry) in entries.into_iter().enumerate() {
+            let mut tx = Transaction::begin(entry.action, install)?;
+            tx.record_privileged(entry)?;
+            if let Some(patched) = op.patched().filter(|_| i == last) {
+                tx.set_patched(&install.id, patched)?;
+            }
+            tx.commit()?;
+        }
+
+        Ok(())
     }
 
     pub fn download_moonlight(&self, branch: MoonlightBranch) -> crate::Result<String> {
@@ -46,6 +136,36 @@ impl Installer {
         })
     }
 
+    /// Includes writing the downloaded version, which callers do right after.
+    #[must_use]
+    pub fn plan_download_moonlight(&self, branch: MoonlightBranch) -> Plan {
+        let dir = get_download_dir();
+        let mut plan = Plan::new();
+
+        if dir.exists() {
+            plan.push(PlanStep::Remove {
+                path: dir.clone(),
+                backup: None,
+            });
+        }
+        plan.push(PlanStep::CreateDir { path: dir.clone() });
+        plan.push(PlanStep::Download {
+            url: match branch {
+                MoonlightBranch::Stable => format!(
+                    "https://github.com/{GITHUB_REPO}/releases/latest/download/{ARTIFACT_NAME}"
+                ),
+                MoonlightBranch::Nightly => NIGHTLY_DIST_URL.to_string(),
+            },
+            to: dir,
+        });
+        plan.push(PlanStep::WriteFile {
+            path: get_moonlight_dir().join(INSTALLED_VERSION_FILE),
+            backup: None,
+        });
+
+        plan
+    }
+
     fn download_stable(&self, dir: PathBuf) -> crate::Result<String> {
         let release = self.get_stable_release()?;
         let asset = release
@@ -86,7 +206,7 @@ impl Installer {
     }
 
     pub fn set_downloaded_version(&self, version: &str) -> crate::Result<()> {
-        let dir = get_moonlight_dir();
+        let dir = ensure_moonlight_dir()?;
         std::fs::write(dir.join(INSTALLED_VERSION_FILE), version)?;
         Ok(())
     }
@@ -111,229 +231,836 @@ impl Installer {
     }
 
     pub fn get_installs(&self) -> crate::Result<Vec<InstallInfo>> {
-        self.detect_installs().map(|installs| {
+        let mut installs =
+            self.get_installs_with(platform_detector().as_ref(), &DetectorEnv::current())?;
+
+        for manual in InstallRegistry::load()?.installs {
+            let Some(install) = manual.to_detected() else {
+                continue;
+            };
+
+            match installs.iter_mut().find(|i| i.install.id == install.id) {
+                Some(existing) => {
+                    // The branch decides things like which config it has
+                    if manual
+                        .branch
+                        .is_some_and(|branch| branch != existing.install.branch)
+                    {
+                        *existing = InstallInfo::new(install);
+                    }
+                    existing.label = manual.label;
+                }
+                None => {
+                    let mut info = InstallInfo::new(install);
+                    info.label = manual.label;
+                    info.manually_added = true;
+                    installs.push(info);
+                }
+            }
+        }
+
+        let detected = installs
+            .iter()
+            .map(|i| i.install.clone())
+            .collect::<Vec<_>>();
+        let running = ProcessInspector::new().find_running(&detected);
+        for info in &mut installs {
+            info.running = running.contains(&info.install.id);
+        }
+
+        Ok(installs)
+    }
+
+    pub fn get_installs_with(
+        &self,
+        detector: &dyn InstallDetector,
+        env: &DetectorEnv,
+    ) -> crate::Result<Vec<InstallInfo>> {
+        detector.detect(env).map(|installs| {
             installs
                 .into_iter()
-                .map(|install| {
-                    let patched = self.is_install_patched(&install).unwrap_or(false);
-                    let has_config = install.branch.config().exists();
-
-                    InstallInfo {
-                        install,
-                        patched,
-                        has_config,
-                    }
-                })
+                .map(|install| InstallInfo::new_with(install, env))
                 .collect()
         })
     }
 
-    fn detect_installs(&self) -> crate::Result<Vec<DetectedInstall>> {
-        match std::env::consts::OS {
-            "windows" => {
-                let appdata = std::env::var("LocalAppData").unwrap();
-                let dirs = [
-                    ("Discord", Branch::Stable),
-                    ("DiscordPTB", Branch::PTB),
-                    ("DiscordCanary", Branch::Canary),
-                    ("DiscordDevelopment", Branch::Development),
-                ];
-                let mut installs = vec![];
-
-                for (dir, branch) in dirs {
-                    let path = PathBuf::from(appdata.clone()).join(dir);
-                    if path.exists() {
-                        // app-(version)
-                        let mut app_dirs: Vec<_> = std::fs::read_dir(&path)?
-                            .filter_map(Result::ok)
-                            .filter(|x| x.file_name().to_string_lossy().starts_with("app-"))
-                            .collect();
-
-                        app_dirs.sort_by_key(DirEntry::file_name);
-
-                        if let Some(most_recent_install) = app_dirs.last() {
-                            installs.push(DetectedInstall {
-                                branch,
-                                path: most_recent_install.path(),
-                                flatpak_id: None,
-                            });
-                        }
-                    }
-                }
+    pub fn add_manual_install(&self, install: ManualInstall) -> crate::Result<InstallId> {
+        let mut registry = InstallRegistry::load()?;
+        let unchanged = registry.installs.contains(&install);
+        let id = registry.add(install)?;
+        if !unchanged {
+            registry.save()?;
+        }
+        Ok(id)
+    }
 
-                Ok(installs)
-            }
+    /// Empty if it's already there as is.
+    pub fn plan_add_manual_install(&self, install: &ManualInstall) -> crate::Result<Plan> {
+        let registry = InstallRegistry::load()?;
+        if install.to_detected().is_none() {
+            return Err(MoonlightError::InstallNotFound(
+                install.path.display().to_string(),
+            ));
+        }
+        Ok(self.plan_registry_write(!registry.installs.contains(install)))
+    }
 
-            "macos" => {
-                let apps_dirs = vec![
-                    PathBuf::from("/Applications"),
-                    get_home_dir().join("Applications"),
-                ];
+    /// Returns whether the install was in the registry.
+    pub fn remove_manual_install(&self, id: &InstallId) -> crate::Result<bool> {
+        let mut registry = InstallRegistry::load()?;
+        let removed = registry.remove(id);
+        if removed {
+            registry.save()?;
+        }
+        Ok(removed)
+    }
 
-                let branches = [
-                    ("Discord", Branch::Stable),
-                    ("Discord PTB", Branch::PTB),
-                    ("Discord Canary", Branch::Canary),
-                    ("Discord Development", Branch::Development),
-                ];
+    /// Empty if it isn't in the registry.
+    pub fn plan_remove_manual_install(&self, id: &InstallId) -> crate::Result<Plan> {
+        let registry = InstallRegistry::load()?;
+        Ok(self.plan_registry_write(registry.contains(id)))
+    }
 
-                let mut installs = vec![];
+    fn plan_registry_write(&self, changed: bool) -> Plan {
+        let mut plan = Plan::new();
+        if changed {
+            plan.push(PlanStep::WriteFile {
+                path: InstallRegistry::path(),
+                backup: None,
+            });
+        }
+        plan
+    }
 
-                for apps_dir in apps_dirs {
-                    for (branch_name, branch) in branches {
-                        let macos_app_dir = apps_dir.join(format!("{branch_name}.app"));
+    pub fn find_install(&self, id: &InstallId) -> crate::Result<InstallInfo> {
+        self.get_installs()?
+            .into_iter()
+            .find(|install| &install.install.id == id)
+            .ok_or_else(|| MoonlightError::InstallNotFound(id.to_string()))
+    }
 
-                        if !macos_app_dir.exists() {
-                            continue;
-                        }
+    pub fn patch_install(
+        &self,
+        install: &DetectedInstall,
+        override_download_dir: Option<PathBuf>,
+        strategy: PatchStrategy,
+    ) -> crate::Result<()> {
+        self.ensure_not_broken(install)?;
+        let stub = self.new_stub(install, override_download_dir, strategy);
+        self.back_up_original(install, &stub)?;
+        self.transaction(JournalAction::Patch, install, |tx| {
+            self.patch_steps(tx, install, &stub)
+        })
+    }
 
-                        let app_dir = macos_app_dir.join("Contents/Resources");
+    pub fn plan_patch_install(
+        &self,
+        install: &DetectedInstall,
+        override_download_dir: Option<PathBuf>,
+        strategy: PatchStrategy,
+    ) -> crate::Result<Plan> {
+        self.ensure_not_broken(install)?;
+        let stub = self.new_stub(install, override_download_dir, strategy);
+        let mut plan = self.plan_back_up_original(install, &stub)?;
+        plan.extend(self.plan(JournalAction::Patch, install, |tx| {
+            self.patch_steps(tx, install, &stub)
+        })?);
+        Ok(plan)
+    }
 
-                        installs.push(DetectedInstall {
-                            branch,
-                            path: app_dir,
-                            flatpak_id: None,
-                        });
-                    }
-                }
+    /// Unpatches whatever is patched in, moonlight or another client mod,
+    /// and patches moonlight in. Rolled back and undone as a whole.
+    pub fn replace_install(
+        &self,
+        install: &DetectedInstall,
+        override_download_dir: Option<PathBuf>,
+        strategy: PatchStrategy,
+    ) -> crate::Result<()> {
+        self.ensure_unpatchable(install)?;
+        let stub = self.replacing_stub(install, override_download_dir, strategy);
+        self.back_up_original(install, &stub)?;
+        self.transaction(JournalAction::Replace, install, |tx| {
+            self.unpatch_steps(tx, install)?;
+            self.patch_steps(tx, install, &stub)
+        })
+    }
+
+    pub fn plan_replace_install(
+        &self,
+        install: &DetectedInstall,
+        override_download_dir: Option<PathBuf>,
+        strategy: PatchStrategy,
+    ) -> crate::Result<Plan> {
+        self.ensure_unpatchable(install)?;
+        let stub = self.replacing_stub(install, override_download_dir, strategy);
+        let mut plan = self.plan_back_up_original(install, &stub)?;
+        plan.extend(self.plan(JournalAction::Replace, install, |tx| {
+            self.unpatch_steps(tx, install)?;
+            self.patch_steps(tx, install, &stub)
+        })?);
+        Ok(plan)
+    }
 
-                Ok(installs)
+    // Unpatching puts back the original app.asar if PATCHED_ASAR was changed,
+    // so the old stub's hash is the one that'll be there
+    fn replacing_stub(
+        &self,
+        install: &DetectedInstall,
+        override_download_dir: Option<PathBuf>,
+        strategy: PatchStrategy,
+    ) -> Stub {
+        let mut stub = self.new_stub(install, override_download_dir, strategy);
+        if let Some(sha256) = get_stub_dir(install)
+            .and_then(|dir| Stub::read(&dir))
+            .and_then(|old| old.original_sha256)
+        {
+            stub.original_sha256 = Some(sha256);
+        }
+        stub
+    }
+
+    fn new_stub(
+        &self,
+        install: &DetectedInstall,
+        override_download_dir: Option<PathBuf>,
+        strategy: PatchStrategy,
+    ) -> Stub {
+        let original_sha256 = get_app_dir(&install.path)
+            .ok()
+            .and_then(|app_dir| sha256_file(&Stub::original_asar(&app_dir)).ok());
+
+        Stub {
+            install_id: Some(install.id.clone()),
+            dist: override_download_dir.as_deref().map(to_host_path),
+            moonlight_dir: self.stub_moonlight_dir(install),
+            strategy,
+            original_sha256,
+            ..Stub::default()
+        }
+    }
+
+    // Records the moonlight dir so Discord finds it without MOONLIGHT_DIR, or
+    // with a different XDG_CONFIG_HOME like inside a Flatpak. The default
+    // is left out otherwise, a system install is every user's.
+    fn stub_moonlight_dir(&self, install: &DetectedInstall) -> Option<PathBuf> {
+        let custom = match &self.helper {
+            Some(helper) => helper.custom_moonlight_dir.clone(),
+            None => get_custom_moonlight_dir(),
+        };
+        custom
+            .or_else(|| install.flatpak_id.is_some().then(get_moonlight_dir))
+            .map(|dir| to_host_path(&dir))
+    }
+
+    // Done before patching, so we don't patch without the backup we were asked for.
+    // The user's side already did it when it's the privileged helper patching.
+    fn back_up_original(&self, install: &DetectedInstall, stub: &Stub) -> crate::Result<()> {
+        if self.helper.is_some() {
+            return Ok(());
+        }
+        if let Some(sha256) = stub.original_sha256.as_deref() {
+            if self.backup_asar || AsarBackups::exists(&install.id) {
+                let app_dir = get_app_dir(&install.path)?;
+                AsarBackups::save(&install.id, &Stub::original_asar(&app_dir), sha256)?;
             }
+        }
+        Ok(())
+    }
 
-            "linux" => {
-                // this is a crime but it has to be done...
-                // please merge pr flatpak devs
-                let local_shares = [get_local_share(), get_local_share_workaround()];
-                let local_share_dirs = [
-                    ("Discord", Branch::Stable, None),
-                    ("DiscordPTB", Branch::PTB, None),
-                    ("DiscordCanary", Branch::Canary, None),
-                    ("DiscordDevelopment", Branch::Development, None),
-                    // flatpak user installations
-                    ("flatpak/app/com.discordapp.Discord/current/active/files/discord", Branch::Stable, Some("com.discordapp.Discord")),
-                    ("flatpak/app/com.discordapp.DiscordCanary/current/active/files/discord-canary", Branch::Canary, Some("com.discordapp.DiscordCanary")),
-                ];
-
-                let mut installs = vec![];
-                for (dir, branch, id) in local_share_dirs {
-                    for local_share in &local_shares {
-                        let path = local_share.join(dir);
-                        if path.join(branch.name()).exists() && path.join("resources").exists() {
-                            installs.push(DetectedInstall {
-                                branch,
-                                path,
-                                flatpak_id: id.map(Into::into),
-                            });
-                            break;
-                        }
-                    }
-                }
+    fn plan_back_up_original(&self, install: &DetectedInstall, stub: &Stub) -> crate::Result<Plan> {
+        let mut plan = Plan::new();
+        if let Some(sha256) = stub.original_sha256.as_deref() {
+            let wanted = self.backup_asar || AsarBackups::exists(&install.id);
+            if wanted && AsarBackups::find(&install.id, sha256).is_none() {
+                plan.push(PlanStep::Copy {
+                    from: Stub::original_asar(&get_app_dir(&install.path)?),
+                    to: AsarBackups::path(&install.id, sha256),
+                });
+            }
+        }
+        Ok(plan)
+    }
 
-                // Handle the new updater, which lives in ~/.config
-                let home = home_dir().unwrap();
-                let dot_configs = [
-                    (get_dot_config(), None),
-                    (
-                        home.join(".var/app/com.discordapp.Discord/config"),
-                        Some("com.discordapp.Discord"),
-                    ),
-                    (
-                        home.join(".var/app/com.discordapp.DiscordCanary/config"),
-                        Some("com.discordapp.DiscordCanary"),
-                    ),
-                ];
-                let dot_config_dirs = [
-                    ("discord", Branch::Stable),
-                    ("discordptb", Branch::PTB),
-                    ("discordcanary", Branch::Canary),
-                    ("discorddevelopment", Branch::Development),
-                ];
-                for (dir, branch) in dot_config_dirs {
-                    for (dot_config, flatpak_id) in &dot_configs {
-                        let path = dot_config.join(dir);
-
-                        if path.exists() {
-                            // app-(version)
-                            let mut app_dirs: Vec<_> = std::fs::read_dir(&path)?
-                                .filter_map(Result::ok)
-                                .filter(|x| x.file_name().to_string_lossy().starts_with("app-"))
-                                .collect();
-
-                            app_dirs.sort_by_key(DirEntry::file_name);
-
-                            if let Some(most_recent_install) = app_dirs.last() {
-                                installs.push(DetectedInstall {
-                                    branch,
-                                    path: most_recent_install.path(),
-                                    flatpak_id: flatpak_id.to_owned().map(ToOwned::to_owned),
-                                });
-                            }
-                        }
-                    }
+    // What to put back instead of PATCHED_ASAR if it isn't what we patched
+    // anymore. Errors if it's been changed and there's nothing to put back.
+    fn original_backup(&self, install: &DetectedInstall) -> crate::Result<Option<PathBuf>> {
+        let Some(sha256) = get_stub_dir(install)
+            .and_then(|dir| Stub::read(&dir))
+            .and_then(|stub| stub.original_sha256)
+        else {
+            return Ok(None);
+        };
+
+        let original = get_app_dir(&install.path)?.join(PATCHED_ASAR);
+        if sha256_file(&original).is_ok_and(|hash| hash == sha256) {
+            return Ok(None);
+        }
+
+        match AsarBackups::find(&install.id, &sha256) {
+            Some(backup) => Ok(Some(backup)),
+            None => Err(MoonlightError::BrokenInstall(format!(
+                "{PATCHED_ASAR} is {} and there's no backup of it, please reinstall Discord",
+                if original.exists() {
+                    "modified"
+                } else {
+                    "missing"
                 }
+            ))),
+        }
+    }
 
-                Ok(installs)
-            }
+    /// What the install was last patched with, going by whatever stub config
+    /// is still around. The config of a wrapped app.asar outlives Discord
+    /// putting back its own.
+    #[must_use]
+    pub fn get_patch_strategy(&self, install: &DetectedInstall) -> PatchStrategy {
+        get_patch_strategy(install)
+    }
+
+    fn patch_steps(
+        &self,
+        tx: &mut Transaction,
+        install: &DetectedInstall,
+        stub: &Stub,
+    ) -> crate::Result<()> {
+        // TODO: flatpak and stuff
+        let app_dir = get_app_dir(&install.path)?;
+        let asar = app_dir.join("app.asar");
+        tx.rename(&asar, &asar.with_file_name(PATCHED_ASAR))?;
+        self.install_stub(tx, &app_dir, stub)?;
+        self.grant_flatpak_access(tx, install, stub)?;
+        tx.set_patched(&install.id, true)
+    }
+
+    // Whatever of the stub's paths Flatpak can share, see InstallInfo for the rest
+    fn grant_flatpak_access(
+        &self,
+        tx: &mut Transaction,
+        install: &DetectedInstall,
+        stub: &Stub,
+    ) -> crate::Result<()> {
+        let Some(flatpak_id) = install.flatpak_id.as_deref() else {
+            return Ok(());
+        };
 
-            _ => Ok(Vec::new()),
+        let filesystems = stub.flatpak_filesystems();
+        if filesystems.overrides.is_empty() {
+            return Ok(());
         }
+        tx.edit_flatpak_overrides(flatpak_id, &filesystems.overrides)
     }
 
-    // This will probably match other client mods that replace app.asar, but it
-    // will just prompt them to unpatch, so I think it's fine
-    fn is_install_patched(&self, install: &DetectedInstall) -> crate::Result<bool> {
-        Ok(!get_app_dir(&install.path)?.join("app.asar").exists())
+    // Expects app.asar to already be out of the way
+    fn install_stub(&self, tx: &mut Transaction, app_dir: &Path, stub: &Stub) -> crate::Result<()> {
+        if stub.strategy == PatchStrategy::Folder {
+            tx.create_dir(&app_dir.join("app"))?;
+        }
+        self.write_stub(tx, app_dir, stub)
     }
 
-    pub fn patch_install(
+    fn write_stub(&self, tx: &mut Transaction, app_dir: &Path, stub: &Stub) -> crate::Result<()> {
+        let original = Stub::original_package(app_dir);
+        match stub.strategy {
+            PatchStrategy::Folder => {
+                tx.write_file(
+                    &app_dir.join("app/package.json"),
+                    stub.package_json(original.as_ref()),
+                )?;
+                tx.write_file(&app_dir.join("app/injector.js"), stub.injector())?;
+                tx.write_file(&app_dir.join("app").join(STUB_CONFIG), stub.config_json())
+            }
+            PatchStrategy::Asar => {
+                let asar = stub.to_asar(original.as_ref())?;
+                tx.modify_file(&app_dir.join("app.asar"), |path| asar.write(path))?;
+                tx.write_file(&app_dir.join(STUB_CONFIG), stub.config_json())
+            }
+        }
+    }
+
+    /// The stub config of a patched install.
+    pub fn get_stub(&self, install: &DetectedInstall) -> crate::Result<Stub> {
+        let app = self.stub_dir(install)?;
+        Stub::read(&app)
+            .ok_or_else(|| MoonlightError::BrokenInstall(format!("couldn't read {STUB_CONFIG}")))
+    }
+
+    /// Changes the stub config of a patched install without touching the
+    /// stub itself. Stubs from before the config existed need a refresh first.
+    pub fn edit_stub(
+        &self,
+        install: &DetectedInstall,
+        edit: impl FnOnce(&mut Stub),
+    ) -> crate::Result<()> {
+        let app = self.stub_dir(install)?;
+        if !app.join(STUB_CONFIG).exists() {
+            return Err(MoonlightError::Unknown(String::from(
+                "this install's stub is outdated, refresh it first",
+            )));
+        }
+
+        let mut stub = self.get_stub(install)?;
+        edit(&mut stub);
+        self.transaction(JournalAction::EditStub, install, |tx| {
+            tx.write_file(&app.join(STUB_CONFIG), stub.config_json())?;
+            self.grant_flatpak_access(tx, install, &stub)
+        })
+    }
+
+    /// Rewrites the stub in a patched install with the current template.
+    /// Keeps loading moonlight from wherever it did before, unless
+    /// `override_download_dir` says otherwise.
+    pub fn refresh_stub(
         &self,
         install: &DetectedInstall,
         override_download_dir: Option<PathBuf>,
     ) -> crate::Result<()> {
-        let download_dir = override_download_dir.unwrap_or_else(get_download_dir);
+        let stub = self.refreshed_stub(install, override_download_dir)?;
+        self.transaction(JournalAction::RefreshStub, install, |tx| {
+            self.write_stub(tx, &get_app_dir(&install.path)?, &stub)?;
+            self.grant_flatpak_access(tx, install, &stub)
+        })
+    }
 
-        // TODO: flatpak and stuff
+    pub fn plan_refresh_stub(
+        &self,
+        install: &DetectedInstall,
+        override_download_dir: Option<PathBuf>,
+    ) -> crate::Result<Plan> {
+        let stub = self.refreshed_stub(install, override_download_dir)?;
+        self.plan(JournalAction::RefreshStub, install, |tx| {
+            self.write_stub(tx, &get_app_dir(&install.path)?, &stub)?;
+            self.grant_flatpak_access(tx, install, &stub)
+        })
+    }
+
+    fn refreshed_stub(
+        &self,
+        install: &DetectedInstall,
+        override_download_dir: Option<PathBuf>,
+    ) -> crate::Result<Stub> {
+        let app = self.stub_dir(install)?;
+        let mut stub = Stub::read(&app).unwrap_or_default();
+        stub.migrate();
+
+        if let Some(dist) = override_download_dir {
+            stub.dist = Some(to_host_path(&dist));
+        }
+        if stub.moonlight_dir.is_none() {
+            stub.moonlight_dir = self.stub_moonlight_dir(install);
+        }
+        stub.version = STUB_VERSION;
+        stub.install_id = Some(install.id.clone());
+        Ok(stub)
+    }
+
+    /// How the stub's recent launches went, if moonlight is patched in and
+    /// Discord has been started since.
+    pub fn get_launch_state(
+        &self,
+        install: &DetectedInstall,
+    ) -> crate::Result<Option<LaunchState>> {
+        match LaunchState::path_for(install) {
+            Some(path) => LaunchState::load(&path),
+            None => Ok(None),
+        }
+    }
+
+    /// Re-enables moonlight after the stub gave up on it.
+    pub fn reset_launch_state(&self, install: &DetectedInstall) -> crate::Result<()> {
+        match LaunchState::path_for(install) {
+            Some(path) => LaunchState::reset(&path),
+            None => Ok(()),
+        }
+    }
+
+    fn stub_dir(&self, install: &DetectedInstall) -> crate::Result<PathBuf> {
+        let not_patched =
+            || MoonlightError::Unknown(String::from("moonlight isn't patched into this install"));
+        if detect_patch_state(install)? != PatchState::Moonlight {
+            return Err(not_patched());
+        }
+
+        get_stub_dir(install).ok_or_else(not_patched)
+    }
+
+    pub fn unpatch_install(&self, install: &DetectedInstall) -> crate::Result<()> {
+        self.ensure_unpatchable(install)?;
+        self.transaction(JournalAction::Unpatch, install, |tx| {
+            self.unpatch_steps(tx, install)
+        })
+    }
+
+    pub fn plan_unpatch_install(&self, install: &DetectedInstall) -> crate::Result<Plan> {
+        self.ensure_unpatchable(install)?;
+        self.plan(JournalAction::Unpatch, install, |tx| {
+            self.unpatch_steps(tx, install)
+        })
+    }
+
+    fn unpatch_steps(&self, tx: &mut Transaction, install: &DetectedInstall) -> crate::Result<()> {
         let app_dir = get_app_dir(&install.path)?;
         let asar = app_dir.join("app.asar");
-        std::fs::rename(&asar, asar.with_file_name(PATCHED_ASAR))?;
-        std::fs::create_dir(app_dir.join("app"))?;
+        let original = app_dir.join(PATCHED_ASAR);
+        let config = app_dir.join(STUB_CONFIG);
+        let wrapped = is_moonlight_asar(&asar);
+        // Needs the stub config, so before it's gone
+        let backup = self.original_backup(install)?;
 
-        let json = serde_json::json!({
-          "name": "discord",
-          "main": "./injector.js",
-          "private": true
-        });
-        std::fs::write(app_dir.join("app/package.json"), json.to_string())?;
-
-        let moonlight_injector = download_dir.join("injector.js");
-        let injector = format!(
-            r#"const MOONLIGHT_INJECTOR = {};
-const PATCHED_ASAR = {};
-const DOWNLOAD_DIR = {};
-{}"#,
-            serde_json::to_string(&moonlight_injector).unwrap(),
-            serde_json::to_string(PATCHED_ASAR).unwrap(),
-            serde_json::to_string(DOWNLOAD_DIR).unwrap(),
-            include_str!("injector.js")
-        );
-        std::fs::write(app_dir.join("app/injector.js"), injector)?;
+        if wrapped {
+            tx.remove(&asar)?;
+        }
+        match backup {
+            Some(backup) => {
+                if original.exists() {
+                    tx.remove(&original)?;
+                }
+                tx.modify_file(&asar, |path| {
+                    std::fs::copy(&backup, path)?;
+                    Ok(())
+                })?;
+            }
+            None => tx.rename(&original, &asar)?,
+        }
+        if !wrapped {
+            tx.remove(&app_dir.join("app"))?;
+        }
+        if config.exists() {
+            tx.remove(&config)?;
+        }
 
         if let Some(flatpak_id) = install.flatpak_id.as_deref() {
-            ensure_flatpak_overrides(flatpak_id)?;
+            if self.owns_flatpak_override(install, flatpak_id)? {
+                tx.remove_flatpak_overrides(flatpak_id)?;
+            }
         }
 
-        Ok(())
+        tx.set_patched(&install.id, false)
     }
 
-    pub fn unpatch_install(&self, install: &DetectedInstall) -> crate::Result<()> {
+    // Only if we added it, and no other install of the same Flatpak needs it
+    fn owns_flatpak_override(
+        &self,
+        install: &DetectedInstall,
+        flatpak_id: &str,
+    ) -> crate::Result<bool> {
+        if !AddedFlatpakOverrides::load()?.contains(flatpak_id) {
+            return Ok(false);
+        }
+
+        let patched = PatchedInstalls::load()?;
+        let needed = self.get_installs()?.iter().any(|info| {
+            info.install.id != install.id
+                && info.install.flatpak_id.as_deref() == Some(flatpak_id)
+                && (info.patch_state == PatchState::Moonlight || patched.contains(&info.install.id))
+        });
+        Ok(!needed)
+    }
+
+    /// Brings a half-patched install back to a cleanly patched state. Does
+    /// nothing if the install isn't broken.
+    pub fn repair_install(
+        &self,
+        install: &DetectedInstall,
+        override_download_dir: Option<PathBuf>,
+    ) -> crate::Result<()> {
+        let PatchState::Broken(broken) = detect_patch_state(install)? else {
+            return Ok(());
+        };
+        let strategy = self.get_patch_strategy(install);
+        let stub = self.new_stub(install, override_download_dir, strategy);
+
+        self.back_up_original(install, &stub)?;
+        self.transaction(JournalAction::Repair, install, |tx| {
+            self.repair_steps(tx, install, broken, &stub)
+        })
+    }
+
+    pub fn plan_repair_install(
+        &self,
+        install: &DetectedInstall,
+        override_download_dir: Option<PathBuf>,
+    ) -> crate::Result<Plan> {
+        let PatchState::Broken(broken) = detect_patch_state(install)? else {
+            return Ok(Plan::new());
+        };
+        let strategy = self.get_patch_strategy(install);
+        let stub = self.new_stub(install, override_download_dir, strategy);
+
+        let mut plan = self.plan_back_up_original(install, &stub)?;
+        plan.extend(self.plan(JournalAction::Repair, install, |tx| {
+            self.repair_steps(tx, install, broken, &stub)
+        })?);
+        Ok(plan)
+    }
+
+    fn repair_steps(
+        &self,
+        tx: &mut Transaction,
+        install: &DetectedInstall,
+        broken: BrokenState,
+        stub: &Stub,
+    ) -> crate::Result<()> {
         let app_dir = get_app_dir(&install.path)?;
-        let asar = app_dir.join(PATCHED_ASAR);
-        std::fs::rename(&asar, asar.with_file_name("app.asar"))?;
-        std::fs::remove_dir_all(app_dir.join("app"))?;
-        Ok(())
+        let asar = app_dir.join("app.asar");
+        let patched_asar = app_dir.join(PATCHED_ASAR);
+        let app = app_dir.join("app");
+
+        match broken {
+            BrokenState::DuplicateAsar => {
+                // The fresh app.asar is what the updated Discord expects, so
+                // the old _app.asar is stale
+                tx.remove(&patched_asar)?;
+                if app.exists() {
+                    tx.remove(&app)?;
+                }
+                tx.rename(&asar, &patched_asar)?;
+            }
+
+            BrokenState::MissingPatchedAsar => {
+                if !asar.exists() || is_moonlight_asar(&asar) {
+                    return Err(MoonlightError::BrokenInstall(String::from(
+                        "the original app.asar is gone, please reinstall Discord",
+                    )));
+                }
+                if app.exists() {
+                    tx.remove(&app)?;
+                }
+                tx.rename(&asar, &patched_asar)?;
+            }
+
+            BrokenState::IncompleteApp => {
+                if app.exists() {
+                    tx.remove(&app)?;
+                }
+            }
+        }
+
+        self.install_stub(tx, &app_dir, stub)?;
+        self.grant_flatpak_access(tx, install, stub)?;
+        tx.set_patched(&install.id, true)
+    }
+
+    // Runs `op` as one journal entry, rolling back whatever it did if it fails
+    fn transaction(
+        &self,
+        action: JournalAction,
+        install: &DetectedInstall,
+        op: impl FnOnce(&mut Transaction) -> crate::Result<()>,
+    ) -> crate::Result<()> {
+        check_sandbox_permissions()?;
+        let mut tx = match &self.helper {
+            Some(_) => Transaction::privileged(action, install)?,
+            None => Transaction::begin(action, install)?,
+        };
+        match op(&mut tx) {
+            Ok(()) => {
+                let entry = tx.commit()?;
+                if let Some(helper) = &self.helper {
+                    helper.entries.lock().unwrap().push(entry);
+                }
+                Ok(())
+            }
+            Err(err) => {
+                if let Err(rollback_err) = tx.rollback(&err) {
+                    return Err(MoonlightError::BrokenInstall(format!(
+                        "{err}, and rolling back failed: {rollback_err}"
+                    )));
+                }
+                Err(err)
+            }
+        }
     }
 
-    pub fn reset_config(&self, branch: Branch) {
+    // Same as transaction, but only collects what it would do
+    fn plan(
+        &self,
+        action: JournalAction,
+        install: &DetectedInstall,
+        op: impl FnOnce(&mut Transaction) -> crate::Result<()>,
+    ) -> crate::Result<Plan> {
+        let mut tx = Transaction::dry_run(action, install)?;
+        op(&mut tx)?;
+        Ok(tx.into_plan())
+    }
+
+    #[must_use]
+    pub fn get_history(&self) -> Vec<JournalEntry> {
+        Journal::load().map(|j| j.entries).unwrap_or_default()
+    }
+
+    /// Undoes a journal entry, or the newest one that can be undone.
+    pub fn undo(&self, entry: Option<u64>) -> crate::Result<JournalEntry> {
+        let _lock = JournalLock::acquire()?;
+        Journal::load()?.undo(entry)
+    }
+
+    pub fn plan_undo(&self, entry: Option<u64>) -> crate::Result<Plan> {
+        Journal::load()?.plan_undo(entry)
+    }
+
+    /// Runs `op` with `install` closed, then starts it again if it was
+    /// running. Discord gets `timeout` to exit on its own before it's killed.
+    pub fn with_discord_closed<T>(
+        &self,
+        install: &DetectedInstall,
+        timeout: Duration,
+        op: impl FnOnce(&Self) -> crate::Result<T>,
+    ) -> crate::Result<T> {
+        let relaunch = close_discord(install, timeout)?;
+        let result = op(self);
+
+        // Bring Discord back even if the operation failed, it's no more
+        // broken than before
+        if let Some(relaunch) = relaunch {
+            let relaunched = relaunch.spawn();
+            if result.is_ok() {
+                relaunched?;
+            }
+        }
+
+        result
+    }
+
+    /// Patches or repairs every install moonlight was patched into before,
+    /// which is what you want after Discord updates itself.
+    pub fn repatch_installs(
+        &self,
+        override_download_dir: Option<PathBuf>,
+    ) -> crate::Result<Vec<RepatchOutcome>> {
+        let installs = self.get_installs()?;
+        let (patched, changed) = self.track_patched(&installs)?;
+        if changed {
+            patched.save()?;
+        }
+
+        let mut outcomes = vec![];
+        for (install, action) in self.repatch_targets(installs, &patched) {
+            let result = match action {
+                RepatchAction::Patched => {
+                    let strategy = self.get_patch_strategy(&install);
+                    self.patch_install(&install, override_download_dir.clone(), strategy)
+                }
+                RepatchAction::Repaired => {
+                    self.repair_install(&install, override_download_dir.clone())
+                }
+            };
+
+            outcomes.push(RepatchOutcome {
+                install,
+                action,
+                result,
+            });
+        }
+
+        Ok(outcomes)
+    }
+
+    pub fn plan_repatch_installs(
+        &self,
+        override_download_dir: Option<PathBuf>,
+    ) -> crate::Result<Plan> {
+        let installs = self.get_installs()?;
+        let (patched, changed) = self.track_patched(&installs)?;
+
+        let mut plan = Plan::new();
+        if changed {
+            plan.push(PlanStep::WriteFile {
+                path: PatchedInstalls::path(),
+                backup: None,
+            });
+        }
+
+        for (install, action) in self.repatch_targets(installs, &patched) {
+            plan.extend(match action {
+                RepatchAction::Patched => {
+                    let strategy = self.get_patch_strategy(&install);
+                    self.plan_patch_install(&install, override_download_dir.clone(), strategy)?
+                }
+                RepatchAction::Repaired => {
+                    self.plan_repair_install(&install, override_download_dir.clone())?
+                }
+            });
+        }
+
+        Ok(plan)
+    }
+
+    // Picks up installs patched before we kept track
+    fn track_patched(&self, installs: &[InstallInfo]) -> crate::Result<(PatchedInstalls, bool)> {
+        let mut patched = PatchedInstalls::load()?;
+        let mut changed = false;
+        for info in installs {
+            if info.patch_state == PatchState::Moonlight {
+                changed |= patched.insert(info.install.id.clone());
+            }
+        }
+        Ok((patched, changed))
+    }
+
+    fn repatch_targets(
+        &self,
+        installs: Vec<InstallInfo>,
+        patched: &PatchedInstalls,
+    ) -> Vec<(DetectedInstall, RepatchAction)> {
+        installs
+            .into_iter()
+            .filter(|info| patched.contains(&info.install.id))
+            .filter_map(|info| match info.patch_state {
+                PatchState::Unpatched | PatchState::OpenAsar => {
+                    Some((info.install, RepatchAction::Patched))
+                }
+                PatchState::Broken(_) => Some((info.install, RepatchAction::Repaired)),
+                _ => None,
+            })
+            .collect()
+    }
+
+    // A missing PATCHED_ASAR is fine as long as there's a backup to put back
+    fn ensure_unpatchable(&self, install: &DetectedInstall) -> crate::Result<()> {
+        match detect_patch_state(install)? {
+            PatchState::Broken(BrokenState::MissingPatchedAsar)
+                if self
+                    .original_backup(install)
+                    .is_ok_and(|backup| backup.is_some()) =>
+            {
+                Ok(())
+            }
+            _ => self.ensure_not_broken(install),
+        }
+    }
+
+    fn ensure_not_broken(&self, install: &DetectedInstall) -> crate::Result<()> {
+        match detect_patch_state(install)? {
+            PatchState::Broken(broken) => Err(MoonlightError::BrokenInstall(String::from(
+                broken.description(),
+            ))),
+            _ => Ok(()),
+        }
+    }
+
+    /// Moves the branch's config aside and returns where to, moonlight
+    /// writes a fresh one next launch.
+    pub fn reset_config(&self, branch: Branch) -> crate::Result<PathBuf> {
+        let (config, backup) = self.config_backup_paths(branch);
+        std::fs::rename(config, &backup)?;
+        Ok(backup)
+    }
+
+    #[must_use]
+    pub fn plan_reset_config(&self, branch: Branch) -> Plan {
+        let (config, backup) = self.config_backup_paths(branch);
+        if !config.exists() {
+            return Plan::new();
+        }
+
+        vec![PlanStep::Rename {
+            from: config,
+            to: backup,
+        }]
+        .into()
+    }
+
+    fn config_backup_paths(&self, branch: Branch) -> (PathBuf, PathBuf) {
         let config = branch.config();
         let new_name = format!(
             "{}-backup-{}.json",
@@ -343,6 +1070,167 @@ const DOWNLOAD_DIR = {};
                 .unwrap()
                 .as_secs()
         );
-        std::fs::rename(&config, config.with_file_name(new_name)).ok();
+        let backup = config.with_file_name(new_name);
+        (config, backup)
+    }
+}
+
+#[cfg(all(test, target_os = "linux"))]
+mod tests {
+    use super::*;
+    use crate::types::Branch;
+    use crate::{Asar, AsarWriter};
+    use serde_json::Value;
+    use tempfile::TempDir;
+
+    // Keeps to the install, so nothing lands in the real moonlight dir
+    fn installer() -> Installer {
+        Installer::for_privileged_helper(None)
+    }
+
+    // An install whose app.asar has Discord's package.json in it
+    fn fixture(tmp: &TempDir) -> DetectedInstall {
+        let path = tmp.path().join("Discord");
+        let resources = path.join("resources");
+        std::fs::create_dir_all(&resources).unwrap();
+        std::fs::write(path.join("Discord"), "").unwrap();
+
+        let mut asar = AsarWriter::new();
+        asar.insert_file(
+            "package.json",
+            r#"{"name":"discord","productName":"Discord","version":"0.0.99","main":"app_bootstrap/index.js"}"#,
+            false,
+        )
+        .unwrap();
+        asar.insert_file("app_bootstrap/index.js", "// Discord", false)
+            .unwrap();
+        asar.write(&resources.join("app.asar")).unwrap();
+
+        DetectedInstall::new(Branch::Stable, path, None)
+    }
+
+    fn assert_merged(package: &str) {
+        let package: Value = serde_json::from_str(package).unwrap();
+        assert_eq!(package["name"], "discord");
+        assert_eq!(package["productName"], "Discord");
+        assert_eq!(package["version"], "0.0.99");
+        assert_eq!(package["main"], "./injector.js");
+        assert_eq!(package["moonlightStubVersion"], STUB_VERSION);
+    }
+
+    fn original_main(asar: &Path) -> Value {
+        let package = Asar::open(asar)
+            .unwrap()
+            .read_to_string("package.json")
+            .unwrap();
+        serde_json::from_str::<Value>(&package).unwrap()["main"].clone()
+    }
+
+    #[test]
+    fn patches_folder_with_discords_package_json() {
+        let tmp = TempDir::new().unwrap();
+        let install = fixture(&tmp);
+        let resources = install.path.join("resources");
+        let installer = installer();
+
+        installer
+            .patch_install(&install, None, PatchStrategy::Folder)
+            .unwrap();
+        assert_merged(&std::fs::read_to_string(resources.join("app/package.json")).unwrap());
+        assert_eq!(
+            original_main(&resources.join(PATCHED_ASAR)),
+            "app_bootstrap/index.js"
+        );
+        assert!(!resources.join("app.asar").exists());
+        assert_eq!(
+            installer.get_patch_strategy(&install),
+            PatchStrategy::Folder
+        );
+
+        installer.unpatch_install(&install).unwrap();
+        assert!(!resources.join("app").exists());
+        assert_eq!(
+            original_main(&resources.join("app.asar")),
+            "app_bootstrap/index.js"
+        );
+    }
+
+    #[test]
+    fn patches_asar_with_discords_package_json() {
+        let tmp = TempDir::new().unwrap();
+        let install = fixture(&tmp);
+        let resources = install.path.join("resources");
+        let installer = installer();
+
+        installer
+            .patch_install(&install, None, PatchStrategy::Asar)
+            .unwrap();
+        let asar = resources.join("app.asar");
+        assert!(is_moonlight_asar(&asar));
+        assert_merged(
+            &Asar::open(&asar)
+                .unwrap()
+                .read_to_string("package.json")
+                .unwrap(),
+        );
+        assert!(resources.join(STUB_CONFIG).exists());
+        assert_eq!(installer.get_patch_strategy(&install), PatchStrategy::Asar);
+
+        // Refreshing reads Discord's package.json from the renamed asar now
+        installer.refresh_stub(&install, None).unwrap();
+        assert_merged(
+            &Asar::open(&asar)
+                .unwrap()
+                .read_to_string("package.json")
+                .unwrap(),
+        );
+
+        installer.unpatch_install(&install).unwrap();
+        assert!(!is_moonlight_asar(&asar));
+        assert_eq!(original_main(&asar), "app_bootstrap/index.js");
+    }
+
+    // Patches the fixture, lets `break_it` mess it up and repairs it
+    fn assert_repairs(broken: BrokenState, break_it: impl FnOnce(&Path)) {
+        let tmp = TempDir::new().unwrap();
+        let install = fixture(&tmp);
+        let installer = installer();
+
+        installer
+            .patch_install(&install, None, PatchStrategy::Folder)
+            .unwrap();
+        break_it(&install.path.join("resources"));
+        assert_eq!(
+            detect_patch_state(&install).unwrap(),
+            PatchState::Broken(broken)
+        );
+
+        installer.repair_install(&install, None).unwrap();
+        assert_eq!(detect_patch_state(&install).unwrap(), PatchState::Moonlight);
+        assert_eq!(
+            original_main(&install.path.join("resources").join(PATCHED_ASAR)),
+            "app_bootstrap/index.js"
+        );
+    }
+
+    #[test]
+    fn repairs_a_fresh_app_asar() {
+        assert_repairs(BrokenState::DuplicateAsar, |resources| {
+            std::fs::copy(resources.join(PATCHED_ASAR), resources.join("app.asar")).unwrap();
+        });
+    }
+
+    #[test]
+    fn repairs_a_missing_patched_asar() {
+        assert_repairs(BrokenState::MissingPatchedAsar, |resources| {
+            std::fs::rename(resources.join(PATCHED_ASAR), resources.join("app.asar")).unwrap();
+        });
+    }
+
+    #[test]
+    fn repairs_a_missing_injector() {
+        assert_repairs(BrokenState::IncompleteApp, |resources| {
+            std::fs::remove_file(resources.join("app/injector.js")).unwrap();
+        });
     }
 }
@@ -0,0 +1,759 @@
+use crate::types::{DetectedInstall, FlatpakFilesystemOverride, InstallId};
+use crate::{
+    ensure_flatpak_overrides, ensure_moonlight_dir, get_flatpak_override_changes,
+    get_flatpak_overrides_path, get_moonlight_dir, remove_flatpak_overrides, AddedFlatpakOverrides,
+    MoonlightError, PatchedInstalls, Plan, PlanStep,
+};
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+pub const JOURNAL: &str = "journal.json";
+pub const JOURNAL_BACKUPS: &str = "journal";
+/// Held while the journal is being changed, so two of us (say the GUI and
+/// `watch`) don't overwrite each other's entries.
+pub const JOURNAL_LOCK: &str = "journal.lock";
+/// Older entries (and their backups) are dropped past this many.
+pub const MAX_JOURNAL_ENTRIES: usize = 50;
+/// Older entries' backups are cleaned up past this, since one can be a
+/// whole app.asar. The newest entry keeps its backups regardless.
+pub const MAX_JOURNAL_BACKUP_SIZE: u64 = 512 * 1024 * 1024;
+/// Where the privileged helper keeps backups until it's done, inside the
+/// install it's changing rather than anywhere a user could write.
+pub const HELPER_BACKUPS: &str = ".moonlight-helper-backups";
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum JournalAction {
+    Patch,
+    Unpatch,
+    /// Unpatching whatever was there and patching moonlight in
+    Replace,
+    Repair,
+    RefreshStub,
+    EditStub,
+}
+
+impl std::fmt::Display for JournalAction {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Self::Patch => "patch",
+            Self::Unpatch => "unpatch",
+            Self::Replace => "replace",
+            Self::Repair => "repair",
+            Self::RefreshStub => "refresh stub",
+            Self::EditStub => "edit stub",
+        })
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum JournalStatus {
+    /// Still running, or we crashed halfway through
+    InProgress,
+    Committed,
+    RolledBack,
+    Undone,
+}
+
+impl std::fmt::Display for JournalStatus {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Self::InProgress => "in progress",
+            Self::Committed => "done",
+            Self::RolledBack => "rolled back",
+            Self::Undone => "undone",
+        })
+    }
+}
+
+/// One filesystem change, with whatever is needed to revert it.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum JournalStep {
+    Rename {
+        from: PathBuf,
+        to: PathBuf,
+    },
+    CreateDir {
+        path: PathBuf,
+    },
+    /// `backup` is `None` if the file didn't exist before
+    WriteFile {
+        path: PathBuf,
+        backup: Option<PathBuf>,
+    },
+    EditFlatpakOverrides {
+        id: String,
+        path: PathBuf,
+        backup: Option<PathBuf>,
+    },
+    Remove {
+        path: PathBuf,
+        backup: PathBuf,
+    },
+    SetPatched {
+        id: InstallId,
+        previous: bool,
+    },
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct JournalEntry {
+    pub id: u64,
+    pub action: JournalAction,
+    pub install: InstallId,
+    pub path: PathBuf,
+    /// Seconds since the Unix epoch
+    pub time: u64,
+    pub status: JournalStatus,
+    pub steps: Vec<JournalStep>,
+    pub error: Option<String>,
+    /// Set once the backups were cleaned up, which makes it history only
+    #[serde(default)]
+    pub backups_removed: bool,
+    /// Done by the privileged helper, which keeps no backups around
+    #[serde(default)]
+    pub privileged: bool,
+}
+
+impl JournalEntry {
+    #[must_use]
+    pub fn age(&self) -> Duration {
+        now().saturating_sub(Duration::from_secs(self.time))
+    }
+
+    #[must_use]
+    pub const fn can_undo(&self) -> bool {
+        !self.backups_removed
+            && !self.privileged
+            && matches!(
+                self.status,
+                JournalStatus::Committed | JournalStatus::InProgress
+            )
+    }
+
+    fn backup_dir(&self) -> PathBuf {
+        get_moonlight_dir()
+            .join(JOURNAL_BACKUPS)
+            .join(self.id.to_string())
+    }
+
+    fn backup_size(&self) -> u64 {
+        dir_size(&self.backup_dir())
+    }
+
+    // Once reverted there's nothing left to need them for
+    fn remove_backups(&mut self) {
+        let _ = std::fs::remove_dir_all(self.backup_dir());
+        self.backups_removed = true;
+    }
+
+    /// What reverting this entry would do.
+    #[must_use]
+    pub fn revert_plan(&self) -> Plan {
+        self.steps
+            .iter()
+            .rev()
+            .map(|step| match step.clone() {
+                JournalStep::Rename { from, to } => PlanStep::Rename { from: to, to: from },
+                JournalStep::CreateDir { path } => PlanStep::Remove { path, backup: None },
+                JournalStep::WriteFile { path, backup }
+                | JournalStep::EditFlatpakOverrides { path, backup, .. } => match backup {
+                    Some(backup) => PlanStep::Copy {
+                        from: backup,
+                        to: path,
+                    },
+                    None => PlanStep::Remove { path, backup: None },
+                },
+                JournalStep::Remove { path, backup } => PlanStep::Rename {
+                    from: backup,
+                    to: path,
+                },
+                JournalStep::SetPatched { id, previous } => PlanStep::SetPatched {
+                    id,
+                    patched: previous,
+                },
+            })
+            .collect::<Vec<_>>()
+            .into()
+    }
+
+    /// Reverts every step, newest first. Steps that were already reverted
+    /// are skipped, so an interrupted undo can just be run again.
+    fn revert(&self) -> crate::Result<()> {
+        for step in self.steps.iter().rev() {
+            match step {
+                JournalStep::Rename { from, to } => {
+                    if to.exists() || !from.exists() {
+                        std::fs::rename(to, from)?;
+                    }
+                }
+
+                JournalStep::CreateDir { path } => {
+                    if path.exists() {
+                        std::fs::remove_dir_all(path)?;
+                    }
+                }
+
+                JournalStep::WriteFile { path, backup }
+                | JournalStep::EditFlatpakOverrides { path, backup, .. } => match backup {
+                    Some(backup) => {
+                        std::fs::copy(backup, path)?;
+                    }
+                    None => {
+                        if path.exists() {
+                            std::fs::remove_file(path)?;
+                        }
+                    }
+                },
+
+                JournalStep::Remove { path, backup } => {
+                    if backup.exists() || !path.exists() {
+                        move_path(backup, path)?;
+                    }
+                }
+
+                JournalStep::SetPatched { id, previous } => {
+                    let mut installs = PatchedInstalls::load()?;
+                    let changed = if *previous {
+                        installs.insert(id.clone())
+                    } else {
+                        installs.remove(id)
+                    };
+                    if changed {
+                        installs.save()?;
+                    }
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Every patch operation we've run, newest last.
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct Journal {
+    pub entries: Vec<JournalEntry>,
+}
+
+impl Journal {
+    #[must_use]
+    pub fn path() -> PathBuf {
+        get_moonlight_dir().join(JOURNAL)
+    }
+
+    pub fn load() -> crate::Result<Self> {
+        match std::fs::read_to_string(Self::path()) {
+            Ok(contents) => serde_json::from_str(&contents)
+                .map_err(|e| MoonlightError::Unknown(format!("invalid {JOURNAL}: {e}"))),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Self::default()),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    // Written to the side first, so nobody reads half a journal
+    pub fn save(&self) -> crate::Result<()> {
+        let json = serde_json::to_string_pretty(self).expect("serialization to succeed");
+        let path = Self::path();
+        let partial = path.with_extension("json.partial");
+        std::fs::write(&partial, json)?;
+        std::fs::rename(partial, path)?;
+        Ok(())
+    }
+
+    #[must_use]
+    pub fn get(&self, id: u64) -> Option<&JournalEntry> {
+        self.entries.iter().find(|entry| entry.id == id)
+    }
+
+    /// The newest entry that can still be undone.
+    #[must_use]
+    pub fn last_undoable(&self) -> Option<&JournalEntry> {
+        self.entries.iter().rev().find(|entry| entry.can_undo())
+    }
+
+    /// Undoes an entry, or the newest undoable one. Entries for the same
+    /// install have to be undone newest first.
+    /// Take [`JournalLock`] before loading the journal this is called on.
+    pub fn undo(&mut self, id: Option<u64>) -> crate::Result<JournalEntry> {
+        let entry = self.undo_target(id)?;
+        entry.revert()?;
+
+        let id = entry.id;
+        let entry = self
+            .entries
+            .iter_mut()
+            .find(|e| e.id == id)
+            .expect("entry to still exist");
+        entry.status = JournalStatus::Undone;
+        entry.remove_backups();
+        let entry = entry.clone();
+        self.save()?;
+
+        Ok(entry)
+    }
+
+    pub fn plan_undo(&self, id: Option<u64>) -> crate::Result<Plan> {
+        self.undo_target(id).map(JournalEntry::revert_plan)
+    }
+
+    fn undo_target(&self, id: Option<u64>) -> crate::Result<&JournalEntry> {
+        let entry = match id {
+            Some(id) => self.get(id),
+            None => self.last_undoable(),
+        }
+        .ok_or_else(|| MoonlightError::Unknown(String::from("nothing to undo")))?;
+
+        if entry.privileged {
+            return Err(MoonlightError::Unknown(format!(
+                "entry {} was done as root and can't be undone",
+                entry.id
+            )));
+        }
+        if entry.backups_removed && entry.status == JournalStatus::Committed {
+            return Err(MoonlightError::Unknown(format!(
+                "entry {} is too old to undo, its backups were cleaned up",
+                entry.id
+            )));
+        }
+        if !entry.can_undo() {
+            return Err(MoonlightError::Unknown(format!(
+                "entry {} is already {}",
+                entry.id, entry.status
+            )));
+        }
+
+        if let Some(newer) = self
+            .entries
+            .iter()
+            .find(|e| e.id > entry.id && e.install == entry.install && e.can_undo())
+        {
+            return Err(MoonlightError::Unknown(format!(
+                "undo entry {} first, it changed the same install",
+                newer.id
+            )));
+        }
+
+        Ok(entry)
+    }
+
+    fn push(&mut self, entry: JournalEntry) {
+        self.entries.push(entry);
+
+        while self.entries.len() > MAX_JOURNAL_ENTRIES {
+            let old = self.entries.remove(0);
+            let _ = std::fs::remove_dir_all(old.backup_dir());
+        }
+
+        let newest = self.entries.len().saturating_sub(1);
+        let mut total: u64 = self.entries.iter().map(JournalEntry::backup_size).sum();
+        for entry in &mut self.entries[..newest] {
+            if total <= MAX_JOURNAL_BACKUP_SIZE {
+                break;
+            }
+            let size = entry.backup_size();
+            if size > 0 {
+                entry.remove_backups();
+                total -= size;
+            }
+        }
+    }
+
+    fn next_id(&self) -> u64 {
+        self.entries.iter().map(|e| e.id + 1).max().unwrap_or(1)
+    }
+}
+
+/// Records each step of a patch operation in the journal as it happens, so
+/// it can be rolled back if a later step fails or undone afterwards. A dry
+/// run only records the steps, without touching anything.
+pub struct Transaction {
+    journal: Journal,
+    entry: JournalEntry,
+    dry_run: bool,
+    /// Running in the privileged helper, see [`Transaction::privileged`]
+    privileged: bool,
+    /// Until the transaction is over, `None` for dry runs
+    _lock: Option<JournalLock>,
+}
+
+impl Transaction {
+    /// Waits for any other transaction to finish first.
+    pub fn begin(action: JournalAction, install: &DetectedInstall) -> crate::Result<Self> {
+        let lock = JournalLock::acquire()?;
+        let journal = Journal::load()?;
+        let mut tx = Self::new(journal, action, install, false, Some(lock));
+        tx.save()?;
+        Ok(tx)
+    }
+
+    pub fn dry_run(action: JournalAction, install: &DetectedInstall) -> crate::Result<Self> {
+        Ok(Self::new(Journal::load()?, action, install, true, None))
+    }
+
+    /// What the privileged helper runs in. Nothing outside the install is
+    /// touched: there's no journal, backups are only kept until it's over
+    /// and the patched installs are left for the user to update. The
+    /// committed entry is for the user's journal, see
+    /// [`Transaction::record_privileged`].
+    pub fn privileged(action: JournalAction, install: &DetectedInstall) -> crate::Result<Self> {
+        let mut tx = Self::new(Journal::default(), action, install, false, None);
+        tx.privileged = true;
+        tx.entry.privileged = true;
+
+        // Left over from a helper that didn't get to clean up
+        let backups = tx.backup_dir();
+        if backups.exists() {
+            std::fs::remove_dir_all(backups)?;
+        }
+        Ok(tx)
+    }
+
+    fn new(
+        journal: Journal,
+        action: JournalAction,
+        install: &DetectedInstall,
+        dry_run: bool,
+        lock: Option<JournalLock>,
+    ) -> Self {
+        let entry = JournalEntry {
+            id: journal.next_id(),
+            action,
+            install: install.id.clone(),
+            path: install.path.clone(),
+            time: now().as_secs(),
+            status: JournalStatus::InProgress,
+            steps: Vec::new(),
+            error: None,
+            backups_removed: false,
+            privileged: false,
+        };
+
+        Self {
+            journal,
+            entry,
+            dry_run,
+            privileged: false,
+            _lock: lock,
+        }
+    }
+
+    pub fn rename(&mut self, from: &Path, to: &Path) -> crate::Result<()> {
+        if !self.dry_run {
+            std::fs::rename(from, to)?;
+        }
+        self.record(JournalStep::Rename {
+            from: from.to_path_buf(),
+            to: to.to_path_buf(),
+        })
+    }
+
+    pub fn create_dir(&mut self, path: &Path) -> crate::Result<()> {
+        if !self.dry_run {
+            std::fs::create_dir(path)?;
+        }
+        self.record(JournalStep::CreateDir {
+            path: path.to_path_buf(),
+        })
+    }
+
+    pub fn write_file(&mut self, path: &Path, contents: impl AsRef<[u8]>) -> crate::Result<()> {
+        self.modify_file(path, |path| Ok(std::fs::write(path, contents)?))
+    }
+
+    /// Backs up `path` before letting `f` change it however it likes.
+    pub fn modify_file(
+        &mut self,
+        path: &Path,
+        f: impl FnOnce(&Path) -> crate::Result<()>,
+    ) -> crate::Result<()> {
+        let backup = self.back_up(path)?;
+
+        // Record first, f might have written half the file before failing
+        self.record(JournalStep::WriteFile {
+            path: path.to_path_buf(),
+            backup,
+        })?;
+        self.apply(|| f(path))
+    }
+
+    /// Gives the Flatpak the filesystem access in `filesystems`, remembering
+    /// what it didn't have before.
+    pub fn edit_flatpak_overrides(
+        &mut self,
+        id: &str,
+        filesystems: &[FlatpakFilesystemOverride],
+    ) -> crate::Result<()> {
+        let mut changes = if self.dry_run {
+            get_flatpak_override_changes(id, filesystems)?
+        } else {
+            Vec::new()
+        };
+        self.change_flatpak_overrides(id, || {
+            changes = ensure_flatpak_overrides(id, filesystems)?;
+            Ok(())
+        })?;
+
+        if changes.is_empty() {
+            return Ok(());
+        }
+        self.record_flatpak_override(|overrides| overrides.insert(id, changes))
+    }
+
+    /// Takes back what [`Transaction::edit_flatpak_overrides`] did.
+    pub fn remove_flatpak_overrides(&mut self, id: &str) -> crate::Result<()> {
+        let changes = AddedFlatpakOverrides::load()?.remove(id);
+        self.change_flatpak_overrides(id, || remove_flatpak_overrides(id, &changes).map(|_| ()))?;
+        self.record_flatpak_override(|overrides| {
+            overrides.remove(id);
+        })
+    }
+
+    fn change_flatpak_overrides(
+        &mut self,
+        id: &str,
+        f: impl FnOnce() -> crate::Result<()>,
+    ) -> crate::Result<()> {
+        let path = get_flatpak_overrides_path(id);
+        let backup = self.back_up(&path)?;
+
+        self.record(JournalStep::EditFlatpakOverrides {
+            id: id.to_string(),
+            path,
+            backup,
+        })?;
+        self.apply(f)
+    }
+
+    fn record_flatpak_override(
+        &mut self,
+        edit: impl FnOnce(&mut AddedFlatpakOverrides),
+    ) -> crate::Result<()> {
+        self.modify_file(&AddedFlatpakOverrides::path(), |path| {
+            let mut overrides = AddedFlatpakOverrides::load_from(path)?;
+            edit(&mut overrides);
+            overrides.save_to(path)
+        })
+    }
+
+    /// Moves a file or directory out of the way instead of deleting it.
+    pub fn remove(&mut self, path: &Path) -> crate::Result<()> {
+        let backup = self.next_backup_path()?;
+        if !self.dry_run {
+            move_path(path, &backup)?;
+        }
+        self.record(JournalStep::Remove {
+            path: path.to_path_buf(),
+            backup,
+        })
+    }
+
+    pub fn set_patched(&mut self, id: &InstallId, patched: bool) -> crate::Result<()> {
+        // The user's, see record_privileged
+        if self.privileged {
+            return Ok(());
+        }
+
+        let mut installs = PatchedInstalls::load()?;
+        let previous = installs.contains(id);
+        let changed = if patched {
+            installs.insert(id.clone())
+        } else {
+            installs.remove(id)
+        };
+
+        if changed {
+            if !self.dry_run {
+                installs.save()?;
+            }
+            self.record(JournalStep::SetPatched {
+                id: id.clone(),
+                previous,
+            })?;
+        }
+
+        Ok(())
+    }
+
+    #[must_use]
+    pub fn into_plan(self) -> Plan {
+        self.entry
+            .steps
+            .iter()
+            .map(PlanStep::from)
+            .collect::<Vec<_>>()
+            .into()
+    }
+
+    /// Adds what the privileged helper did to this entry, as history only.
+    pub fn record_privileged(&mut self, entry: JournalEntry) -> crate::Result<()> {
+        self.entry.steps.extend(entry.steps);
+        self.entry.privileged = true;
+        self.save()
+    }
+
+    pub fn commit(mut self) -> crate::Result<JournalEntry> {
+        self.entry.status = JournalStatus::Committed;
+        if self.privileged {
+            self.remove_backups();
+        }
+        self.save()?;
+        Ok(self.entry)
+    }
+
+    /// Reverts everything done so far. `error` is what made us give up.
+    pub fn rollback(mut self, error: &MoonlightError) -> crate::Result<()> {
+        self.entry.error = Some(error.to_string());
+        self.entry.revert()?;
+        self.entry.status = JournalStatus::RolledBack;
+        self.remove_backups();
+        self.save()
+    }
+
+    fn record(&mut self, step: JournalStep) -> crate::Result<()> {
+        self.entry.steps.push(step);
+        self.save()
+    }
+
+    fn apply(&self, f: impl FnOnce() -> crate::Result<()>) -> crate::Result<()> {
+        if self.dry_run {
+            Ok(())
+        } else {
+            f()
+        }
+    }
+
+    fn back_up(&self, path: &Path) -> crate::Result<Option<PathBuf>> {
+        if !path.exists() {
+            return Ok(None);
+        }
+
+        let backup = self.next_backup_path()?;
+        if !self.dry_run {
+            std::fs::copy(path, &backup)?;
+        }
+        Ok(Some(backup))
+    }
+
+    fn next_backup_path(&self) -> crate::Result<PathBuf> {
+        let dir = self.backup_dir();
+        if !self.dry_run {
+            std::fs::create_dir_all(&dir)?;
+        }
+        Ok(dir.join(self.entry.steps.len().to_string()))
+    }
+
+    fn backup_dir(&self) -> PathBuf {
+        if self.privileged {
+            self.entry.path.join(HELPER_BACKUPS)
+        } else {
+            self.entry.backup_dir()
+        }
+    }
+
+    fn remove_backups(&mut self) {
+        if self.privileged {
+            let _ = std::fs::remove_dir_all(self.backup_dir());
+        } else {
+            self.entry.remove_backups();
+        }
+    }
+
+    fn save(&mut self) -> crate::Result<()> {
+        if self.dry_run || self.privileged {
+            return Ok(());
+        }
+
+        match self.journal.entries.last_mut() {
+            Some(last) if last.id == self.entry.id => *last = self.entry.clone(),
+            _ => self.journal.push(self.entry.clone()),
+        }
+        self.journal.save()
+    }
+}
+
+/// An exclusive lock on [`JOURNAL_LOCK`], let go of on drop.
+pub struct JournalLock {
+    _file: std::fs::File,
+}
+
+impl JournalLock {
+    /// Blocks until whoever has it is done.
+    pub fn acquire() -> crate::Result<Self> {
+        let file = std::fs::OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(ensure_moonlight_dir()?.join(JOURNAL_LOCK))?;
+        file.lock()?;
+        Ok(Self { _file: file })
+    }
+}
+
+fn dir_size(path: &Path) -> u64 {
+    let Ok(metadata) = std::fs::symlink_metadata(path) else {
+        return 0;
+    };
+    if !metadata.is_dir() {
+        return metadata.len();
+    }
+
+    std::fs::read_dir(path)
+        .map(|entries| {
+            entries
+                .filter_map(Result::ok)
+                .map(|entry| dir_size(&entry.path()))
+                .sum()
+        })
+        .unwrap_or(0)
+}
+
+fn now() -> Duration {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+}
+
+// Renames where possible, the moonlight dir can be on another filesystem
+fn move_path(from: &Path, to: &Path) -> crate::Result<()> {
+    if std::fs::rename(from, to).is_ok() {
+        return Ok(());
+    }
+
+    copy_path(from, to)?;
+    if from.is_dir() {
+        std::fs::remove_dir_all(from)?;
+    } else {
+        std::fs::remove_file(from)?;
+    }
+    Ok(())
+}
+
+fn copy_path(from: &Path, to: &Path) -> crate::Result<()> {
+    if !from.is_dir() {
+        std::fs::copy(from, to)?;
+        return Ok(());
+    }
+
+    std::fs::create_dir_all(to)?;
+    for entry in std::fs::read_dir(from)? {
+        let entry = entry?;
+        copy_path(&entry.path(), &to.join(entry.file_name()))?;
+    }
+    Ok(())
+}
+
+/// "5m ago" and friends, for showing journal entries.
+#[must_use]
+pub fn format_age(age: Duration) -> String {
+    let secs = age.as_secs();
+    match secs {
+        0..60 => String::from("just now"),
+        60..3600 => format!("{}m ago", secs / 60),
+        3600..86400 => format!("{}h ago", secs / 3600),
+        _ => format!("{}d ago", secs / 86400),
+    }
+}
@@ -0,0 +1,425 @@
+use crate::MoonlightError;
+use std::fmt::{Display, Formatter};
+use std::path::Path;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Line {
+    /// Comments, blank lines, kept as they were
+    Other(String),
+    Group {
+        raw: String,
+        name: String,
+    },
+    Entry {
+        raw: String,
+        key: String,
+        value: String,
+    },
+}
+
+impl Line {
+    fn raw(&self) -> &str {
+        match self {
+            Self::Other(raw) | Self::Group { raw, .. } | Self::Entry { raw, .. } => raw,
+        }
+    }
+}
+
+/// A GKeyFile, the ini-like format Flatpak keeps overrides in. Only the
+/// entries that get changed are rewritten, everything else (comments,
+/// ordering, spacing) comes back out exactly as it went in.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct KeyFile {
+    lines: Vec<Line>,
+    trailing_newline: bool,
+}
+
+impl KeyFile {
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// `None` if there's no file yet.
+    pub fn load(path: &Path) -> crate::Result<Option<Self>> {
+        match std::fs::read_to_string(path) {
+            Ok(contents) => Self::parse_lines(&contents)
+                .map(Some)
+                .map_err(|e| MoonlightError::Unknown(format!("{}: {e}", path.display()))),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Fails on anything GLib wouldn't load either, rather than guessing.
+    pub fn parse(contents: &str) -> crate::Result<Self> {
+        Self::parse_lines(contents).map_err(MoonlightError::Unknown)
+    }
+
+    fn parse_lines(contents: &str) -> Result<Self, String> {
+        let mut lines = contents.split('\n').map(String::from).collect::<Vec<_>>();
+        let trailing_newline = lines.last().is_some_and(String::is_empty);
+        if trailing_newline {
+            lines.pop();
+        }
+
+        let mut in_group = false;
+        let lines = lines
+            .into_iter()
+            .enumerate()
+            .map(|(i, raw)| {
+                let invalid = |reason: &str| format!("invalid key file, line {}: {reason}", i + 1);
+                let line = raw.trim_end_matches('\r').trim_start();
+
+                if line.is_empty() || line.starts_with('#') {
+                    return Ok(Line::Other(raw));
+                }
+
+                if let Some(group) = line.strip_prefix('[') {
+                    let name = group
+                        .trim_end()
+                        .strip_suffix(']')
+                        .filter(|name| !name.is_empty() && !name.contains(['[', ']']))
+                        .ok_or_else(|| invalid("bad group header"))?
+                        .to_string();
+                    in_group = true;
+                    return Ok(Line::Group { raw, name });
+                }
+
+                let (key, value) = line
+                    .split_once('=')
+                    .ok_or_else(|| invalid("not a group, key or comment"))?;
+                let key = key.trim_end();
+                if key.is_empty() {
+                    return Err(invalid("empty key"));
+                }
+                if !in_group {
+                    return Err(invalid("key outside of a group"));
+                }
+
+                Ok(Line::Entry {
+                    key: key.to_string(),
+                    value: value.trim_start().to_string(),
+                    raw,
+                })
+            })
+            .collect::<Result<_, _>>()?;
+
+        Ok(Self {
+            lines,
+            trailing_newline,
+        })
+    }
+
+    /// The raw value, still escaped. Later entries win, like in GLib.
+    #[must_use]
+    pub fn get(&self, group: &str, key: &str) -> Option<&str> {
+        self.find(group, key).map(|i| match &self.lines[i] {
+            Line::Entry { value, .. } => value.as_str(),
+            _ => unreachable!("find only returns entries"),
+        })
+    }
+
+    #[must_use]
+    pub fn get_list(&self, group: &str, key: &str) -> Option<Vec<String>> {
+        self.get(group, key).map(parse_list)
+    }
+
+    /// Replaces the entry where it is, or adds it to the end of the group.
+    pub fn set(&mut self, group: &str, key: &str, value: &str) {
+        if let Some(i) = self.find(group, key) {
+            // Keep the indent and spacing around `=` the file already had
+            let Line::Entry {
+                raw, value: old, ..
+            } = &self.lines[i]
+            else {
+                unreachable!("find only returns entries");
+            };
+            let prefix = raw.trim_end_matches('\r');
+            let prefix = &prefix[..prefix.len() - old.len()];
+            let cr = if raw.ends_with('\r') { "\r" } else { "" };
+            self.lines[i] = Line::Entry {
+                raw: format!("{prefix}{value}{cr}"),
+                key: key.to_string(),
+                value: value.to_string(),
+            };
+            return;
+        }
+
+        let line = Line::Entry {
+            raw: format!("{key}={value}"),
+            key: key.to_string(),
+            value: value.to_string(),
+        };
+        match self.group_end(group) {
+            Some(end) => self.lines.insert(end, line),
+            None => {
+                if self
+                    .lines
+                    .last()
+                    .is_some_and(|last| !last.raw().trim().is_empty())
+                {
+                    self.lines.push(Line::Other(String::new()));
+                }
+                self.lines.push(Line::Group {
+                    raw: format!("[{group}]"),
+                    name: group.to_string(),
+                });
+                self.lines.push(line);
+                self.trailing_newline = true;
+            }
+        }
+    }
+
+    pub fn set_list(&mut self, group: &str, key: &str, values: &[String]) {
+        self.set(group, key, &format_list(values));
+    }
+
+    /// Returns whether the key was there.
+    pub fn remove(&mut self, group: &str, key: &str) -> bool {
+        let mut removed = false;
+        while let Some(i) = self.find(group, key) {
+            self.lines.remove(i);
+            removed = true;
+        }
+        removed
+    }
+
+    fn find(&self, group: &str, key: &str) -> Option<usize> {
+        let mut current = None;
+        let mut found = None;
+        for (i, line) in self.lines.iter().enumerate() {
+            match line {
+                Line::Group { name, .. } => current = Some(name.as_str()),
+                Line::Entry { key: k, .. } if current == Some(group) && k == key => {
+                    found = Some(i);
+                }
+                _ => {}
+            }
+        }
+        found
+    }
+
+    // Just past the last entry (or header) of the last group with that name
+    fn group_end(&self, group: &str) -> Option<usize> {
+        let mut current = None;
+        let mut end = None;
+        for (i, line) in self.lines.iter().enumerate() {
+            match line {
+                Line::Group { name, .. } => {
+                    current = Some(name.as_str());
+                    if name == group {
+                        end = Some(i + 1);
+                    }
+                }
+                Line::Entry { .. } if current == Some(group) => end = Some(i + 1),
+                _ => {}
+            }
+        }
+        end
+    }
+}
+
+impl Display for KeyFile {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        for (i, line) in self.lines.iter().enumerate() {
+            if i > 0 {
+                f.write_str("\n")?;
+            }
+            f.write_str(line.raw())?;
+        }
+        if self.trailing_newline && !self.lines.is_empty() {
+            f.write_str("\n")?;
+        }
+        Ok(())
+    }
+}
+
+/// Splits a list value on unescaped `;`, unescaping each item like GLib.
+#[must_use]
+pub fn parse_list(value: &str) -> Vec<String> {
+    let mut items = Vec::new();
+    let mut item = String::new();
+    let mut chars = value.chars();
+
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => match chars.next() {
+                Some('s') => item.push(' '),
+                Some('n') => item.push('\n'),
+                Some('t') => item.push('\t'),
+                Some('r') => item.push('\r'),
+                Some(other) => item.push(other),
+                None => item.push('\\'),
+            },
+            ';' => items.push(std::mem::take(&mut item)),
+            c => item.push(c),
+        }
+    }
+    if !item.is_empty() {
+        items.push(item);
+    }
+
+    items
+}
+
+/// The other way around from [`parse_list`], with the trailing `;` GLib writes.
+#[must_use]
+pub fn format_list(items: &[String]) -> String {
+    let mut value = String::new();
+    for item in items {
+        for (i, c) in item.chars().enumerate() {
+            match c {
+                ' ' if i == 0 => value.push_str("\\s"),
+                '\n' => value.push_str("\\n"),
+                '\t' => value.push_str("\\t"),
+                '\r' => value.push_str("\\r"),
+                '\\' => value.push_str("\\\\"),
+                ';' => value.push_str("\\;"),
+                c => value.push(c),
+            }
+        }
+        value.push(';');
+    }
+    value
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    const OVERRIDES: &str = "# Written by hand\n[Context]\nfilesystems=xdg-config/moonlight-mod:ro;~/Music;\n  shared = network;ipc;\n\n[Environment]\nFOO=bar\n";
+
+    fn round_trip(contents: &str) {
+        assert_eq!(KeyFile::parse(contents).unwrap().to_string(), contents);
+    }
+
+    #[test]
+    fn round_trips_untouched() {
+        round_trip(OVERRIDES);
+        round_trip(&OVERRIDES.replace('\n', "\r\n"));
+        round_trip(OVERRIDES.trim_end());
+        round_trip("");
+        round_trip("\n\n# just comments\n");
+    }
+
+    #[test]
+    fn edits_in_place() {
+        let mut file = KeyFile::parse(OVERRIDES).unwrap();
+        file.set(
+            "Context",
+            "filesystems",
+            "xdg-config/moonlight-mod:rw;~/Music;",
+        );
+        file.set("Context", "shared", "network;");
+        assert_eq!(
+            file.to_string(),
+            OVERRIDES
+                .replace(":ro;", ":rw;")
+                .replace("shared = network;ipc;", "shared = network;")
+        );
+    }
+
+    #[test]
+    fn keeps_crlf_and_missing_newline_when_editing() {
+        let crlf = OVERRIDES.replace('\n', "\r\n");
+        let mut file = KeyFile::parse(&crlf).unwrap();
+        file.set("Environment", "FOO", "baz");
+        assert_eq!(file.to_string(), crlf.replace("FOO=bar", "FOO=baz"));
+
+        let mut file = KeyFile::parse("[Context]\nshared=ipc;").unwrap();
+        file.set("Context", "shared", "network;");
+        assert_eq!(file.to_string(), "[Context]\nshared=network;");
+    }
+
+    #[test]
+    fn adds_to_the_end_of_groups() {
+        let mut file = KeyFile::parse(OVERRIDES).unwrap();
+        file.set("Context", "devices", "dri;");
+        file.set(
+            "Session Bus Policy",
+            "org.freedesktop.Notifications",
+            "talk",
+        );
+        assert_eq!(
+            file.to_string(),
+            OVERRIDES.replace("ipc;\n", "ipc;\ndevices=dri;\n")
+                + "\n[Session Bus Policy]\norg.freedesktop.Notifications=talk\n"
+        );
+
+        let mut file = KeyFile::new();
+        file.set_list("Context", "filesystems", &[String::from("home")]);
+        assert_eq!(file.to_string(), "[Context]\nfilesystems=home;\n");
+    }
+
+    #[test]
+    fn duplicate_groups_and_keys() {
+        let contents = "[Context]\nshared=ipc;\n[Environment]\nA=1\n[Context]\nshared=network;\n";
+        let mut file = KeyFile::parse(contents).unwrap();
+        // GLib goes with the last one
+        assert_eq!(file.get("Context", "shared"), Some("network;"));
+
+        file.set("Context", "devices", "dri;");
+        assert_eq!(file.to_string(), contents.to_string() + "devices=dri;\n");
+
+        assert!(file.remove("Context", "shared"));
+        assert_eq!(file.get("Context", "shared"), None);
+        assert_eq!(
+            file.to_string(),
+            "[Context]\n[Environment]\nA=1\n[Context]\ndevices=dri;\n"
+        );
+        assert!(!file.remove("Context", "shared"));
+    }
+
+    #[test]
+    fn escaped_lists() {
+        let items = vec![
+            String::from(" leading space"),
+            String::from("semi;colon"),
+            String::from("back\\slash"),
+            String::from("tab\tand\nnewline"),
+        ];
+        let value = format_list(&items);
+        assert_eq!(
+            value,
+            r"\sleading space;semi\;colon;back\\slash;tab\tand\nnewline;"
+        );
+        assert_eq!(parse_list(&value), items);
+
+        let mut file = KeyFile::parse(OVERRIDES).unwrap();
+        file.set_list("Context", "filesystems", &items);
+        let file = KeyFile::parse(&file.to_string()).unwrap();
+        assert_eq!(file.get_list("Context", "filesystems"), Some(items));
+
+        // Without the trailing `;` too
+        assert_eq!(parse_list("a;b"), ["a", "b"]);
+        assert!(parse_list("").is_empty());
+    }
+
+    #[test]
+    fn rejects_what_glib_would() {
+        for contents in [
+            "shared=ipc;\n",
+            "[Context\nshared=ipc;\n",
+            "[]\n",
+            "[Context]\njust some text\n",
+            "[Context]\n=ipc;\n",
+        ] {
+            assert!(KeyFile::parse(contents).is_err(), "{contents:?}");
+        }
+    }
+
+    #[test]
+    fn load_errors_leave_the_file_alone() {
+        let tmp = TempDir::new().unwrap();
+        let path = tmp.path().join("com.discordapp.Discord");
+        assert_eq!(KeyFile::load(&path).unwrap(), None);
+
+        let broken = "[Context]\nfilesystems=home;\nthis isn't a key file\n";
+        std::fs::write(&path, broken).unwrap();
+        let err = KeyFile::load(&path).unwrap_err().to_string();
+        assert!(err.contains("line 3"), "{err}");
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), broken);
+    }
+}
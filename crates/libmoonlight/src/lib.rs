@@ -1,7 +1,39 @@
+mod asar;
+mod backup;
+mod detect;
 mod error;
+mod helper;
 mod installer;
+mod journal;
+mod keyfile;
+mod patch_state;
+mod plan;
+mod process;
+mod registry;
+mod sandbox;
+#[cfg(target_os = "linux")]
+mod service;
+mod stub;
 mod util;
+#[cfg(target_os = "linux")]
+mod watch;
+pub use asar::*;
+pub use backup::*;
+pub use detect::*;
 pub use error::*;
+pub use helper::*;
 pub use installer::Installer;
+pub use journal::*;
+pub use keyfile::*;
+pub use patch_state::*;
+pub use plan::*;
+pub use process::*;
+pub use registry::*;
+pub use sandbox::*;
+#[cfg(target_os = "linux")]
+pub use service::*;
+pub use stub::*;
 pub use util::*;
+#[cfg(target_os = "linux")]
+pub use watch::*;
 pub mod types;
@@ -0,0 +1,379 @@
+use crate::types::{Branch, BrokenState, DetectedInstall, PatchState, PatchStrategy};
+use crate::{get_app_dir, Asar, DetectorEnv, Stub, PATCHED_ASAR, STUB_CONFIG};
+use std::path::{Path, PathBuf};
+
+// OpenAsar is tiny compared to the real app.asar, so don't bother scanning
+// anything bigger than this
+const OPENASAR_MAX_SIZE: u64 = 1024 * 1024;
+
+pub fn detect_patch_state(install: &DetectedInstall) -> crate::Result<PatchState> {
+    detect_patch_state_with(install, &DetectorEnv::current())
+}
+
+/// [`detect_patch_state`], looking for Discord's user data in `env`.
+pub fn detect_patch_state_with(
+    install: &DetectedInstall,
+    env: &DetectorEnv,
+) -> crate::Result<PatchState> {
+    let app_dir = get_app_dir(&install.path)?;
+    let asar = app_dir.join("app.asar");
+    let patched_asar = app_dir.join(PATCHED_ASAR);
+    let app = app_dir.join("app");
+    let wrapped = is_moonlight_asar(&asar);
+
+    if let Some(broken) = detect_broken_state(&asar, &patched_asar, &app, wrapped) {
+        return Ok(PatchState::Broken(broken));
+    }
+
+    if wrapped {
+        return Ok(PatchState::Moonlight);
+    }
+
+    if !asar.exists() {
+        if is_moonlight_app(&app) {
+            return Ok(PatchState::Moonlight);
+        }
+
+        if is_vencord_app(&app) {
+            return Ok(PatchState::Vencord);
+        }
+
+        return Ok(PatchState::Unknown);
+    }
+
+    if is_openasar(&asar) {
+        return Ok(PatchState::OpenAsar);
+    }
+
+    if is_betterdiscord(install, env) {
+        return Ok(PatchState::BetterDiscord);
+    }
+
+    Ok(PatchState::Unpatched)
+}
+
+fn detect_broken_state(
+    asar: &Path,
+    patched_asar: &Path,
+    app: &Path,
+    wrapped: bool,
+) -> Option<BrokenState> {
+    // Leave other mods' app folders alone, they can fix their own mess
+    if is_vencord_app(app) {
+        return None;
+    }
+
+    match (asar.exists(), patched_asar.exists()) {
+        (true, true) if !wrapped => Some(BrokenState::DuplicateAsar),
+        (_, false) if wrapped || looks_like_moonlight_app(app) => {
+            Some(BrokenState::MissingPatchedAsar)
+        }
+        (false, true) if !is_moonlight_app(app) => Some(BrokenState::IncompleteApp),
+        _ => None,
+    }
+}
+
+fn is_moonlight_app(app: &Path) -> bool {
+    let read = |name: &str| std::fs::read_to_string(app.join(name));
+    match (read("package.json"), read("injector.js")) {
+        (Ok(package), Ok(injector)) => Stub::is_stub(&package, &injector),
+        _ => false,
+    }
+}
+
+/// Whether app.asar is one we made to wrap the real one, see
+/// [`PatchStrategy::Asar`](crate::types::PatchStrategy::Asar).
+#[must_use]
+pub fn is_moonlight_asar(asar: &Path) -> bool {
+    // Ours only holds the stub, don't go parsing Discord's
+    let small = std::fs::metadata(asar).is_ok_and(|m| m.is_file() && m.len() <= OPENASAR_MAX_SIZE);
+    small
+        && Asar::open(asar).is_ok_and(|asar| {
+            match (
+                asar.read_to_string("package.json"),
+                asar.read_to_string("injector.js"),
+            ) {
+                (Ok(package), Ok(injector)) => Stub::is_stub(&package, &injector),
+                _ => false,
+            }
+        })
+}
+
+// Any trace of us, even if files are missing
+fn looks_like_moonlight_app(app: &Path) -> bool {
+    let injector = std::fs::read_to_string(app.join("injector.js"))
+        .is_ok_and(|injector| Stub::is_stub_injector(&injector));
+    let package = std::fs::read_to_string(app.join("package.json")).is_ok_and(|package| {
+        Stub::is_stub_package(&package)
+            || serde_json::from_str::<serde_json::Value>(&package)
+                .is_ok_and(|package| package["main"] == "./injector.js")
+    });
+
+    injector || package || app.join(STUB_CONFIG).exists()
+}
+
+/// Where the stub's config lives: the app folder, or next to app.asar when
+/// the stub is wrapped in one.
+#[must_use]
+pub fn get_stub_dir(install: &DetectedInstall) -> Option<PathBuf> {
+    let app_dir = get_app_dir(&install.path).ok()?;
+    let app = app_dir.join("app");
+    if is_moonlight_app(&app) {
+        return Some(app);
+    }
+
+    is_moonlight_asar(&app_dir.join("app.asar")).then_some(app_dir)
+}
+
+/// What the install was patched with last, as long as the stub's config is
+/// still around, even if the install is broken.
+#[must_use]
+pub fn get_patch_strategy(install: &DetectedInstall) -> PatchStrategy {
+    let Ok(app_dir) = get_app_dir(&install.path) else {
+        return PatchStrategy::default();
+    };
+
+    [app_dir.join("app"), app_dir]
+        .iter()
+        .find_map(|dir| Stub::read(dir))
+        .map(|stub| stub.strategy)
+        .unwrap_or_default()
+}
+
+/// Version of the moonlight stub in the install, if there is one.
+#[must_use]
+pub fn get_stub_version(install: &DetectedInstall) -> Option<u32> {
+    let dir = get_stub_dir(install)?;
+    Some(Stub::version(&dir).unwrap_or(0))
+}
+
+fn is_vencord_app(app: &Path) -> bool {
+    if !app.with_file_name(PATCHED_ASAR).exists() {
+        return false;
+    }
+
+    std::fs::read_to_string(app.join("index.js")).is_ok_and(|index| {
+        let index = index.to_lowercase();
+        index.contains("vencord") || index.contains("equicord") || index.contains("patcher.js")
+    })
+}
+
+fn is_openasar(asar: &Path) -> bool {
+    let small = std::fs::metadata(asar).is_ok_and(|m| m.len() <= OPENASAR_MAX_SIZE);
+    small && std::fs::read(asar).is_ok_and(|bytes| contains(&bytes, b"OpenAsar"))
+}
+
+fn is_betterdiscord(install: &DetectedInstall, env: &DetectorEnv) -> bool {
+    let mut roots = vec![install.path.join("modules")];
+    if let Some(user_data) = get_user_data_dir(install, env) {
+        // <user data>/0.0.x/modules
+        if let Ok(entries) = std::fs::read_dir(user_data) {
+            roots.extend(
+                entries
+                    .filter_map(Result::ok)
+                    .map(|e| e.path().join("modules")),
+            );
+        }
+    }
+
+    roots.iter().any(|modules| {
+        let Ok(entries) = std::fs::read_dir(modules) else {
+            return false;
+        };
+
+        entries
+            .filter_map(Result::ok)
+            .filter(|e| {
+                e.file_name()
+                    .to_string_lossy()
+                    .starts_with("discord_desktop_core")
+            })
+            .any(|e| {
+                std::fs::read_to_string(e.path().join("discord_desktop_core/index.js"))
+                    .or_else(|_| std::fs::read_to_string(e.path().join("index.js")))
+                    .is_ok_and(|index| index.to_lowercase().contains("betterdiscord"))
+            })
+    })
+}
+
+// Where Discord keeps its settings and (on older layouts) its modules
+fn get_user_data_dir(install: &DetectedInstall, env: &DetectorEnv) -> Option<PathBuf> {
+    let name = user_data_name(install.branch);
+
+    if let Some(flatpak_id) = install.flatpak_id.as_deref() {
+        return Some(
+            env.home
+                .join(".var/app")
+                .join(flatpak_id)
+                .join("config")
+                .join(name),
+        );
+    }
+
+    match std::env::consts::OS {
+        "windows" => env.var("APPDATA").map(|appdata| appdata.join(name)),
+        "macos" => Some(env.home.join("Library/Application Support").join(name)),
+        "linux" => Some(env.dot_config().join(name)),
+        _ => None,
+    }
+}
+
+const fn user_data_name(branch: Branch) -> &'static str {
+    match branch {
+        Branch::Stable => "discord",
+        Branch::PTB => "discordptb",
+        Branch::Canary => "discordcanary",
+        Branch::Development => "discorddevelopment",
+    }
+}
+
+fn contains(haystack: &[u8], needle: &[u8]) -> bool {
+    haystack.windows(needle.len()).any(|w| w == needle)
+}
+
+#[cfg(all(test, target_os = "linux"))]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    fn install(tmp: &TempDir) -> DetectedInstall {
+        let path = tmp.path().join("Discord");
+        std::fs::create_dir_all(path.join("resources")).unwrap();
+        std::fs::write(path.join("resources/app.asar"), "not really an asar").unwrap();
+        DetectedInstall::new(Branch::Stable, path, None)
+    }
+
+    fn detect(install: &DetectedInstall, tmp: &TempDir) -> PatchState {
+        let env = DetectorEnv::new(tmp.path().to_path_buf(), tmp.path().join("home"));
+        detect_patch_state_with(install, &env).unwrap()
+    }
+
+    // app.asar moved aside and an app folder with `index` in its place
+    fn app_folder(install: &DetectedInstall, index: &str) -> PathBuf {
+        let resources = install.path.join("resources");
+        std::fs::rename(resources.join("app.asar"), resources.join(PATCHED_ASAR)).unwrap();
+        let app = resources.join("app");
+        std::fs::create_dir(&app).unwrap();
+        std::fs::write(app.join("index.js"), index).unwrap();
+        app
+    }
+
+    fn betterdiscord(user_data: &Path) {
+        let core = user_data.join("0.0.90/modules/discord_desktop_core_1/discord_desktop_core");
+        std::fs::create_dir_all(&core).unwrap();
+        std::fs::write(core.join("index.js"), "require(\"BetterDiscord\");").unwrap();
+    }
+
+    #[test]
+    fn unpatched() {
+        let tmp = TempDir::new().unwrap();
+        let env = DetectorEnv::new(tmp.path().to_path_buf(), tmp.path().join("home"));
+        assert_eq!(
+            detect_patch_state_with(&install(&tmp), &env).unwrap(),
+            PatchState::Unpatched
+        );
+    }
+
+    #[test]
+    fn betterdiscord_in_env_user_data() {
+        let tmp = TempDir::new().unwrap();
+        let config = tmp.path().join("config");
+        betterdiscord(&config.join("discord"));
+
+        let env = DetectorEnv::new(tmp.path().to_path_buf(), tmp.path().join("home"))
+            .with_var("XDG_CONFIG_HOME", &config);
+        assert_eq!(
+            detect_patch_state_with(&install(&tmp), &env).unwrap(),
+            PatchState::BetterDiscord
+        );
+
+        // Someone else's user data doesn't count
+        let elsewhere = DetectorEnv::new(tmp.path().to_path_buf(), tmp.path().join("home"));
+        assert_eq!(
+            detect_patch_state_with(&install(&tmp), &elsewhere).unwrap(),
+            PatchState::Unpatched
+        );
+    }
+
+    #[test]
+    fn betterdiscord_in_flatpak_user_data() {
+        let tmp = TempDir::new().unwrap();
+        let home = tmp.path().join("home");
+        betterdiscord(&home.join(".var/app/com.discordapp.Discord/config/discord"));
+
+        let mut install = install(&tmp);
+        install.flatpak_id = Some(String::from("com.discordapp.Discord"));
+        let env = DetectorEnv::new(tmp.path().to_path_buf(), home);
+        assert_eq!(
+            detect_patch_state_with(&install, &env).unwrap(),
+            PatchState::BetterDiscord
+        );
+    }
+
+    #[test]
+    fn vencord() {
+        let tmp = TempDir::new().unwrap();
+        let install = install(&tmp);
+        app_folder(&install, "require(\"/home/user/Vencord/dist/patcher.js\");");
+        assert_eq!(detect(&install, &tmp), PatchState::Vencord);
+
+        // Without Discord's app.asar next to it, it isn't Vencord's to fix
+        std::fs::remove_file(install.path.join("resources").join(PATCHED_ASAR)).unwrap();
+        assert_eq!(detect(&install, &tmp), PatchState::Unknown);
+    }
+
+    #[test]
+    fn equicord() {
+        let tmp = TempDir::new().unwrap();
+        let install = install(&tmp);
+        app_folder(
+            &install,
+            "require(\"/opt/Equicord/equicordDesktopMain.js\");",
+        );
+        assert_eq!(detect(&install, &tmp), PatchState::Vencord);
+    }
+
+    #[test]
+    fn openasar() {
+        let tmp = TempDir::new().unwrap();
+        let install = install(&tmp);
+        let asar = install.path.join("resources/app.asar");
+        std::fs::write(&asar, "// OpenAsar").unwrap();
+        assert_eq!(detect(&install, &tmp), PatchState::OpenAsar);
+
+        // Too big to be OpenAsar, whatever it mentions
+        let mut big = vec![b' '; OPENASAR_MAX_SIZE as usize];
+        big.extend_from_slice(b"OpenAsar");
+        std::fs::write(&asar, big).unwrap();
+        assert_eq!(detect(&install, &tmp), PatchState::Unpatched);
+    }
+
+    #[test]
+    fn moonlight_stub() {
+        let tmp = TempDir::new().unwrap();
+        let install = install(&tmp);
+        let stub = Stub::default();
+        let app = app_folder(&install, "");
+        std::fs::write(app.join("package.json"), stub.package_json(None)).unwrap();
+        std::fs::write(app.join("injector.js"), stub.injector()).unwrap();
+        assert_eq!(detect(&install, &tmp), PatchState::Moonlight);
+
+        // Wrapped in an asar instead
+        std::fs::remove_dir_all(&app).unwrap();
+        let asar = install.path.join("resources/app.asar");
+        stub.to_asar(None).unwrap().write(&asar).unwrap();
+        assert_eq!(detect(&install, &tmp), PatchState::Moonlight);
+        assert!(is_moonlight_asar(&asar));
+    }
+
+    #[test]
+    fn unknown_app_folder() {
+        let tmp = TempDir::new().unwrap();
+        let install = install(&tmp);
+        let resources = install.path.join("resources");
+        std::fs::remove_file(resources.join("app.asar")).unwrap();
+        std::fs::create_dir(resources.join("app")).unwrap();
+        std::fs::write(resources.join("app/index.js"), "require(\"./something\");").unwrap();
+        assert_eq!(detect(&install, &tmp), PatchState::Unknown);
+    }
+}
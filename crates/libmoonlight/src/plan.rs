@@ -0,0 +1,164 @@
+use crate::types::InstallId;
+use crate::JournalStep;
+use serde::{Deserialize, Serialize};
+use std::fmt::{Display, Formatter};
+use std::path::PathBuf;
+
+/// Something an operation would do, for dry runs.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum PlanStep {
+    Download {
+        url: String,
+        to: PathBuf,
+    },
+    Rename {
+        from: PathBuf,
+        to: PathBuf,
+    },
+    Copy {
+        from: PathBuf,
+        to: PathBuf,
+    },
+    CreateDir {
+        path: PathBuf,
+    },
+    /// `backup` is where the old contents are kept, if there are any
+    WriteFile {
+        path: PathBuf,
+        backup: Option<PathBuf>,
+    },
+    Remove {
+        path: PathBuf,
+        backup: Option<PathBuf>,
+    },
+    EditFlatpakOverrides {
+        id: String,
+        path: PathBuf,
+        backup: Option<PathBuf>,
+    },
+    SetPatched {
+        id: InstallId,
+        patched: bool,
+    },
+    CloseDiscord {
+        path: PathBuf,
+        relaunch: bool,
+    },
+    Run {
+        command: Vec<String>,
+    },
+}
+
+impl Display for PlanStep {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Download { url, to } => write!(f, "download {url} into {}", to.display()),
+            Self::Rename { from, to } => {
+                write!(f, "rename {} to {}", from.display(), to.display())
+            }
+            Self::Copy { from, to } => write!(f, "copy {} to {}", from.display(), to.display()),
+            Self::CreateDir { path } => write!(f, "create directory {}", path.display()),
+            Self::WriteFile { path, backup } => {
+                write!(f, "write {}", path.display())?;
+                write_backup(f, backup.as_ref())
+            }
+            Self::Remove { path, backup } => {
+                write!(f, "remove {}", path.display())?;
+                write_backup(f, backup.as_ref())
+            }
+            Self::EditFlatpakOverrides { id, path, backup } => {
+                write!(f, "edit Flatpak overrides for {id} at {}", path.display())?;
+                write_backup(f, backup.as_ref())
+            }
+            Self::SetPatched { id, patched: true } => write!(f, "remember {id} as patched"),
+            Self::SetPatched { id, patched: false } => write!(f, "forget {id} was patched"),
+            Self::CloseDiscord { path, relaunch } => {
+                write!(f, "close Discord at {}", path.display())?;
+                if *relaunch {
+                    f.write_str(" and reopen it afterwards")?;
+                }
+                Ok(())
+            }
+            Self::Run { command } => write!(f, "run {}", command.join(" ")),
+        }
+    }
+}
+
+fn write_backup(f: &mut Formatter<'_>, backup: Option<&PathBuf>) -> std::fmt::Result {
+    match backup {
+        Some(backup) => write!(f, " (backed up to {})", backup.display()),
+        None => Ok(()),
+    }
+}
+
+impl From<&JournalStep> for PlanStep {
+    fn from(step: &JournalStep) -> Self {
+        match step.clone() {
+            JournalStep::Rename { from, to } => Self::Rename { from, to },
+            JournalStep::CreateDir { path } => Self::CreateDir { path },
+            JournalStep::WriteFile { path, backup } => Self::WriteFile { path, backup },
+            JournalStep::EditFlatpakOverrides { id, path, backup } => {
+                Self::EditFlatpakOverrides { id, path, backup }
+            }
+            JournalStep::Remove { path, backup } => Self::Remove {
+                path,
+                backup: Some(backup),
+            },
+            JournalStep::SetPatched { id, previous } => Self::SetPatched {
+                id,
+                patched: !previous,
+            },
+        }
+    }
+}
+
+/// Everything an operation would touch, in order.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+pub struct Plan {
+    pub steps: Vec<PlanStep>,
+}
+
+impl Plan {
+    #[must_use]
+    pub const fn new() -> Self {
+        Self { steps: Vec::new() }
+    }
+
+    pub fn push(&mut self, step: PlanStep) {
+        self.steps.push(step);
+    }
+
+    pub fn extend(&mut self, other: Self) {
+        self.steps.extend(other.steps);
+    }
+
+    #[must_use]
+    pub fn is_empty(&self) -> bool {
+        self.steps.is_empty()
+    }
+
+    #[must_use]
+    pub fn to_json(&self) -> String {
+        serde_json::to_string_pretty(self).expect("serialization to succeed")
+    }
+}
+
+impl From<Vec<PlanStep>> for Plan {
+    fn from(steps: Vec<PlanStep>) -> Self {
+        Self { steps }
+    }
+}
+
+impl Display for Plan {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        if self.steps.is_empty() {
+            return writeln!(f, "nothing to do");
+        }
+
+        for (i, step) in self.steps.iter().enumerate() {
+            writeln!(f, "{:>3}. {step}", i + 1)?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,609 @@
+use crate::types::{DetectedInstall, InstallId};
+use crate::{host_command, is_sandboxed, to_sandbox_path, MoonlightError};
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+/// Finds running Discord processes, so it works without `killall` and can
+/// tell native and Flatpak installs apart. Linux walks `/proc`, macOS asks
+/// `ps` and Windows asks PowerShell. Anywhere else it never finds anything.
+///
+/// Inside a Flatpak sandbox `/proc` only has the sandbox in it, so the host
+/// is asked for the same information instead.
+#[derive(Debug, Clone)]
+pub struct ProcessInspector {
+    source: ProcessSource,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum ProcessSource {
+    Proc(PathBuf),
+    Host,
+    Ps,
+    PowerShell,
+    None,
+}
+
+#[derive(Debug, Clone, Default)]
+struct ProcessInfo {
+    pid: u32,
+    /// As the host sees it
+    exe: Option<PathBuf>,
+    flatpak_app: Option<String>,
+    /// Electron's helper processes, which have a --type=
+    helper: bool,
+}
+
+// One host call for every process instead of one per file, since
+// flatpak-spawn is slow. Sections follow each marker line.
+const HOST_PROCESS_SCRIPT: &str = r#"
+cd /proc || exit 0
+echo '#exe'
+find . -mindepth 2 -maxdepth 2 -name exe -printf '%h\t%l\n' 2>/dev/null
+echo '#app'
+grep -s -H -m1 '^name=' [0-9]*/root/.flatpak-info
+echo '#helper'
+grep -s -l -z -- '^--type=' [0-9]*/cmdline
+"#;
+
+// Tab separated, since paths and command lines have spaces in them
+const POWERSHELL_PROCESS_SCRIPT: &str = r#"Get-CimInstance Win32_Process | ForEach-Object { "$($_.ProcessId)`t$($_.ExecutablePath)`t$($_.CommandLine)" }"#;
+
+impl Default for ProcessInspector {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ProcessInspector {
+    #[must_use]
+    pub fn new() -> Self {
+        let source = match std::env::consts::OS {
+            "linux" if is_sandboxed() => ProcessSource::Host,
+            "linux" => ProcessSource::Proc(PathBuf::from("/proc")),
+            "macos" => ProcessSource::Ps,
+            "windows" => ProcessSource::PowerShell,
+            _ => ProcessSource::None,
+        };
+        Self { source }
+    }
+
+    #[must_use]
+    pub const fn with_proc_root(proc_root: PathBuf) -> Self {
+        Self {
+            source: ProcessSource::Proc(proc_root),
+        }
+    }
+
+    /// Whether processes can be found at all here. Without that, closing
+    /// Discord around an operation can't work.
+    #[must_use]
+    pub fn is_supported(&self) -> bool {
+        self.source != ProcessSource::None
+    }
+
+    /// PIDs of every process belonging to `install`, sorted.
+    #[must_use]
+    pub fn find_pids(&self, install: &DetectedInstall) -> Vec<u32> {
+        self.find(install)
+            .into_iter()
+            .map(|info| info.pid)
+            .collect()
+    }
+
+    /// Which of `installs` are running, going through the processes once
+    /// for all of them.
+    #[must_use]
+    pub fn find_running(&self, installs: &[DetectedInstall]) -> Vec<InstallId> {
+        let processes = self.processes();
+        installs
+            .iter()
+            .filter(|install| {
+                let install_path = canonical_path(install);
+                processes
+                    .iter()
+                    .any(|info| matches(info, install, &install_path))
+            })
+            .map(|install| install.id.clone())
+            .collect()
+    }
+
+    fn find(&self, install: &DetectedInstall) -> Vec<ProcessInfo> {
+        let install_path = canonical_path(install);
+
+        let mut found: Vec<ProcessInfo> = self
+            .processes()
+            .into_iter()
+            .filter(|info| matches(info, install, &install_path))
+            .collect();
+
+        found.sort_unstable_by_key(|info| info.pid);
+        found
+    }
+
+    fn processes(&self) -> Vec<ProcessInfo> {
+        match &self.source {
+            ProcessSource::Proc(proc_root) => proc_processes(proc_root),
+            ProcessSource::Host => host_processes(),
+            ProcessSource::Ps => listed_processes("ps", &["-axww", "-o", "pid=,comm="])
+                .map(|output| parse_ps_processes(&output))
+                .unwrap_or_default(),
+            ProcessSource::PowerShell => listed_processes(
+                "powershell",
+                &["-NoProfile", "-Command", POWERSHELL_PROCESS_SCRIPT],
+            )
+            .map(|output| parse_powershell_processes(&output))
+            .unwrap_or_default(),
+            ProcessSource::None => Vec::new(),
+        }
+    }
+
+    #[must_use]
+    pub fn is_running(&self, install: &DetectedInstall) -> bool {
+        !self.find_pids(install).is_empty()
+    }
+
+    /// The executable to run to start `install` again, based on the running
+    /// main process. Flatpak installs always go through `flatpak run`.
+    #[must_use]
+    pub fn relaunch_command(&self, install: &DetectedInstall) -> Option<RelaunchCommand> {
+        if let Some(flatpak_id) = install.flatpak_id.as_deref() {
+            return Some(RelaunchCommand::Flatpak(flatpak_id.to_string()));
+        }
+
+        // Child processes all get a --type=renderer/gpu-process/etc.
+        self.find(install)
+            .into_iter()
+            .find(|info| !info.helper)
+            .and_then(|info| info.exe)
+            .map(RelaunchCommand::Exe)
+    }
+}
+
+// On macOS the install is the app bundle's Resources, but the executables
+// are elsewhere in the bundle
+fn canonical_path(install: &DetectedInstall) -> PathBuf {
+    let path = if install.path.ends_with("Contents/Resources") {
+        install.path.ancestors().nth(2).unwrap_or(&install.path)
+    } else {
+        &install.path
+    };
+    std::fs::canonicalize(path).unwrap_or_else(|_| path.to_path_buf())
+}
+
+fn matches(info: &ProcessInfo, install: &DetectedInstall, install_path: &Path) -> bool {
+    // Inside a Flatpak the exe path is relative to the sandbox, so go by
+    // which app the sandbox belongs to instead. bwrap itself runs outside
+    // the sandbox, so we only catch what it spawned.
+    if let Some(flatpak_id) = install.flatpak_id.as_deref() {
+        return info.flatpak_app.as_deref() == Some(flatpak_id);
+    }
+
+    if info.flatpak_app.is_some() {
+        return false;
+    }
+
+    let Some(exe) = &info.exe else {
+        return false;
+    };
+
+    // Discord updating itself leaves the old binary deleted
+    let exe = exe
+        .to_str()
+        .and_then(|s| s.strip_suffix(" (deleted)"))
+        .map_or(exe.clone(), PathBuf::from);
+
+    to_sandbox_path(&exe).starts_with(install_path)
+}
+
+fn proc_processes(proc_root: &Path) -> Vec<ProcessInfo> {
+    let Ok(entries) = std::fs::read_dir(proc_root) else {
+        return Vec::new();
+    };
+
+    entries
+        .filter_map(Result::ok)
+        .filter_map(|entry| {
+            let pid = entry.file_name().to_str()?.parse::<u32>().ok()?;
+            let dir = entry.path();
+            let helper = std::fs::read(dir.join("cmdline")).is_ok_and(|cmdline| {
+                cmdline
+                    .split(|b| *b == 0)
+                    .any(|arg| arg.starts_with(b"--type="))
+            });
+
+            Some(ProcessInfo {
+                pid,
+                exe: std::fs::read_link(dir.join("exe")).ok(),
+                flatpak_app: flatpak_app(&dir),
+                helper,
+            })
+        })
+        .collect()
+}
+
+// Empty if the host can't be asked, same as not having /proc
+fn host_processes() -> Vec<ProcessInfo> {
+    let Ok(output) = host_command("sh")
+        .args(["-c", HOST_PROCESS_SCRIPT])
+        .stdin(std::process::Stdio::null())
+        .stderr(std::process::Stdio::null())
+        .output()
+    else {
+        return Vec::new();
+    };
+
+    parse_host_processes(&String::from_utf8_lossy(&output.stdout))
+}
+
+fn parse_host_processes(output: &str) -> Vec<ProcessInfo> {
+    let mut processes = BTreeMap::new();
+    let mut section = "";
+
+    for item in output.split(['\n', '\0']).filter(|item| !item.is_empty()) {
+        if let Some(marker) = item.strip_prefix('#') {
+            section = marker;
+            continue;
+        }
+
+        match section {
+            "exe" => {
+                if let Some((dir, exe)) = item.split_once('\t') {
+                    if let Some(info) = host_process(&mut processes, dir) {
+                        info.exe = Some(PathBuf::from(exe));
+                    }
+                }
+            }
+            "app" => {
+                if let Some((path, name)) = item.split_once(":name=") {
+                    if let Some(info) = host_process(&mut processes, path) {
+                        info.flatpak_app = Some(name.to_string());
+                    }
+                }
+            }
+            "helper" => {
+                if let Some(info) = host_process(&mut processes, item) {
+                    info.helper = true;
+                }
+            }
+            _ => {}
+        }
+    }
+
+    processes.into_values().collect()
+}
+
+fn listed_processes(program: &str, args: &[&str]) -> Option<String> {
+    let mut command = std::process::Command::new(program);
+    command
+        .args(args)
+        .stdin(std::process::Stdio::null())
+        .stderr(std::process::Stdio::null());
+    #[cfg(windows)]
+    {
+        use std::os::windows::process::CommandExt;
+        // CREATE_NO_WINDOW, or the GUI flashes a console every check
+        command.creation_flags(0x0800_0000);
+    }
+
+    let output = command.output().ok()?;
+    Some(String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+// `<pid> <exe>` per line, right aligned pids
+fn parse_ps_processes(output: &str) -> Vec<ProcessInfo> {
+    output
+        .lines()
+        .filter_map(|line| {
+            let (pid, exe) = line.trim_start().split_once(' ')?;
+            let exe = PathBuf::from(exe.trim_start());
+            Some(ProcessInfo {
+                pid: pid.parse().ok()?,
+                // Electron's helpers live in their own bundles in Frameworks
+                helper: exe.components().any(|c| c.as_os_str() == "Frameworks"),
+                exe: Some(exe),
+                flatpak_app: None,
+            })
+        })
+        .collect()
+}
+
+// `<pid>\t<exe>\t<command line>` per line, see POWERSHELL_PROCESS_SCRIPT
+fn parse_powershell_processes(output: &str) -> Vec<ProcessInfo> {
+    output
+        .lines()
+        .filter_map(|line| {
+            let mut fields = line.trim_end_matches('\r').splitn(3, '\t');
+            let pid = fields.next()?.trim().parse().ok()?;
+            let exe = fields.next().filter(|exe| !exe.is_empty());
+            let cmdline = fields.next().unwrap_or_default();
+            Some(ProcessInfo {
+                pid,
+                exe: exe.map(PathBuf::from),
+                flatpak_app: None,
+                helper: cmdline.contains(" --type="),
+            })
+        })
+        .collect()
+}
+
+// Everything is relative to /proc, so paths start with ./<pid>/ or <pid>/
+fn host_process<'a>(
+    processes: &'a mut BTreeMap<u32, ProcessInfo>,
+    path: &str,
+) -> Option<&'a mut ProcessInfo> {
+    let pid = path
+        .trim_start_matches("./")
+        .split('/')
+        .next()?
+        .parse::<u32>()
+        .ok()?;
+    Some(processes.entry(pid).or_insert_with(|| ProcessInfo {
+        pid,
+        ..ProcessInfo::default()
+    }))
+}
+
+// The app ID from the sandbox's /.flatpak-info, if the process is in one
+fn flatpak_app(proc_dir: &Path) -> Option<String> {
+    let info = std::fs::read_to_string(proc_dir.join("root/.flatpak-info")).ok()?;
+    let mut in_application = false;
+
+    for line in info.lines() {
+        let line = line.trim();
+        if line.starts_with('[') {
+            in_application = line == "[Application]";
+        } else if in_application {
+            if let Some(name) = line.strip_prefix("name=") {
+                return Some(name.to_string());
+            }
+        }
+    }
+
+    None
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RelaunchCommand {
+    Exe(PathBuf),
+    Flatpak(String),
+}
+
+impl RelaunchCommand {
+    pub fn spawn(&self) -> crate::Result<()> {
+        let mut command = match self {
+            Self::Exe(exe) => host_command(exe),
+            Self::Flatpak(id) => {
+                let mut command = host_command("flatpak");
+                command.args(["run", id]);
+                command
+            }
+        };
+
+        command
+            .stdin(std::process::Stdio::null())
+            .stdout(std::process::Stdio::null())
+            .stderr(std::process::Stdio::null())
+            .spawn()?;
+
+        Ok(())
+    }
+}
+
+/// Asks every process of `install` to exit with SIGTERM, and only SIGKILLs
+/// whatever is still around after `timeout`. Returns how to start it again,
+/// or `None` if it wasn't running. On Windows that's `taskkill`, then
+/// `taskkill /F`.
+pub fn close_discord(
+    install: &DetectedInstall,
+    timeout: Duration,
+) -> crate::Result<Option<RelaunchCommand>> {
+    let inspector = ProcessInspector::new();
+    if !inspector.is_supported() {
+        return Err(MoonlightError::Unknown(String::from(
+            "can't find Discord's processes on this platform, close it by hand",
+        )));
+    }
+    if !inspector.is_running(install) {
+        return Ok(None);
+    }
+
+    let relaunch = inspector.relaunch_command(install);
+
+    signal_all(&inspector, install, false)?;
+    if !wait_for_exit(&inspector, install, timeout) {
+        signal_all(&inspector, install, true)?;
+        if !wait_for_exit(&inspector, install, KILL_TIMEOUT) {
+            return Err(MoonlightError::Unknown(String::from(
+                "Discord is still running after being killed",
+            )));
+        }
+    }
+
+    Ok(relaunch)
+}
+
+/// How long Discord gets to exit on its own by default.
+pub const DEFAULT_CLOSE_TIMEOUT: Duration = Duration::from_secs(10);
+const KILL_TIMEOUT: Duration = Duration::from_secs(5);
+const POLL_INTERVAL: Duration = Duration::from_millis(250);
+
+fn wait_for_exit(
+    inspector: &ProcessInspector,
+    install: &DetectedInstall,
+    timeout: Duration,
+) -> bool {
+    let start = Instant::now();
+    loop {
+        if !inspector.is_running(install) {
+            return true;
+        }
+        if start.elapsed() >= timeout {
+            return false;
+        }
+        std::thread::sleep(POLL_INTERVAL);
+    }
+}
+
+#[cfg(unix)]
+fn signal_all(
+    inspector: &ProcessInspector,
+    install: &DetectedInstall,
+    force: bool,
+) -> crate::Result<()> {
+    use nix::sys::signal::{kill, Signal};
+    use nix::unistd::Pid;
+
+    let signal = if force {
+        Signal::SIGKILL
+    } else {
+        Signal::SIGTERM
+    };
+
+    // Host PIDs mean nothing in our namespace. Failures show up as Discord
+    // still running afterwards, like with ESRCH below.
+    if inspector.source == ProcessSource::Host {
+        let pids = inspector.find_pids(install);
+        if !pids.is_empty() {
+            host_command("kill")
+                .arg(format!("-{}", signal.as_str().trim_start_matches("SIG")))
+                .args(pids.iter().map(ToString::to_string))
+                .status()?;
+        }
+        return Ok(());
+    }
+
+    for pid in inspector.find_pids(install) {
+        let Ok(pid) = i32::try_from(pid) else {
+            continue;
+        };
+
+        match kill(Pid::from_raw(pid), signal) {
+            // Already gone, which is what we wanted anyway
+            Ok(()) | Err(nix::errno::Errno::ESRCH) => {}
+            Err(err) => return Err(std::io::Error::from(err).into()),
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(windows)]
+fn signal_all(
+    inspector: &ProcessInspector,
+    install: &DetectedInstall,
+    force: bool,
+) -> crate::Result<()> {
+    use std::os::windows::process::CommandExt;
+
+    for pid in inspector.find_pids(install) {
+        let mut command = std::process::Command::new("taskkill");
+        if force {
+            command.arg("/F");
+        }
+        // CREATE_NO_WINDOW, same as when listing processes
+        command
+            .args(["/PID", &pid.to_string()])
+            .creation_flags(0x0800_0000)
+            .status()?;
+    }
+
+    Ok(())
+}
+
+#[cfg(all(test, target_os = "linux"))]
+mod tests {
+    use super::*;
+    use crate::types::Branch;
+    use tempfile::TempDir;
+
+    fn process(proc_root: &Path, pid: u32, exe: &Path, cmdline: &[u8]) {
+        let dir = proc_root.join(pid.to_string());
+        std::fs::create_dir_all(&dir).unwrap();
+        std::os::unix::fs::symlink(exe, dir.join("exe")).unwrap();
+        std::fs::write(dir.join("cmdline"), cmdline).unwrap();
+    }
+
+    #[test]
+    fn finds_running_installs_in_one_pass() {
+        let tmp = TempDir::new().unwrap();
+        let proc_root = tmp.path().join("proc");
+        let stable = tmp.path().join("Discord");
+        let canary = tmp.path().join("DiscordCanary");
+        let ptb = tmp.path().join("DiscordPTB");
+        for dir in [&stable, &canary, &ptb] {
+            std::fs::create_dir_all(dir).unwrap();
+        }
+
+        process(&proc_root, 100, &stable.join("Discord"), b"Discord\0");
+        process(
+            &proc_root,
+            101,
+            &stable.join("Discord"),
+            b"Discord\0--type=renderer\0",
+        );
+        process(
+            &proc_root,
+            200,
+            &canary.join("DiscordCanary (deleted)"),
+            b"",
+        );
+        process(&proc_root, 300, Path::new("/usr/bin/sh"), b"sh\0");
+
+        let installs = [
+            DetectedInstall::new(Branch::Stable, stable, None),
+            DetectedInstall::new(Branch::Canary, canary, None),
+            DetectedInstall::new(Branch::PTB, ptb, None),
+        ];
+        let inspector = ProcessInspector::with_proc_root(proc_root);
+
+        assert_eq!(
+            inspector.find_running(&installs),
+            [installs[0].id.clone(), installs[1].id.clone()]
+        );
+        assert_eq!(inspector.find_pids(&installs[0]), [100, 101]);
+        assert_eq!(
+            inspector.relaunch_command(&installs[0]),
+            Some(RelaunchCommand::Exe(installs[0].path.join("Discord")))
+        );
+    }
+
+    #[test]
+    fn parses_ps() {
+        let output = "    1 /sbin/launchd\n  512 /Applications/Discord.app/Contents/MacOS/Discord\n  513 /Applications/Discord.app/Contents/Frameworks/Discord Helper (Renderer).app/Contents/MacOS/Discord Helper (Renderer)\n";
+        let processes = parse_ps_processes(output);
+        let pids: Vec<_> = processes.iter().map(|p| (p.pid, p.helper)).collect();
+        assert_eq!(pids, [(1, false), (512, false), (513, true)]);
+        assert_eq!(
+            processes[2].exe.as_deref(),
+            Some(Path::new("/Applications/Discord.app/Contents/Frameworks/Discord Helper (Renderer).app/Contents/MacOS/Discord Helper (Renderer)"))
+        );
+
+        // Matched against the whole bundle, not just Resources
+        let install = DetectedInstall::new(
+            Branch::Stable,
+            PathBuf::from("/Applications/Discord.app/Contents/Resources"),
+            None,
+        );
+        let matching: Vec<_> = processes
+            .iter()
+            .filter(|p| matches(p, &install, &canonical_path(&install)))
+            .map(|p| p.pid)
+            .collect();
+        assert_eq!(matching, [512, 513]);
+    }
+
+    #[test]
+    fn parses_powershell() {
+        let output = "4\t\t\r\n9001\tC:\\Users\\me\\AppData\\Local\\Discord\\app-1.0.9010\\Discord.exe\t\"C:\\Users\\me\\AppData\\Local\\Discord\\app-1.0.9010\\Discord.exe\"\r\n9002\tC:\\Users\\me\\AppData\\Local\\Discord\\app-1.0.9010\\Discord.exe\t\"C:\\Users\\me\\AppData\\Local\\Discord\\app-1.0.9010\\Discord.exe\" --type=renderer --field-trial-handle=1\r\n";
+        let processes = parse_powershell_processes(output);
+        let pids: Vec<_> = processes
+            .iter()
+            .map(|p| (p.pid, p.exe.is_some(), p.helper))
+            .collect();
+        assert_eq!(
+            pids,
+            [(4, false, false), (9001, true, false), (9002, true, true)]
+        );
+    }
+}
@@ -0,0 +1,212 @@
+use crate::types::{Branch, DetectedInstall, FlatpakOverrideChange, InstallId};
+use crate::{
+    detect_install_type, ensure_moonlight_dir, get_app_dir, get_moonlight_dir, to_sandbox_path,
+    MoonlightError,
+};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+pub const INSTALL_REGISTRY: &str = "installs.json";
+pub const PATCHED_INSTALLS: &str = "patched.json";
+pub const ADDED_FLATPAK_OVERRIDES: &str = "flatpak-overrides.json";
+
+/// A Discord install the user pointed us at because detection missed it.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct ManualInstall {
+    /// Path to the Discord executable
+    pub path: PathBuf,
+    /// Used instead of whatever detect_install_type guesses
+    pub branch: Option<Branch>,
+    pub label: Option<String>,
+}
+
+impl ManualInstall {
+    #[must_use]
+    pub fn to_detected(&self) -> Option<DetectedInstall> {
+        // Paths are kept as the host has them
+        let path = to_sandbox_path(&self.path);
+        let folder = path.parent()?;
+        let branch = self.branch.or_else(|| detect_install_type(&path))?;
+        get_app_dir(folder).ok().filter(|dir| dir.exists())?;
+
+        Some(DetectedInstall::new(branch, folder.to_path_buf(), None))
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct InstallRegistry {
+    pub installs: Vec<ManualInstall>,
+}
+
+impl InstallRegistry {
+    #[must_use]
+    pub fn path() -> PathBuf {
+        get_moonlight_dir().join(INSTALL_REGISTRY)
+    }
+
+    pub fn load() -> crate::Result<Self> {
+        Self::load_from(&Self::path())
+    }
+
+    pub fn load_from(path: &Path) -> crate::Result<Self> {
+        match std::fs::read_to_string(path) {
+            Ok(contents) => serde_json::from_str(&contents)
+                .map_err(|e| MoonlightError::Unknown(format!("invalid {INSTALL_REGISTRY}: {e}"))),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Self::default()),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    pub fn save(&self) -> crate::Result<()> {
+        ensure_moonlight_dir()?;
+        self.save_to(&Self::path())
+    }
+
+    pub fn save_to(&self, path: &Path) -> crate::Result<()> {
+        let json = serde_json::to_string_pretty(self).expect("registry serialization to succeed");
+        std::fs::write(path, json)?;
+        Ok(())
+    }
+
+    /// Adds an install, replacing any existing entry for the same install.
+    pub fn add(&mut self, install: ManualInstall) -> crate::Result<InstallId> {
+        let detected = install
+            .to_detected()
+            .ok_or_else(|| MoonlightError::InstallNotFound(install.path.display().to_string()))?;
+
+        self.remove(&detected.id);
+        self.installs.push(install);
+        Ok(detected.id)
+    }
+
+    #[must_use]
+    pub fn contains(&self, id: &InstallId) -> bool {
+        self.installs.iter().any(|install| {
+            install
+                .to_detected()
+                .is_some_and(|detected| &detected.id == id)
+        })
+    }
+
+    /// Returns whether anything was removed.
+    pub fn remove(&mut self, id: &InstallId) -> bool {
+        let len = self.installs.len();
+        self.installs.retain(|install| {
+            install
+                .to_detected()
+                .is_none_or(|detected| &detected.id != id)
+        });
+        self.installs.len() != len
+    }
+}
+
+/// Installs moonlight has patched, so they can be patched again after Discord
+/// updates itself.
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct PatchedInstalls {
+    pub installs: Vec<InstallId>,
+}
+
+impl PatchedInstalls {
+    #[must_use]
+    pub fn path() -> PathBuf {
+        get_moonlight_dir().join(PATCHED_INSTALLS)
+    }
+
+    pub fn load() -> crate::Result<Self> {
+        match std::fs::read_to_string(Self::path()) {
+            Ok(contents) => serde_json::from_str(&contents)
+                .map_err(|e| MoonlightError::Unknown(format!("invalid {PATCHED_INSTALLS}: {e}"))),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Self::default()),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    pub fn save(&self) -> crate::Result<()> {
+        ensure_moonlight_dir()?;
+        let json = serde_json::to_string_pretty(self).expect("serialization to succeed");
+        std::fs::write(Self::path(), json)?;
+        Ok(())
+    }
+
+    #[must_use]
+    pub fn contains(&self, id: &InstallId) -> bool {
+        self.installs.contains(id)
+    }
+
+    /// Returns whether the install wasn't already recorded.
+    pub fn insert(&mut self, id: InstallId) -> bool {
+        if self.contains(&id) {
+            return false;
+        }
+        self.installs.push(id);
+        true
+    }
+
+    /// Returns whether the install was recorded.
+    pub fn remove(&mut self, id: &InstallId) -> bool {
+        let len = self.installs.len();
+        self.installs.retain(|i| i != id);
+        self.installs.len() != len
+    }
+}
+
+/// Flatpak ids we gave access to the moonlight dir ourselves, so we know which
+/// overrides are ours to take away again.
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct AddedFlatpakOverrides {
+    /// By Flatpak id
+    #[serde(default)]
+    pub added: BTreeMap<String, Vec<FlatpakOverrideChange>>,
+}
+
+impl AddedFlatpakOverrides {
+    #[must_use]
+    pub fn path() -> PathBuf {
+        get_moonlight_dir().join(ADDED_FLATPAK_OVERRIDES)
+    }
+
+    pub fn load() -> crate::Result<Self> {
+        Self::load_from(&Self::path())
+    }
+
+    pub fn load_from(path: &Path) -> crate::Result<Self> {
+        match std::fs::read_to_string(path) {
+            Ok(contents) => serde_json::from_str(&contents).map_err(|e| {
+                MoonlightError::Unknown(format!("invalid {ADDED_FLATPAK_OVERRIDES}: {e}"))
+            }),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Self::default()),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    pub fn save_to(&self, path: &Path) -> crate::Result<()> {
+        let json = serde_json::to_string_pretty(self).expect("serialization to succeed");
+        std::fs::write(path, json)?;
+        Ok(())
+    }
+
+    #[must_use]
+    pub fn contains(&self, id: &str) -> bool {
+        self.added.contains_key(id)
+    }
+
+    pub fn insert(&mut self, id: &str, changes: Vec<FlatpakOverrideChange>) {
+        if changes.is_empty() {
+            return;
+        }
+
+        let added = self.added.entry(id.to_string()).or_default();
+        for change in changes {
+            if !added.iter().any(|c| c.entry == change.entry) {
+                added.push(change);
+            }
+        }
+    }
+
+    /// Forgets everything added for `id`, handing it back.
+    pub fn remove(&mut self, id: &str) -> Vec<FlatpakOverrideChange> {
+        self.added.remove(id).unwrap_or_default()
+    }
+}
@@ -0,0 +1,144 @@
+use crate::types::{FlatpakFilesystemOverride, FlatpakFilesystemOverridePermission};
+use crate::{KeyFile, MoonlightError};
+use std::ffi::{OsStr, OsString};
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::sync::OnceLock;
+
+/// Flatpak puts this at the root of every sandbox.
+pub const FLATPAK_INFO: &str = "/.flatpak-info";
+/// Where a sandbox sees the host's OS dirs, with `--filesystem=host-os`.
+const HOST_ROOT: &str = "/run/host";
+/// Host dirs that end up under [`HOST_ROOT`], since the sandbox has its own.
+const HOST_OS_DIRS: &[&str] = &["/usr", "/etc", "/bin", "/sbin", "/lib", "/lib32", "/lib64"];
+/// Flatpak points these at the app's own dirs in ~/.var/app, and passes the
+/// host's along as `HOST_<name>` if it had them set.
+const XDG_VARS: &[&str] = &[
+    "XDG_CONFIG_HOME",
+    "XDG_DATA_HOME",
+    "XDG_CACHE_HOME",
+    "XDG_STATE_HOME",
+];
+
+/// Whether the installer itself is running in a Flatpak sandbox.
+#[must_use]
+pub fn is_sandboxed() -> bool {
+    static SANDBOXED: OnceLock<bool> = OnceLock::new();
+    *SANDBOXED.get_or_init(|| Path::new(FLATPAK_INFO).exists())
+}
+
+/// An environment variable as the host has it, for the ones Flatpak changes.
+#[must_use]
+pub fn get_host_var(key: &str) -> Option<OsString> {
+    if is_sandboxed() && XDG_VARS.contains(&key) {
+        std::env::var_os(format!("HOST_{key}"))
+    } else {
+        std::env::var_os(key)
+    }
+}
+
+/// Where a path on the host is from in here. Home and most other dirs are
+/// shared as they are, only the OS dirs move.
+#[must_use]
+pub fn to_sandbox_path(path: &Path) -> PathBuf {
+    if !is_sandboxed() || !HOST_OS_DIRS.iter().any(|dir| path.starts_with(dir)) {
+        return path.to_path_buf();
+    }
+    Path::new(HOST_ROOT).join(path.strip_prefix("/").unwrap_or(path))
+}
+
+/// The other way around from [`to_sandbox_path`].
+#[must_use]
+pub fn to_host_path(path: &Path) -> PathBuf {
+    match path.strip_prefix(HOST_ROOT) {
+        Ok(rest) if is_sandboxed() => Path::new("/").join(rest),
+        _ => path.to_path_buf(),
+    }
+}
+
+/// A command that runs on the host, through `flatpak-spawn --host` when
+/// we're sandboxed. It doesn't get our environment in that case.
+#[must_use]
+pub fn host_command(program: impl AsRef<OsStr>) -> Command {
+    if is_sandboxed() {
+        let mut command = Command::new("flatpak-spawn");
+        command.arg("--host").arg(program);
+        command
+    } else {
+        Command::new(program)
+    }
+}
+
+/// How the host runs this binary again, say from a systemd unit or through
+/// pkexec: as it is, or with `flatpak run` when sandboxed, since our path
+/// only means something in here.
+pub fn get_self_command() -> crate::Result<Vec<OsString>> {
+    let exe = std::env::current_exe()?;
+    if !is_sandboxed() {
+        return Ok(vec![exe.into_os_string()]);
+    }
+
+    let info = KeyFile::load(Path::new(FLATPAK_INFO))?.unwrap_or_default();
+    let (Some(id), Some(name)) = (info.get("Application", "name"), exe.file_name()) else {
+        return Err(MoonlightError::Unknown(format!(
+            "couldn't tell how to run {} from outside the sandbox",
+            exe.display()
+        )));
+    };
+
+    let mut command = OsString::from("--command=");
+    command.push(name);
+    Ok(vec!["flatpak".into(), "run".into(), command, id.into()])
+}
+
+/// Errors with what to grant if the sandbox we're in can't get at Discord
+/// or the host. Always fine outside of one.
+pub fn check_sandbox_permissions() -> crate::Result<()> {
+    if !is_sandboxed() {
+        return Ok(());
+    }
+
+    let info = KeyFile::load(Path::new(FLATPAK_INFO))?.unwrap_or_default();
+    let missing = get_missing_sandbox_permissions(&info);
+    if missing.is_empty() {
+        return Ok(());
+    }
+
+    let id = info.get("Application", "name").unwrap_or("<app id>");
+    Err(MoonlightError::SandboxPermission(format!(
+        "{}, grant with `flatpak override --user {} {}`",
+        missing.join(" and "),
+        id,
+        missing.join(" ")
+    )))
+}
+
+fn get_missing_sandbox_permissions(info: &KeyFile) -> Vec<&'static str> {
+    let mut missing = Vec::new();
+
+    // Discord, the moonlight dir and the Flatpak overrides all live in home
+    let home = info
+        .get_list("Context", "filesystems")
+        .unwrap_or_default()
+        .iter()
+        .filter_map(|entry| entry.parse::<FlatpakFilesystemOverride>().ok())
+        .any(|entry| {
+            matches!(entry.path.as_str(), "home" | "host" | "~")
+                && entry
+                    .permission
+                    .includes(FlatpakFilesystemOverridePermission::ReadWrite)
+        });
+    if !home {
+        missing.push("--filesystem=home");
+    }
+
+    // What flatpak-spawn --host goes through
+    let spawn = info
+        .get("Session Bus Policy", "org.freedesktop.Flatpak")
+        .is_some_and(|policy| matches!(policy, "talk" | "own"));
+    if !spawn {
+        missing.push("--talk-name=org.freedesktop.Flatpak");
+    }
+
+    missing
+}
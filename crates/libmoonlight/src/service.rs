@@ -0,0 +1,287 @@
+use crate::types::MoonlightBranch;
+use crate::{get_dot_config, MoonlightError, Plan, PlanStep};
+use std::ffi::OsString;
+use std::path::{Path, PathBuf};
+
+pub const SERVICE_NAME: &str = "moonlight-update";
+
+/// What the generated units should run.
+#[derive(Debug, Clone)]
+pub struct ServiceConfig {
+    /// What runs moonlight-cli, see [`get_self_command`](crate::get_self_command)
+    pub command: Vec<OsString>,
+    pub branch: MoonlightBranch,
+    /// systemd calendar expression, see systemd.time(7)
+    pub on_calendar: String,
+    /// Baked into the units so they touch the same moonlight dir we do
+    pub moonlight_dir: Option<PathBuf>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ServiceStatus {
+    pub service_installed: bool,
+    pub timer_installed: bool,
+}
+
+/// Writes systemd user units that keep moonlight updated and Discord patched
+/// in the background.
+#[derive(Debug, Clone)]
+pub struct ServiceManager {
+    unit_dir: PathBuf,
+}
+
+impl Default for ServiceManager {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ServiceManager {
+    #[must_use]
+    pub fn new() -> Self {
+        Self::with_unit_dir(get_dot_config().join("systemd/user"))
+    }
+
+    #[must_use]
+    pub const fn with_unit_dir(unit_dir: PathBuf) -> Self {
+        Self { unit_dir }
+    }
+
+    #[must_use]
+    pub fn unit_dir(&self) -> &Path {
+        &self.unit_dir
+    }
+
+    #[must_use]
+    pub fn service_path(&self) -> PathBuf {
+        self.unit_dir.join(format!("{SERVICE_NAME}.service"))
+    }
+
+    #[must_use]
+    pub fn timer_path(&self) -> PathBuf {
+        self.unit_dir.join(format!("{SERVICE_NAME}.timer"))
+    }
+
+    #[must_use]
+    pub fn service_unit(&self, config: &ServiceConfig) -> String {
+        let exe = config
+            .command
+            .iter()
+            .map(|arg| quote(&arg.to_string_lossy()))
+            .collect::<Vec<_>>()
+            .join(" ");
+        let branch = config.branch.to_string();
+        let environment = config
+            .moonlight_dir
+            .as_ref()
+            .map(|dir| {
+                format!(
+                    "Environment={}\n",
+                    quote(&format!("MOONLIGHT_DIR={}", dir.to_string_lossy()))
+                )
+            })
+            .unwrap_or_default();
+
+        // `-` so being offline doesn't skip repatching too
+        format!(
+            r#"[Unit]
+Description=Update moonlight and repatch Discord
+After=network-online.target
+Wants=network-online.target
+
+[Service]
+Type=oneshot
+{environment}ExecStart=-{exe} install --if-outdated {branch}
+ExecStart={exe} repatch
+"#
+        )
+    }
+
+    #[must_use]
+    pub fn timer_unit(&self, config: &ServiceConfig) -> String {
+        format!(
+            r#"[Unit]
+Description=Periodically update moonlight and repatch Discord
+
+[Timer]
+OnCalendar={}
+Persistent=true
+
+[Install]
+WantedBy=timers.target
+"#,
+            config.on_calendar
+        )
+    }
+
+    pub fn install(&self, config: &ServiceConfig) -> crate::Result<()> {
+        validate_on_calendar(&config.on_calendar)?;
+        std::fs::create_dir_all(&self.unit_dir)?;
+        std::fs::write(self.service_path(), self.service_unit(config))?;
+        std::fs::write(self.timer_path(), self.timer_unit(config))?;
+        Ok(())
+    }
+
+    #[must_use]
+    pub fn plan_install(&self) -> Plan {
+        let mut plan = Plan::new();
+        if !self.unit_dir.exists() {
+            plan.push(PlanStep::CreateDir {
+                path: self.unit_dir.clone(),
+            });
+        }
+        for path in [self.service_path(), self.timer_path()] {
+            plan.push(PlanStep::WriteFile { path, backup: None });
+        }
+        plan
+    }
+
+    /// Returns whether there was anything to remove.
+    pub fn uninstall(&self) -> crate::Result<bool> {
+        let mut removed = false;
+        for path in [self.timer_path(), self.service_path()] {
+            match std::fs::remove_file(&path) {
+                Ok(()) => removed = true,
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+                Err(e) => return Err(e.into()),
+            }
+        }
+        Ok(removed)
+    }
+
+    #[must_use]
+    pub fn plan_uninstall(&self) -> Plan {
+        [self.timer_path(), self.service_path()]
+            .into_iter()
+            .filter(|path| path.exists())
+            .map(|path| PlanStep::Remove { path, backup: None })
+            .collect::<Vec<_>>()
+            .into()
+    }
+
+    #[must_use]
+    pub fn status(&self) -> ServiceStatus {
+        ServiceStatus {
+            service_installed: self.service_path().exists(),
+            timer_installed: self.timer_path().exists(),
+        }
+    }
+}
+
+/// Rejects anything that isn't plausibly a calendar expression, since it goes
+/// into the timer unit as is. systemd itself checks the rest when it loads it.
+pub fn validate_on_calendar(expr: &str) -> crate::Result<()> {
+    let valid = !expr.trim().is_empty()
+        && expr
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || " *-:,./~_+".contains(c));
+    if valid {
+        Ok(())
+    } else {
+        Err(MoonlightError::Unknown(format!(
+            "{expr:?} isn't a systemd calendar expression"
+        )))
+    }
+}
+
+// systemd's own quoting, plus % which it treats as a specifier
+fn quote(s: &str) -> String {
+    let escaped = s
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('%', "%%");
+    format!("\"{escaped}\"")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    fn config() -> ServiceConfig {
+        ServiceConfig {
+            command: vec![OsString::from("/opt/moon light/moonlight-cli")],
+            branch: MoonlightBranch::Nightly,
+            on_calendar: String::from("Mon..Fri *-*-* 04:00:00 Europe/Berlin"),
+            moonlight_dir: Some(PathBuf::from("/home/me/100%/moonlight")),
+        }
+    }
+
+    #[test]
+    fn install_status_uninstall() {
+        let tmp = TempDir::new().unwrap();
+        let manager = ServiceManager::with_unit_dir(tmp.path().join("systemd/user"));
+        assert_eq!(manager.plan_install().steps.len(), 3);
+
+        manager.install(&config()).unwrap();
+        assert_eq!(
+            manager.status(),
+            ServiceStatus {
+                service_installed: true,
+                timer_installed: true
+            }
+        );
+        assert_eq!(manager.plan_uninstall().steps.len(), 2);
+
+        assert!(manager.uninstall().unwrap());
+        assert!(!manager.uninstall().unwrap());
+        assert!(!manager.status().service_installed);
+        assert!(manager.plan_uninstall().is_empty());
+    }
+
+    #[test]
+    fn service_unit_keeps_going_when_the_update_fails() {
+        let tmp = TempDir::new().unwrap();
+        let manager = ServiceManager::with_unit_dir(tmp.path().to_path_buf());
+        manager.install(&config()).unwrap();
+
+        let service = std::fs::read_to_string(manager.service_path()).unwrap();
+        assert!(service.contains("Environment=\"MOONLIGHT_DIR=/home/me/100%%/moonlight\"\n"));
+        assert!(service.contains(
+            "ExecStart=-\"/opt/moon light/moonlight-cli\" install --if-outdated nightly\n"
+        ));
+        assert!(service.contains("ExecStart=\"/opt/moon light/moonlight-cli\" repatch\n"));
+
+        let timer = std::fs::read_to_string(manager.timer_path()).unwrap();
+        assert!(timer.contains("OnCalendar=Mon..Fri *-*-* 04:00:00 Europe/Berlin\n"));
+    }
+
+    #[test]
+    fn runs_flatpak_commands() {
+        let manager = ServiceManager::with_unit_dir(PathBuf::from("/nowhere"));
+        let config = ServiceConfig {
+            command: [
+                "flatpak",
+                "run",
+                "--command=moonlight-cli",
+                "io.github.moonlight",
+            ]
+            .map(OsString::from)
+            .to_vec(),
+            moonlight_dir: None,
+            ..config()
+        };
+
+        let service = manager.service_unit(&config);
+        assert!(service.contains(
+            "ExecStart=\"flatpak\" \"run\" \"--command=moonlight-cli\" \"io.github.moonlight\" repatch\n"
+        ));
+        assert!(!service.contains("Environment="));
+    }
+
+    #[test]
+    fn rejects_bad_calendar_expressions() {
+        let tmp = TempDir::new().unwrap();
+        let manager = ServiceManager::with_unit_dir(tmp.path().to_path_buf());
+
+        for expr in ["", "daily\nExecStart=/bin/sh", "%h", "[Install]"] {
+            let config = ServiceConfig {
+                on_calendar: String::from(expr),
+                ..config()
+            };
+            assert!(manager.install(&config).is_err(), "{expr:?}");
+        }
+        assert!(!manager.status().timer_installed);
+        assert!(validate_on_calendar("daily").is_ok());
+    }
+}
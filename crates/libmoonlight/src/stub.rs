@@ -0,0 +1,441 @@
+use crate::types::{
+    DetectedInstall, FlatpakFilesystemOverride, FlatpakFilesystemOverridePermission,
+    FlatpakFilesystems, InstallId, PatchStrategy,
+};
+use crate::{
+    get_default_moonlight_dir, get_flatpak_filesystem, get_moonlight_dir, get_stub_dir,
+    is_moonlight_asar, Asar, AsarWriter, MoonlightError, DOWNLOAD_DIR, PATCHED_ASAR, STUB_VERSION,
+};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use std::path::{Path, PathBuf};
+
+/// The sidecar next to the stub that holds everything install specific.
+pub const STUB_CONFIG: &str = "moonlight-install.json";
+/// Where the stub counts crashes, one file per install, in the moonlight dir.
+pub const LAUNCH_STATE_DIR: &str = "launch-state";
+/// First line of the injector.js we write, which is how we tell it's ours.
+pub const STUB_MARKER: &str = "// moonlight-installer stub";
+/// Launches that can fail in a row before the stub stops loading moonlight.
+pub const DEFAULT_MAX_CRASHES: u32 = 3;
+
+/// The app we put in place of app.asar: a package.json pointing at a fixed
+/// injector.js, which reads this from [`STUB_CONFIG`] to find moonlight's
+/// dist and load it. Changing it doesn't need a repatch.
+///
+/// The config sits next to injector.js, or next to app.asar when the stub is
+/// wrapped in one, so it survives Discord replacing app.asar.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub struct Stub {
+    pub version: u32,
+    /// Names the stub's launch state file
+    pub install_id: Option<InstallId>,
+    /// A custom moonlight build to load instead of the downloaded one
+    pub dist: Option<PathBuf>,
+    /// The moonlight dir this install was patched with, as the host sees it.
+    /// Older stubs only have it when it wasn't the default. `MOONLIGHT_DIR`
+    /// still wins when Discord is started with it.
+    pub moonlight_dir: Option<PathBuf>,
+    pub download_dir: String,
+    pub patched_asar: String,
+    pub profile: Option<String>,
+    #[serde(default)]
+    pub flags: Vec<String>,
+    #[serde(default = "default_max_crashes")]
+    pub max_crashes: u32,
+    #[serde(default)]
+    pub strategy: PatchStrategy,
+    /// Of Discord's app.asar when it was patched, checked before it's put back
+    #[serde(default)]
+    pub original_sha256: Option<String>,
+}
+
+const fn default_max_crashes() -> u32 {
+    DEFAULT_MAX_CRASHES
+}
+
+impl Default for Stub {
+    fn default() -> Self {
+        Self {
+            version: STUB_VERSION,
+            install_id: None,
+            dist: None,
+            moonlight_dir: None,
+            download_dir: String::from(DOWNLOAD_DIR),
+            patched_asar: String::from(PATCHED_ASAR),
+            profile: None,
+            flags: Vec::new(),
+            max_crashes: DEFAULT_MAX_CRASHES,
+            strategy: PatchStrategy::default(),
+            original_sha256: None,
+        }
+    }
+}
+
+impl Stub {
+    /// Reads back what an existing stub was set up with, including stubs
+    /// from before the sidecar that had it baked into injector.js. `app` is
+    /// wherever [`get_stub_dir`] says the config is.
+    #[must_use]
+    pub fn read(app: &Path) -> Option<Self> {
+        if let Ok(config) = std::fs::read_to_string(app.join(STUB_CONFIG)) {
+            return serde_json::from_str(&config).ok();
+        }
+
+        let injector = std::fs::read_to_string(app.join("injector.js")).ok()?;
+        let dist = stub_constant::<Option<PathBuf>>(&injector, "MOONLIGHT_INJECTOR")?
+            .and_then(|injector| injector.parent().map(Path::to_path_buf));
+        let moonlight_dir = stub_constant(&injector, "MOONLIGHT_DIR").flatten();
+
+        Some(Self {
+            version: Self::version(app).unwrap_or(0),
+            dist,
+            moonlight_dir,
+            ..Self::default()
+        })
+    }
+
+    /// Whether an app folder's (or our app.asar's) files are our stub.
+    #[must_use]
+    pub fn is_stub(package: &str, injector: &str) -> bool {
+        Self::is_stub_package(package) || Self::is_stub_injector(injector)
+    }
+
+    #[must_use]
+    pub fn is_stub_package(package: &str) -> bool {
+        serde_json::from_str::<Value>(package)
+            .is_ok_and(|package| package.get("moonlightStubVersion").is_some())
+    }
+
+    /// Stubs from before [`STUB_MARKER`] had their config baked in as
+    /// constants instead.
+    #[must_use]
+    pub fn is_stub_injector(injector: &str) -> bool {
+        injector.starts_with(STUB_MARKER)
+            || stub_constant::<Value>(injector, "MOONLIGHT_INJECTOR").is_some()
+    }
+
+    /// Brings a stub read from an older version up to date, short of the
+    /// version itself.
+    pub fn migrate(&mut self) {
+        // Older stubs always baked in <moonlight dir>/dist, which is now
+        // only done for custom builds
+        if self.version < 2 {
+            if let Some(dist) = self.dist.take_if(|dist| dist.ends_with(DOWNLOAD_DIR)) {
+                self.moonlight_dir = dist
+                    .parent()
+                    .filter(|dir| *dir != get_default_moonlight_dir())
+                    .map(Path::to_path_buf);
+            }
+        }
+    }
+
+    #[must_use]
+    pub fn version(app: &Path) -> Option<u32> {
+        let json = |name: &str| {
+            std::fs::read_to_string(app.join(name))
+                .ok()
+                .and_then(|json| serde_json::from_str::<serde_json::Value>(&json).ok())
+        };
+
+        json(STUB_CONFIG)
+            .and_then(|config| config["version"].as_u64())
+            .or_else(|| json("package.json").and_then(|p| p["moonlightStubVersion"].as_u64()))
+            .or_else(|| {
+                let injector = std::fs::read_to_string(app.join("injector.js")).ok()?;
+                stub_constant(&injector, "STUB_VERSION")
+            })
+            .and_then(|version| u32::try_from(version).ok())
+    }
+
+    /// Discord's own package.json with `main` pointed at us, since some of
+    /// Discord reads its version and such from there.
+    #[must_use]
+    pub fn package_json(&self, original: Option<&Map<String, Value>>) -> String {
+        let mut package = original.cloned().unwrap_or_else(|| {
+            let mut package = Map::new();
+            package.insert(String::from("name"), Value::from("discord"));
+            package.insert(String::from("private"), Value::from(true));
+            package
+        });
+        package.insert(String::from("main"), Value::from("./injector.js"));
+        package.insert(
+            String::from("moonlightStubVersion"),
+            Value::from(STUB_VERSION),
+        );
+        Value::Object(package).to_string()
+    }
+
+    /// Whichever of Discord's asars is around: app.asar before patching or
+    /// after Discord put it back, `PATCHED_ASAR` otherwise.
+    #[must_use]
+    pub fn original_asar(app_dir: &Path) -> PathBuf {
+        let asar = app_dir.join("app.asar");
+        if asar.exists() && !is_moonlight_asar(&asar) {
+            asar
+        } else {
+            app_dir.join(PATCHED_ASAR)
+        }
+    }
+
+    /// The package.json in [`Stub::original_asar`].
+    #[must_use]
+    pub fn original_package(app_dir: &Path) -> Option<Map<String, Value>> {
+        let asar = Asar::open(&Self::original_asar(app_dir)).ok()?;
+        let package = asar.read_to_string("package.json").ok()?;
+        match serde_json::from_str(&package).ok()? {
+            Value::Object(package) => Some(package),
+            _ => None,
+        }
+    }
+
+    #[must_use]
+    pub fn injector(&self) -> &'static str {
+        include_str!("injector.js")
+    }
+
+    /// What the stub reads and writes outside of Discord, for a Flatpak: the
+    /// moonlight dir, and a custom build if it lives somewhere else.
+    #[must_use]
+    pub fn flatpak_filesystems(&self) -> FlatpakFilesystems {
+        use FlatpakFilesystemOverridePermission::*;
+
+        let moonlight_dir = self
+            .moonlight_dir
+            .clone()
+            .unwrap_or_else(get_default_moonlight_dir);
+        let mut needed = vec![(moonlight_dir.clone(), ReadWrite)];
+        if let Some(dist) = self
+            .dist
+            .as_ref()
+            .filter(|dist| !dist.starts_with(&moonlight_dir))
+        {
+            needed.push((dist.clone(), ReadOnly));
+        }
+
+        let mut filesystems = FlatpakFilesystems::default();
+        for (path, permission) in needed {
+            match get_flatpak_filesystem(&path) {
+                Some(path) => filesystems
+                    .overrides
+                    .push(FlatpakFilesystemOverride { path, permission }),
+                None => filesystems.unsupported.push(path),
+            }
+        }
+        filesystems
+    }
+
+    #[must_use]
+    pub fn config_json(&self) -> String {
+        serde_json::to_string_pretty(self).expect("serialization to succeed")
+    }
+
+    /// The app.asar for [`PatchStrategy::Asar`], without the config.
+    pub fn to_asar(&self, original: Option<&Map<String, Value>>) -> crate::Result<AsarWriter> {
+        let mut asar = AsarWriter::new();
+        asar.insert_file("package.json", self.package_json(original), false)?;
+        asar.insert_file("injector.js", self.injector(), false)?;
+        Ok(asar)
+    }
+}
+
+/// What the stub keeps track of between launches, so it can start Discord
+/// without moonlight when moonlight keeps crashing it.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+#[serde(rename_all = "camelCase", default)]
+pub struct LaunchState {
+    /// Launches in a row where moonlight failed to inject, or Discord died
+    /// before a window finished loading
+    pub crashes: u32,
+    /// Set once crashes hits the limit, until it's reset
+    pub disabled: bool,
+    /// Set while a launch hasn't got a window up yet. Still being set on
+    /// the next launch means the last one died.
+    pub launching: bool,
+    pub last_error: Option<String>,
+}
+
+impl LaunchState {
+    /// `moonlight_dir` should be the one the stub resolves, see [`Stub`].
+    #[must_use]
+    pub fn path(moonlight_dir: &Path, id: &InstallId) -> PathBuf {
+        moonlight_dir
+            .join(LAUNCH_STATE_DIR)
+            .join(format!("{id}.json"))
+    }
+
+    /// Where the stub patched into `install` keeps its state, if it has one.
+    #[must_use]
+    pub fn path_for(install: &DetectedInstall) -> Option<PathBuf> {
+        let stub = Stub::read(&get_stub_dir(install)?)?;
+        let id = stub.install_id.as_ref().unwrap_or(&install.id);
+        let moonlight_dir = stub.moonlight_dir.clone().unwrap_or_else(get_moonlight_dir);
+        Some(Self::path(&moonlight_dir, id))
+    }
+
+    /// `None` if the stub hasn't run yet.
+    pub fn load(path: &Path) -> crate::Result<Option<Self>> {
+        match std::fs::read_to_string(path) {
+            Ok(contents) => serde_json::from_str(&contents).map(Some).map_err(|e| {
+                MoonlightError::Unknown(format!("invalid launch state {}: {e}", path.display()))
+            }),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Lets the stub try loading moonlight again.
+    pub fn reset(path: &Path) -> crate::Result<()> {
+        match std::fs::remove_file(path) {
+            Ok(()) => Ok(()),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(()),
+            Err(e) => Err(e.into()),
+        }
+    }
+}
+
+// The constants at the top of injector.js from before the sidecar were all JSON
+fn stub_constant<T: DeserializeOwned>(injector: &str, name: &str) -> Option<T> {
+    let prefix = format!("const {name} = ");
+    injector.lines().find_map(|line| {
+        let value = line.strip_prefix(&prefix)?.strip_suffix(';')?;
+        serde_json::from_str(value).ok()
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    #[test]
+    fn package_json_keeps_discords_fields() {
+        let original = serde_json::json!({
+            "name": "discord",
+            "version": "0.0.99",
+            "main": "app_bootstrap/index.js",
+        });
+        let package: Value =
+            serde_json::from_str(&Stub::default().package_json(original.as_object())).unwrap();
+        assert_eq!(package["version"], "0.0.99");
+        assert_eq!(package["main"], "./injector.js");
+        assert_eq!(package["moonlightStubVersion"], STUB_VERSION);
+
+        let package: Value = serde_json::from_str(&Stub::default().package_json(None)).unwrap();
+        assert_eq!(package["name"], "discord");
+        assert_eq!(package["main"], "./injector.js");
+    }
+
+    #[test]
+    fn config_json_round_trips() {
+        let stub = Stub {
+            install_id: Some("linux-stable-0123456789abcdef".parse().unwrap()),
+            dist: Some(PathBuf::from("/srv/moonlight/build")),
+            moonlight_dir: Some(PathBuf::from("/home/me/.config/moonlight-mod")),
+            flags: vec![String::from("--no-sandbox")],
+            strategy: PatchStrategy::Asar,
+            original_sha256: Some(String::from("abc")),
+            ..Stub::default()
+        };
+        let tmp = TempDir::new().unwrap();
+        std::fs::write(tmp.path().join(STUB_CONFIG), stub.config_json()).unwrap();
+
+        assert_eq!(Stub::read(tmp.path()), Some(stub));
+        assert_eq!(Stub::version(tmp.path()), Some(STUB_VERSION));
+    }
+
+    #[test]
+    fn config_json_defaults_missing_fields() {
+        let tmp = TempDir::new().unwrap();
+        std::fs::write(
+            tmp.path().join(STUB_CONFIG),
+            r#"{"version":3,"installId":null,"dist":null,"moonlightDir":null,"downloadDir":"dist","patchedAsar":"_app.asar","profile":null}"#,
+        )
+        .unwrap();
+
+        let stub = Stub::read(tmp.path()).unwrap();
+        assert_eq!(stub.version, 3);
+        assert_eq!(stub.max_crashes, DEFAULT_MAX_CRASHES);
+        assert!(stub.flags.is_empty());
+        assert_eq!(stub.strategy, PatchStrategy::default());
+        assert_eq!(stub.original_sha256, None);
+    }
+
+    #[test]
+    fn reads_stubs_from_before_the_sidecar() {
+        let tmp = TempDir::new().unwrap();
+
+        // What the first versions wrote, with the dist always baked in
+        std::fs::write(
+            tmp.path().join("injector.js"),
+            "const MOONLIGHT_INJECTOR = \"/srv/moonlight/dist/injector.js\";\nconst PATCHED_ASAR = \"_app.asar\";\nconst DOWNLOAD_DIR = \"dist\";\nrequire(\"x\");\n",
+        )
+        .unwrap();
+        let stub = Stub::read(tmp.path()).unwrap();
+        assert_eq!(stub.version, 0);
+        assert_eq!(stub.dist, Some(PathBuf::from("/srv/moonlight/dist")));
+        assert_eq!(stub.moonlight_dir, None);
+
+        std::fs::write(
+            tmp.path().join("injector.js"),
+            "const STUB_VERSION = 1;\nconst MOONLIGHT_INJECTOR = null;\nconst MOONLIGHT_DIR = \"/srv/moonlight\";\nconst PATCHED_ASAR = \"_app.asar\";\n",
+        )
+        .unwrap();
+        let stub = Stub::read(tmp.path()).unwrap();
+        assert_eq!(stub.version, 1);
+        assert_eq!(stub.dist, None);
+        assert_eq!(stub.moonlight_dir, Some(PathBuf::from("/srv/moonlight")));
+
+        std::fs::write(tmp.path().join("injector.js"), "require(\"x\");\n").unwrap();
+        assert_eq!(Stub::read(tmp.path()), None);
+    }
+
+    #[test]
+    fn tells_our_stub_apart() {
+        let stub = Stub::default();
+        assert!(Stub::is_stub(&stub.package_json(None), stub.injector()));
+        assert!(Stub::is_stub_injector(stub.injector()));
+        assert!(Stub::is_stub_package(&stub.package_json(None)));
+
+        let legacy = "const MOONLIGHT_INJECTOR = null;\nconst MOONLIGHT_DIR = null;\n";
+        assert!(Stub::is_stub(r#"{"main":"./injector.js"}"#, legacy));
+
+        // Something else that happens to mention us
+        let other = "// loads MOONLIGHT_INJECTOR\nrequire(\"./moonlight\");\n";
+        assert!(!Stub::is_stub(r#"{"main":"./injector.js"}"#, other));
+    }
+
+    #[test]
+    fn migrates_baked_in_dist_to_moonlight_dir() {
+        let legacy = |dist: PathBuf| Stub {
+            version: 1,
+            dist: Some(dist),
+            moonlight_dir: None,
+            ..Stub::default()
+        };
+
+        let mut stub = legacy(PathBuf::from("/srv/moonlight").join(DOWNLOAD_DIR));
+        stub.migrate();
+        assert_eq!(stub.dist, None);
+        assert_eq!(stub.moonlight_dir, Some(PathBuf::from("/srv/moonlight")));
+
+        let mut stub = legacy(get_default_moonlight_dir().join(DOWNLOAD_DIR));
+        stub.migrate();
+        assert_eq!(stub.dist, None);
+        assert_eq!(stub.moonlight_dir, None);
+
+        // A custom build is left alone, and so are newer stubs
+        let mut stub = legacy(PathBuf::from("/srv/moonlight/build"));
+        stub.migrate();
+        assert_eq!(stub.dist, Some(PathBuf::from("/srv/moonlight/build")));
+
+        let mut stub = Stub {
+            version: 2,
+            ..legacy(PathBuf::from("/srv/moonlight").join(DOWNLOAD_DIR))
+        };
+        stub.migrate();
+        assert_eq!(stub.dist, Some(PathBuf::from("/srv/moonlight/dist")));
+    }
+}
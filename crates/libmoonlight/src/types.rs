@@ -1,10 +1,10 @@
-use crate::get_moonlight_dir;
-use serde::de::Visitor;
+use crate::{
+    detect_patch_state_with, get_build_info, get_flatpak_filesystems_for, get_moonlight_dir,
+    get_patch_strategy, get_stub_version, DetectorEnv, LaunchState, STUB_VERSION,
+};
 use serde::{Deserialize, Serialize};
-use std::collections::BTreeMap;
 use std::fmt::Display;
-use std::marker::PhantomData;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::str::FromStr;
 
 #[derive(Serialize, Deserialize, clap::ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
@@ -44,7 +44,7 @@ impl MoonlightBranch {
     }
 }
 
-#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[derive(Serialize, Deserialize, clap::ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
 #[allow(clippy::upper_case_acronyms)]
 pub enum Branch {
     Stable,
@@ -79,6 +79,17 @@ impl Branch {
         }
     }
 
+    #[must_use]
+    pub fn from_release_channel(channel: &str) -> Option<Self> {
+        match channel {
+            "stable" => Some(Self::Stable),
+            "ptb" => Some(Self::PTB),
+            "canary" => Some(Self::Canary),
+            "development" => Some(Self::Development),
+            _ => None,
+        }
+    }
+
     pub fn dashed_name(&self) -> &'static str {
         match self {
             Self::Stable => "discord",
@@ -101,7 +112,7 @@ impl Branch {
 
         #[cfg(unix)]
         {
-            std::process::Command::new("killall")
+            crate::host_command("killall")
                 .args([name])
                 .spawn()?
                 .wait()?;
@@ -116,106 +127,330 @@ impl Branch {
     }
 }
 
+/// Identifies an install across runs, the GUI, the CLI and the library. Two
+/// installs of the same branch (say native and Flatpak) get different IDs.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Hash)]
+#[serde(transparent)]
+pub struct InstallId(String);
+
+impl InstallId {
+    #[must_use]
+    pub fn new(path: &Path, flatpak_id: Option<&str>) -> Self {
+        let path = std::fs::canonicalize(path).unwrap_or_else(|_| path.to_path_buf());
+
+        // Discord's updater puts every version in its own app-(version)
+        // folder, so key on the folder above it to survive updates
+        let path = match path.file_name() {
+            Some(name) if name.to_string_lossy().starts_with("app-") => {
+                path.parent().unwrap_or(&path).to_path_buf()
+            }
+            _ => path,
+        };
+
+        // FNV-1a, we just need something short and stable
+        let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+        let bytes = path
+            .to_string_lossy()
+            .into_owned()
+            .into_bytes()
+            .into_iter()
+            .chain(std::iter::once(0))
+            .chain(flatpak_id.unwrap_or_default().bytes());
+        for byte in bytes {
+            hash ^= u64::from(byte);
+            hash = hash.wrapping_mul(0x0100_0000_01b3);
+        }
+
+        Self(format!("{hash:016x}"))
+    }
+
+    #[must_use]
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+impl Display for InstallId {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl FromStr for InstallId {
+    type Err = std::convert::Infallible;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(Self(String::from(s)))
+    }
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct DetectedInstall {
+    pub id: InstallId,
     pub branch: Branch,
     pub path: PathBuf,
     pub flatpak_id: Option<String>,
 }
 
-// Just DetectedInstall but tracking patched for the UI
-#[derive(Serialize, Deserialize, Debug, Clone)]
-pub struct InstallInfo {
-    pub install: DetectedInstall,
-    pub patched: bool,
-    pub has_config: bool,
+impl DetectedInstall {
+    #[must_use]
+    pub fn new(branch: Branch, path: PathBuf, flatpak_id: Option<String>) -> Self {
+        Self {
+            id: InstallId::new(&path, flatpak_id.as_deref()),
+            branch,
+            path,
+            flatpak_id,
+        }
+    }
 }
 
-// Lot more in here but idc
-#[derive(Deserialize, Debug)]
-pub struct GitHubReleaseAsset {
-    pub name: String,
-    pub browser_download_url: String,
+/// How the stub gets in front of Discord's app.asar.
+#[derive(Serialize, Deserialize, clap::ValueEnum, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum PatchStrategy {
+    /// Rename app.asar and put an app folder next to it
+    #[default]
+    Folder,
+    /// Rename app.asar and put a tiny app.asar with the stub in its place.
+    /// Survives Discord putting back a missing app.asar.
+    Asar,
 }
 
-#[derive(Deserialize, Debug)]
-pub struct GitHubRelease {
-    pub name: String,
-    pub assets: Vec<GitHubReleaseAsset>,
-}
+impl PatchStrategy {
+    #[must_use]
+    pub const fn name(&self) -> &'static str {
+        match self {
+            Self::Folder => "Folder",
+            Self::Asar => "Asar",
+        }
+    }
 
-// we only care about filesystem so
-#[derive(Serialize, Deserialize, Debug)]
-pub struct FlatpakOverrides {
-    #[serde(rename = "Context")]
-    pub context: Option<FlatpakOverridesContext>,
-    #[serde(flatten)]
-    other: serde_value::Value,
+    #[must_use]
+    pub const fn description(&self) -> &'static str {
+        match self {
+            Self::Folder => "Puts an app folder next to Discord's app.asar. Suggested for most users.",
+            Self::Asar => "Puts a small app.asar in place of Discord's. Use this if Discord keeps putting back its own after updates.",
+        }
+    }
 }
 
-impl Default for FlatpakOverrides {
-    fn default() -> Self {
-        Self {
-            context: Some(Default::default()),
-            other: serde_value::Value::Map(BTreeMap::new()),
+impl Display for PatchStrategy {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Folder => write!(f, "folder"),
+            Self::Asar => write!(f, "asar"),
         }
     }
 }
 
-#[derive(Serialize, Deserialize, Debug)]
-pub struct FlatpakOverridesContext {
-    pub filesystems: Option<FlatpakArray<FlatpakFilesystemOverride>>,
-    #[serde(flatten)]
-    other: serde_value::Value,
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PatchState {
+    Unpatched,
+    Moonlight,
+    // Vencord, Equicord and friends rename app.asar and drop in an app folder
+    // just like we do
+    Vencord,
+    // Patches modules/discord_desktop_core/index.js
+    BetterDiscord,
+    // Replaces app.asar outright
+    OpenAsar,
+    // app.asar is gone but we don't recognize what replaced it
+    Unknown,
+    // Half-patched, usually after a Discord update, see Installer::repair_install
+    Broken(BrokenState),
 }
 
-impl Default for FlatpakOverridesContext {
-    fn default() -> Self {
-        Self {
-            filesystems: Some(Default::default()),
-            other: serde_value::Value::Map(BTreeMap::new()),
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BrokenState {
+    // Discord updated and put a fresh app.asar next to our _app.asar
+    DuplicateAsar,
+    // Our stub is there but _app.asar isn't
+    MissingPatchedAsar,
+    // _app.asar is there but the stub is missing files
+    IncompleteApp,
+}
+
+impl BrokenState {
+    #[must_use]
+    pub const fn description(&self) -> &'static str {
+        match self {
+            Self::DuplicateAsar => {
+                "Discord was updated while patched and now has both app.asar and _app.asar."
+            }
+            Self::MissingPatchedAsar => "The moonlight stub exists but _app.asar is missing.",
+            Self::IncompleteApp => "The moonlight stub is missing injector.js or package.json.",
         }
     }
 }
 
-#[derive(Debug)]
-pub struct FlatpakArray<T> {
-    inner: Vec<T>,
+impl Display for PatchState {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.name())
+    }
 }
 
-impl<T> Default for FlatpakArray<T> {
-    fn default() -> Self {
-        Self { inner: Vec::new() }
+impl PatchState {
+    #[must_use]
+    pub const fn name(&self) -> &'static str {
+        match self {
+            Self::Unpatched => "Unpatched",
+            Self::Moonlight => "moonlight",
+            Self::Vencord => "Vencord/Equicord",
+            Self::BetterDiscord => "BetterDiscord",
+            Self::OpenAsar => "OpenAsar",
+            Self::Unknown => "Unknown modification",
+            Self::Broken(_) => "Broken",
+        }
     }
-}
 
-impl<T> std::ops::Deref for FlatpakArray<T> {
-    type Target = Vec<T>;
+    /// Whether moonlight can be patched in without removing anything first.
+    #[must_use]
+    pub const fn can_patch(&self) -> bool {
+        matches!(self, Self::Unpatched | Self::OpenAsar)
+    }
 
-    fn deref(&self) -> &Self::Target {
-        &self.inner
+    /// Whether `Installer::unpatch_install` knows how to undo this state.
+    #[must_use]
+    pub const fn can_unpatch(&self) -> bool {
+        matches!(self, Self::Moonlight | Self::Vencord)
     }
 }
 
-impl<T> std::ops::DerefMut for FlatpakArray<T> {
-    fn deref_mut(&mut self) -> &mut Self::Target {
-        &mut self.inner
-    }
+// Just DetectedInstall but tracking patched for the UI
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct InstallInfo {
+    pub install: DetectedInstall,
+    pub patch_state: PatchState,
+    pub has_config: bool,
+    pub discord_version: Option<String>,
+    // Only set for installs added by hand, see InstallRegistry
+    pub label: Option<String>,
+    pub manually_added: bool,
+    /// Filled in by [`crate::Installer::get_installs`], which looks for
+    /// every install's processes in one go
+    pub running: bool,
+    /// Only set when moonlight is patched in
+    pub stub_version: Option<u32>,
+    /// What it was patched with last, or the default
+    #[serde(default)]
+    pub patch_strategy: PatchStrategy,
+    pub launch_state: Option<LaunchState>,
+    /// Paths the stub needs that a Flatpak install can't be given
+    #[serde(default)]
+    pub flatpak_unsupported: Vec<PathBuf>,
 }
 
-impl<T> From<FlatpakArray<T>> for Vec<T> {
-    fn from(value: FlatpakArray<T>) -> Self {
-        value.inner
+impl InstallInfo {
+    #[must_use]
+    pub fn new(install: DetectedInstall) -> Self {
+        Self::new_with(install, &DetectorEnv::current())
+    }
+
+    /// [`InstallInfo::new`], with the patch state detected in `env`.
+    #[must_use]
+    pub fn new_with(install: DetectedInstall, env: &DetectorEnv) -> Self {
+        let patch_state = detect_patch_state_with(&install, env).unwrap_or(PatchState::Unknown);
+        let has_config = install.branch.config().exists();
+        let discord_version = get_build_info(&install.path).map(|info| info.version);
+        let stub_version = get_stub_version(&install);
+        let patch_strategy = get_patch_strategy(&install);
+        let launch_state = LaunchState::path_for(&install)
+            .and_then(|path| LaunchState::load(&path).ok().flatten());
+        let flatpak_unsupported = get_flatpak_filesystems_for(&install).unsupported;
+
+        Self {
+            install,
+            patch_state,
+            has_config,
+            discord_version,
+            label: None,
+            manually_added: false,
+            running: false,
+            stub_version,
+            patch_strategy,
+            launch_state,
+            flatpak_unsupported,
+        }
+    }
+
+    /// How many crashes moonlight was disabled after, if it was.
+    #[must_use]
+    pub fn safe_mode_crashes(&self) -> Option<u32> {
+        self.launch_state
+            .as_ref()
+            .filter(|state| state.disabled)
+            .map(|state| state.crashes)
     }
+
+    /// Whether the stub was generated by an older version of the installer.
+    #[must_use]
+    pub fn stub_outdated(&self) -> bool {
+        self.stub_version
+            .is_some_and(|version| version < STUB_VERSION)
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RepatchAction {
+    Patched,
+    Repaired,
 }
 
-impl<T> From<Vec<T>> for FlatpakArray<T> {
-    fn from(value: Vec<T>) -> Self {
-        Self { inner: value }
+impl Display for RepatchAction {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Patched => write!(f, "patched"),
+            Self::Repaired => write!(f, "repaired"),
+        }
     }
 }
 
-#[derive(Debug, Default, PartialEq, Eq)]
+// What Installer::repatch_installs did to a single install
+#[derive(Debug)]
+pub struct RepatchOutcome {
+    pub install: DetectedInstall,
+    pub action: RepatchAction,
+    pub result: crate::Result<()>,
+}
+
+// resources/build_info.json, shipped with every Discord build
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct DiscordBuildInfo {
+    pub release_channel: String,
+    pub version: String,
+}
+
+// Lot more in here but idc
+#[derive(Deserialize, Debug)]
+pub struct GitHubReleaseAsset {
+    pub name: String,
+    pub browser_download_url: String,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct GitHubRelease {
+    pub name: String,
+    pub assets: Vec<GitHubReleaseAsset>,
+}
+
+/// An entry [`crate::ensure_flatpak_overrides`] put in a Flatpak's overrides.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct FlatpakOverrideChange {
+    pub entry: String,
+    /// The user's own entry for the same path it took the place of
+    pub replaced: Option<String>,
+}
+
+/// What a stub needs to get at from inside a Flatpak.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct FlatpakFilesystems {
+    pub overrides: Vec<FlatpakFilesystemOverride>,
+    /// Paths no Flatpak permission can share
+    pub unsupported: Vec<PathBuf>,
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
 pub enum FlatpakFilesystemOverridePermission {
     #[default]
     ReadWrite,
@@ -224,7 +459,22 @@ pub enum FlatpakFilesystemOverridePermission {
     Off,
 }
 
-#[derive(Debug)]
+impl FlatpakFilesystemOverridePermission {
+    /// Whether having this is enough for something that needs `other`.
+    #[must_use]
+    pub fn includes(self, other: Self) -> bool {
+        use FlatpakFilesystemOverridePermission::*;
+
+        match self {
+            Off => other == Off,
+            ReadOnly => other == ReadOnly,
+            ReadWrite => matches!(other, ReadOnly | ReadWrite),
+            Create => other != Off,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct FlatpakFilesystemOverride {
     pub path: String,
     pub permission: FlatpakFilesystemOverridePermission,
@@ -290,71 +540,3 @@ impl Display for FlatpakFilesystemOverride {
         write!(f, "{}:{p}", self.path)
     }
 }
-
-// no one has implemented the flatpak filesystem overrides serialization
-// format correctly except for flatpak itself, so we wont try too hard
-impl<T> Serialize for FlatpakArray<T>
-where
-    T: ToString,
-{
-    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
-    where
-        S: serde::Serializer,
-    {
-        let as_strings: Vec<String> = self.iter().map(|x| x.to_string()).collect();
-        let mut v = as_strings.join(";");
-        v += ";";
-        serializer.serialize_str(&v)
-    }
-}
-
-impl<'de, T> Deserialize<'de> for FlatpakArray<T>
-where
-    T: FromStr,
-    T::Err: std::error::Error,
-{
-    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
-    where
-        D: serde::Deserializer<'de>,
-    {
-        struct FlatpakArrayVisitor<T>(PhantomData<T>);
-        impl<T> FlatpakArrayVisitor<T> {
-            pub fn new() -> Self {
-                Self(PhantomData)
-            }
-        }
-
-        impl<T> Visitor<'_> for FlatpakArrayVisitor<T>
-        where
-            T: FromStr,
-            T::Err: std::error::Error,
-        {
-            type Value = FlatpakArray<T>;
-
-            fn expecting(&self, formatter: &mut std::fmt::Formatter) -> std::fmt::Result {
-                write!(formatter, "an array of strings separated by semicolons")
-            }
-
-            fn visit_str<E>(self, v: &str) -> Result<Self::Value, E>
-            where
-                E: serde::de::Error,
-            {
-                let parts: Vec<String> = v
-                    .strip_suffix(";")
-                    .unwrap_or(v)
-                    .split(';')
-                    .map(String::from)
-                    .collect();
-                let mut vec = Vec::with_capacity(parts.len());
-
-                for part in parts {
-                    vec.push(T::from_str(&part).map_err(E::custom)?);
-                }
-
-                Ok(vec.into())
-            }
-        }
-
-        deserializer.deserialize_str(FlatpakArrayVisitor::<T>::new())
-    }
-}
@@ -2,42 +2,72 @@
 use nix::unistd::{Uid, User};
 
 use crate::types::{
-    Branch, DetectedInstall, FlatpakFilesystemOverride, FlatpakFilesystemOverridePermission,
-    FlatpakOverrides, InstallInfo,
+    Branch, DetectedInstall, DiscordBuildInfo, FlatpakFilesystemOverride, FlatpakFilesystems,
+    FlatpakOverrideChange, InstallInfo,
 };
-use std::path::{Path, PathBuf};
+use crate::{detect_patch_state, get_host_var, get_stub_dir, KeyFile, ProcessInspector, Stub};
+use std::path::{Component, Path, PathBuf};
 
 pub const DOWNLOAD_DIR: &str = "dist";
 pub const PATCHED_ASAR: &str = "_app.asar";
+pub const BUILD_INFO: &str = "build_info.json";
+/// Bump whenever the app folder we generate changes, so existing installs
+/// get offered a refresh. Stubs from before this existed count as 0.
+pub const STUB_VERSION: u32 = 9;
 
-pub fn get_moonlight_dir() -> PathBuf {
-    let dir = std::env::var_os("MOONLIGHT_DIR")
-        .map(PathBuf::from)
-        .or_else(|| {
-            Some(match std::env::consts::OS {
-                "windows" => {
-                    let appdata = std::env::var("APPDATA").unwrap();
-                    PathBuf::from(appdata).join("moonlight-mod")
-                }
-                "macos" => {
-                    let home = std::env::var("HOME").unwrap();
-                    PathBuf::from(home).join("Library/Application Support/moonlight-mod")
-                }
-                "linux" => get_dot_config().join("moonlight-mod"),
-                _ => unimplemented!("Unsupported OS"),
-            })
-        })
-        .unwrap();
+/// The moonlight dir from `MOONLIGHT_DIR`, if it's been moved from the default.
+#[must_use]
+pub fn get_custom_moonlight_dir() -> Option<PathBuf> {
+    std::env::var_os("MOONLIGHT_DIR").map(PathBuf::from)
+}
 
-    if !dir.exists() {
-        let _ = std::fs::create_dir_all(&dir);
+/// Where the moonlight dir is without `MOONLIGHT_DIR`. The injector stub
+/// resolves this the same way.
+pub fn get_default_moonlight_dir() -> PathBuf {
+    match std::env::consts::OS {
+        "windows" => {
+            let appdata = std::env::var("APPDATA").unwrap();
+            PathBuf::from(appdata).join("moonlight-mod")
+        }
+        "macos" => {
+            let home = std::env::var("HOME").unwrap();
+            PathBuf::from(home).join("Library/Application Support/moonlight-mod")
+        }
+        "linux" => get_dot_config().join("moonlight-mod"),
+        _ => unimplemented!("Unsupported OS"),
     }
+}
+
+/// Doesn't create it, so looking (and dry runs) leave no trace. Anything
+/// writing into it goes through [`ensure_moonlight_dir`].
+#[must_use]
+pub fn get_moonlight_dir() -> PathBuf {
+    get_custom_moonlight_dir().unwrap_or_else(get_default_moonlight_dir)
+}
 
-    dir
+pub fn ensure_moonlight_dir() -> crate::Result<PathBuf> {
+    let dir = get_moonlight_dir();
+    std::fs::create_dir_all(&dir)?;
+    Ok(dir)
+}
+
+#[must_use]
+pub fn get_build_info(path: &Path) -> Option<DiscordBuildInfo> {
+    let app_dir = get_app_dir(path).ok()?;
+    let file = std::fs::File::open(app_dir.join(BUILD_INFO)).ok()?;
+    serde_json::from_reader(file).ok()
 }
 
 #[must_use]
 pub fn detect_install_type(exe: &Path) -> Option<Branch> {
+    if let Some(branch) = exe
+        .parent()
+        .and_then(get_build_info)
+        .and_then(|info| Branch::from_release_channel(&info.release_channel))
+    {
+        return Some(branch);
+    }
+
     let name = exe.file_name()?.to_string_lossy();
 
     // lmfao
@@ -56,17 +86,12 @@ pub fn detect_install_type(exe: &Path) -> Option<Branch> {
 pub fn detect_install(exe: &Path) -> Option<InstallInfo> {
     let folder = exe.parent()?;
     let install_type = detect_install_type(exe)?;
-    let app_dir = get_app_dir(folder).ok()?;
+    let install = DetectedInstall::new(install_type, folder.to_path_buf(), None);
+    detect_patch_state(&install).ok()?;
 
-    Some(InstallInfo {
-        install: DetectedInstall {
-            branch: install_type,
-            path: folder.to_path_buf(),
-            flatpak_id: None,
-        },
-        patched: app_dir.join(PATCHED_ASAR).exists(),
-        has_config: false,
-    })
+    let mut info = InstallInfo::new(install);
+    info.running = ProcessInspector::new().is_running(&info.install);
+    Some(info)
 }
 
 pub fn get_app_dir(path: &Path) -> crate::Result<PathBuf> {
@@ -99,7 +124,7 @@ pub fn get_home_dir() -> PathBuf {
 
 pub fn get_local_share() -> PathBuf {
     std::env::var_os("MOONLIGHT_DISCORD_SHARE_LINUX")
-        .or_else(|| std::env::var_os("XDG_DATA_HOME"))
+        .or_else(|| get_host_var("XDG_DATA_HOME"))
         .map(PathBuf::from)
         .unwrap_or_else(|| get_home_dir().join(".local/share"))
 }
@@ -124,74 +149,271 @@ fn get_flatpak_home() -> PathBuf {
 }
 
 pub fn get_dot_config() -> PathBuf {
-    std::env::var_os("XDG_CONFIG_HOME")
+    get_host_var("XDG_CONFIG_HOME")
         .map(PathBuf::from)
         .unwrap_or_else(|| get_home_dir().join(".config"))
 }
 
-fn get_flatpak_overrides(id: &str) -> crate::Result<Option<FlatpakOverrides>> {
-    let overrides = get_flatpak_home().join("overrides");
+#[must_use]
+pub fn get_flatpak_overrides_path(id: &str) -> PathBuf {
+    get_flatpak_home().join("overrides").join(id)
+}
 
-    std::fs::create_dir_all(&overrides)?;
+const FLATPAK_CONTEXT: &str = "Context";
+const FLATPAK_FILESYSTEMS: &str = "filesystems";
+/// Flatpak won't share these with an app, whatever the overrides say.
+const FLATPAK_RESERVED_PATHS: &[&str] = &[
+    "/app",
+    "/bin",
+    "/dev",
+    "/etc",
+    "/lib",
+    "/lib32",
+    "/lib64",
+    "/proc",
+    "/run/flatpak",
+    "/run/host",
+    "/sbin",
+    "/sys",
+    "/usr",
+];
 
-    let app_overrides = overrides.join(id);
+fn get_xdg_dir(var: &str, fallback: &str) -> PathBuf {
+    get_host_var(var)
+        .map(PathBuf::from)
+        .unwrap_or_else(|| get_home_dir().join(fallback))
+}
 
-    let file = match std::fs::OpenOptions::new().read(true).open(&app_overrides) {
-        Ok(v) => v,
-        Err(err) => match err.kind() {
-            std::io::ErrorKind::NotFound => return Ok(None),
-            _ => return Err(err.into()),
-        },
-    };
+/// How a Flatpak filesystem permission spells `path`: relative to the XDG
+/// dirs or home where it can be, absolute otherwise. `None` if there's no
+/// way for Flatpak to share it.
+#[must_use]
+pub fn get_flatpak_filesystem(path: &Path) -> Option<String> {
+    let absolute = path.to_str()?;
+    let normal = path
+        .components()
+        .all(|c| matches!(c, Component::RootDir | Component::Normal(_)));
+    // `:` would be read as the start of the mode
+    if !path.is_absolute() || !normal || absolute.contains(':') {
+        return None;
+    }
+    if path == Path::new("/") || FLATPAK_RESERVED_PATHS.iter().any(|r| path.starts_with(r)) {
+        return None;
+    }
+
+    let relative_to = [
+        ("xdg-config", get_dot_config()),
+        ("xdg-data", get_xdg_dir("XDG_DATA_HOME", ".local/share")),
+        ("xdg-cache", get_xdg_dir("XDG_CACHE_HOME", ".cache")),
+        ("~", get_home_dir()),
+    ];
+    for (prefix, dir) in relative_to {
+        if let Ok(rest) = path.strip_prefix(&dir) {
+            return Some(match rest.to_str()? {
+                "" if prefix == "~" => String::from("home"),
+                "" => prefix.to_string(),
+                rest => format!("{prefix}/{rest}"),
+            });
+        }
+    }
 
-    serde_ini::from_read(file).or(Ok(None))
+    Some(absolute.to_string())
 }
 
-pub fn ensure_flatpak_overrides(id: &str) -> crate::Result<()> {
-    let overrides = get_flatpak_overrides(id)?;
+/// What a Flatpak install's stub needs shared, or would need if it were
+/// patched now. Nothing for installs that aren't Flatpaks.
+#[must_use]
+pub fn get_flatpak_filesystems_for(install: &DetectedInstall) -> FlatpakFilesystems {
+    if install.flatpak_id.is_none() {
+        return FlatpakFilesystems::default();
+    }
 
-    let has = overrides
-        .as_ref()
-        .and_then(|v| v.context.as_ref())
-        .and_then(|v| v.filesystems.as_ref())
-        .is_some_and(|v| {
-            v.iter().any(|entry| {
-                entry.path == "xdg-config/moonlight-mod"
-                    && entry.permission == FlatpakFilesystemOverridePermission::ReadWrite
-            })
+    get_stub_dir(install)
+        .and_then(|dir| Stub::read(&dir))
+        .unwrap_or_else(|| Stub {
+            moonlight_dir: get_custom_moonlight_dir(),
+            ..Stub::default()
+        })
+        .flatpak_filesystems()
+}
+
+fn get_flatpak_overrides(id: &str) -> crate::Result<Option<KeyFile>> {
+    KeyFile::load(&get_flatpak_overrides_path(id))
+}
+
+fn write_flatpak_overrides(id: &str, overrides: &KeyFile) -> crate::Result<()> {
+    let app_overrides = get_flatpak_overrides_path(id);
+    std::fs::create_dir_all(app_overrides.parent().unwrap())?;
+    std::fs::write(app_overrides, overrides.to_string())?;
+    Ok(())
+}
+
+fn get_flatpak_filesystems(overrides: &KeyFile) -> Vec<String> {
+    overrides
+        .get_list(FLATPAK_CONTEXT, FLATPAK_FILESYSTEMS)
+        .unwrap_or_default()
+}
+
+// Upgrades entries the user already has for a path rather than adding a
+// second one, and leaves ones that already give enough alone
+fn apply_flatpak_overrides(
+    overrides: &mut KeyFile,
+    wanted: &[FlatpakFilesystemOverride],
+) -> Vec<FlatpakOverrideChange> {
+    let mut filesystems = get_flatpak_filesystems(overrides);
+    let mut changes = Vec::new();
+
+    for wanted in wanted {
+        let entry = wanted.to_string();
+        let existing = filesystems.iter().enumerate().find_map(|(i, existing)| {
+            existing
+                .parse::<FlatpakFilesystemOverride>()
+                .ok()
+                .filter(|existing| existing.path == wanted.path)
+                .map(|existing| (i, existing.permission))
         });
 
-    if has {
-        return Ok(());
+        match existing {
+            Some((_, permission)) if permission.includes(wanted.permission) => {}
+            Some((i, _)) => changes.push(FlatpakOverrideChange {
+                replaced: Some(std::mem::replace(&mut filesystems[i], entry.clone())),
+                entry,
+            }),
+            None => {
+                filesystems.push(entry.clone());
+                changes.push(FlatpakOverrideChange {
+                    entry,
+                    replaced: None,
+                });
+            }
+        }
+    }
+
+    if !changes.is_empty() {
+        overrides.set_list(FLATPAK_CONTEXT, FLATPAK_FILESYSTEMS, &filesystems);
+    }
+    changes
+}
+
+/// What [`ensure_flatpak_overrides`] would change, without changing it.
+pub fn get_flatpak_override_changes(
+    id: &str,
+    wanted: &[FlatpakFilesystemOverride],
+) -> crate::Result<Vec<FlatpakOverrideChange>> {
+    let mut overrides = get_flatpak_overrides(id)?.unwrap_or_default();
+    Ok(apply_flatpak_overrides(&mut overrides, wanted))
+}
+
+/// Gives the Flatpak the filesystem access in `wanted`, returning the
+/// entries that had to be added or replaced for it.
+pub fn ensure_flatpak_overrides(
+    id: &str,
+    wanted: &[FlatpakFilesystemOverride],
+) -> crate::Result<Vec<FlatpakOverrideChange>> {
+    let mut overrides = get_flatpak_overrides(id)?.unwrap_or_default();
+    let changes = apply_flatpak_overrides(&mut overrides, wanted);
+    if !changes.is_empty() {
+        write_flatpak_overrides(id, &overrides)?;
     }
+    Ok(changes)
+}
+
+/// Takes out the entries [`ensure_flatpak_overrides`] made and nothing else,
+/// putting back whatever they replaced. Entries that have been edited since
+/// are left alone. Returns whether anything was taken out.
+pub fn remove_flatpak_overrides(
+    id: &str,
+    changes: &[FlatpakOverrideChange],
+) -> crate::Result<bool> {
+    let Some(mut overrides) = get_flatpak_overrides(id)? else {
+        return Ok(false);
+    };
+    let mut filesystems = get_flatpak_filesystems(&overrides);
+    let mut removed = false;
 
-    let mut overrides = overrides.unwrap_or_default();
+    for change in changes.iter().rev() {
+        let Some(i) = filesystems.iter().position(|entry| *entry == change.entry) else {
+            continue;
+        };
+        match &change.replaced {
+            Some(previous) => filesystems[i].clone_from(previous),
+            None => {
+                filesystems.remove(i);
+            }
+        }
+        removed = true;
+    }
+    if !removed {
+        return Ok(false);
+    }
 
-    if overrides.context.is_none() {
-        overrides.context = Some(Default::default());
+    if filesystems.is_empty() {
+        overrides.remove(FLATPAK_CONTEXT, FLATPAK_FILESYSTEMS);
+    } else {
+        overrides.set_list(FLATPAK_CONTEXT, FLATPAK_FILESYSTEMS, &filesystems);
     }
-    let context = overrides.context.as_mut().unwrap();
+    write_flatpak_overrides(id, &overrides)?;
+    Ok(true)
+}
 
-    if context.filesystems.is_none() {
-        context.filesystems = Some(Default::default());
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::FlatpakFilesystemOverridePermission;
+
+    fn wanted(
+        path: &str,
+        permission: FlatpakFilesystemOverridePermission,
+    ) -> FlatpakFilesystemOverride {
+        FlatpakFilesystemOverride {
+            path: String::from(path),
+            permission,
+        }
     }
-    let filesystem = context.filesystems.as_mut().unwrap();
 
-    filesystem.push(FlatpakFilesystemOverride {
-        path: String::from("xdg-config/moonlight-mod"),
-        permission: FlatpakFilesystemOverridePermission::ReadWrite,
-    });
+    #[test]
+    fn upgrades_overrides_in_place() {
+        use FlatpakFilesystemOverridePermission::*;
 
-    // ensured that it exists in get_flatpak_overrides
-    let app_overrides = get_flatpak_home().join("overrides").join(id);
-    let mut file = std::fs::OpenOptions::new()
-        .write(true)
-        .create(true)
-        .truncate(true)
-        .append(false)
-        .open(&app_overrides)?;
+        let contents = "# mine\n[Context]\r\nfilesystems=~/Music;xdg-config/moonlight-mod:ro;\r\nshared=ipc;\r\n";
+        let mut overrides = KeyFile::parse(contents).unwrap();
+        let changes = apply_flatpak_overrides(
+            &mut overrides,
+            &[
+                wanted("xdg-config/moonlight-mod", ReadWrite),
+                wanted("~/Music", ReadOnly),
+                wanted("/srv/moonlight", ReadOnly),
+            ],
+        );
 
-    serde_ini::to_writer(&mut file, &overrides).expect("ini serialization to succeed");
+        assert_eq!(
+            changes,
+            [
+                FlatpakOverrideChange {
+                    entry: String::from("xdg-config/moonlight-mod:rw"),
+                    replaced: Some(String::from("xdg-config/moonlight-mod:ro")),
+                },
+                FlatpakOverrideChange {
+                    entry: String::from("/srv/moonlight:ro"),
+                    replaced: None,
+                },
+            ]
+        );
+        assert_eq!(
+            overrides.to_string(),
+            contents.replace(
+                "~/Music;xdg-config/moonlight-mod:ro;",
+                "~/Music;xdg-config/moonlight-mod:rw;/srv/moonlight:ro;"
+            )
+        );
 
-    Ok(())
+        // Already enough, nothing to change
+        let before = overrides.to_string();
+        assert!(apply_flatpak_overrides(
+            &mut overrides,
+            &[wanted("xdg-config/moonlight-mod", ReadOnly)]
+        )
+        .is_empty());
+        assert_eq!(overrides.to_string(), before);
+    }
 }
@@ -0,0 +1,147 @@
+use crate::types::InstallInfo;
+use crate::{get_app_dir, DetectorEnv, LinuxDetector, MoonlightError};
+use nix::sys::inotify::{AddWatchFlags, InitFlags, Inotify, WatchDescriptor};
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::time::Duration;
+
+/// Everything worth watching to notice Discord updating itself: the folder
+/// holding app-(version) folders, each install's resources and the new
+/// updater's folders in ~/.config.
+#[must_use]
+pub fn get_watch_paths(installs: &[InstallInfo], env: &DetectorEnv) -> Vec<PathBuf> {
+    let mut paths = vec![];
+
+    for info in installs {
+        let path = &info.install.path;
+        if path
+            .file_name()
+            .is_some_and(|name| name.to_string_lossy().starts_with("app-"))
+        {
+            if let Some(parent) = path.parent() {
+                paths.push(parent.to_path_buf());
+            }
+        }
+
+        if let Ok(app_dir) = get_app_dir(path) {
+            paths.push(app_dir);
+        }
+    }
+
+    for (dir, _, _) in LinuxDetector::updater_dirs(env) {
+        paths.push(dir);
+    }
+
+    paths.retain(|path| path.exists());
+    paths.sort();
+    paths.dedup();
+    paths
+}
+
+/// An inotify instance kept across checks, so nothing Discord's updater does
+/// in between gets missed.
+pub struct UpdateWatcher {
+    inotify: Inotify,
+    watches: HashMap<PathBuf, WatchDescriptor>,
+}
+
+impl UpdateWatcher {
+    pub fn new() -> crate::Result<Self> {
+        let inotify = Inotify::init(InitFlags::IN_CLOEXEC).map_err(std::io::Error::from)?;
+        Ok(Self {
+            inotify,
+            watches: HashMap::new(),
+        })
+    }
+
+    /// Watches `paths` and nothing else from now on. Paths that couldn't be
+    /// watched come back with why, which is normal for ones the updater
+    /// deleted since they were found.
+    pub fn set_paths(&mut self, paths: &[PathBuf]) -> Vec<(PathBuf, MoonlightError)> {
+        let flags = AddWatchFlags::IN_CREATE
+            | AddWatchFlags::IN_MOVED_TO
+            | AddWatchFlags::IN_MOVED_FROM
+            | AddWatchFlags::IN_DELETE;
+
+        let stale = self
+            .watches
+            .keys()
+            .filter(|path| !paths.contains(path))
+            .cloned()
+            .collect::<Vec<_>>();
+        for path in stale {
+            if let Some(wd) = self.watches.remove(&path) {
+                let _ = self.inotify.rm_watch(wd);
+            }
+        }
+
+        let mut failed = vec![];
+        for path in paths {
+            if self.watches.contains_key(path) {
+                continue;
+            }
+            match self.inotify.add_watch(path.as_path(), flags) {
+                Ok(wd) => {
+                    self.watches.insert(path.clone(), wd);
+                }
+                Err(e) => failed.push((path.clone(), std::io::Error::from(e).into())),
+            }
+        }
+        failed
+    }
+
+    #[must_use]
+    pub fn is_empty(&self) -> bool {
+        self.watches.is_empty()
+    }
+
+    /// Blocks until something is created, moved or deleted in one of the
+    /// paths, then waits out `debounce` so the updater can finish what it's
+    /// doing.
+    pub fn wait(&mut self, debounce: Duration) -> crate::Result<()> {
+        let events = self.inotify.read_events().map_err(std::io::Error::from)?;
+
+        // The kernel drops the watch when its folder goes away
+        for event in events {
+            if event.mask.contains(AddWatchFlags::IN_IGNORED) {
+                self.watches.retain(|_, wd| *wd != event.wd);
+            }
+        }
+
+        std::thread::sleep(debounce);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    #[test]
+    fn missing_paths_dont_stop_the_rest() {
+        let tmp = TempDir::new().unwrap();
+        let kept = tmp.path().join("Discord");
+        let gone = tmp.path().join("app-0.0.89");
+        std::fs::create_dir(&kept).unwrap();
+
+        let mut watcher = UpdateWatcher::new().unwrap();
+        let failed = watcher.set_paths(&[gone.clone(), kept.clone()]);
+        assert_eq!(failed.len(), 1);
+        assert_eq!(failed[0].0, gone);
+        assert!(!watcher.is_empty());
+
+        // Changes made between checks are still there for the next wait
+        std::fs::create_dir(kept.join("app-0.0.90")).unwrap();
+        watcher.wait(Duration::ZERO).unwrap();
+    }
+
+    #[test]
+    fn drops_paths_no_longer_wanted() {
+        let tmp = TempDir::new().unwrap();
+        let mut watcher = UpdateWatcher::new().unwrap();
+        assert!(watcher.set_paths(&[tmp.path().to_path_buf()]).is_empty());
+        assert!(watcher.set_paths(&[]).is_empty());
+        assert!(watcher.is_empty());
+    }
+}
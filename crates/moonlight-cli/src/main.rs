@@ -1,7 +1,24 @@
 use clap::{CommandFactory, Parser, Subcommand};
-use libmoonlight::detect_install;
-use libmoonlight::types::MoonlightBranch;
+use libmoonlight::types::{
+    Branch, BrokenState, DetectedInstall, InstallId, InstallInfo, MoonlightBranch, PatchState,
+    PatchStrategy, RepatchOutcome,
+};
+use libmoonlight::{
+    detect_install, get_flatpak_filesystems_for, serve_privileged_helper, HelperOp, Installer,
+    LaunchState, ManualInstall, Plan, PlanStep, DEFAULT_CLOSE_TIMEOUT, HELPER_ARG,
+};
 use std::path::PathBuf;
+#[cfg(target_os = "linux")]
+use std::time::Duration;
+
+// Give the updater a moment to finish writing before we look
+#[cfg(target_os = "linux")]
+const WATCH_DEBOUNCE: Duration = Duration::from_secs(5);
+#[cfg(target_os = "linux")]
+const WATCH_IDLE: Duration = Duration::from_secs(60);
+
+#[cfg(target_os = "linux")]
+mod service;
 
 #[derive(Parser, Debug)]
 #[clap(
@@ -15,24 +32,148 @@ use std::path::PathBuf;
 pub struct Cli {
     #[clap(subcommand)]
     command: Commands,
+
+    /// Print what would be changed instead of changing it
+    #[clap(long, global = true)]
+    dry_run: bool,
+
+    /// Print --dry-run plans as JSON
+    #[clap(long, global = true, requires = "dry_run")]
+    json: bool,
 }
 
 #[derive(Subcommand, Debug)]
 pub enum Commands {
     /// Install or update moonlight
-    Install { branch: MoonlightBranch },
+    Install {
+        branch: MoonlightBranch,
+
+        /// Skip the download if the latest version is already installed
+        #[clap(long)]
+        if_outdated: bool,
+    },
+
+    /// List detected Discord installs and their IDs
+    List,
+
+    /// Show whether moonlight is loading in patched installs
+    Status {
+        /// Path to the Discord executable, or an install ID from `list`
+        install: Option<String>,
+
+        /// Let moonlight load again after crashes disabled it
+        #[clap(long, requires = "install")]
+        reset: bool,
+    },
 
     /// Patch a Discord install
     Patch {
-        exe: PathBuf,
+        /// Path to the Discord executable, or an install ID from `list`
+        install: String,
 
         /// Path to a custom moonlight build
         #[clap(long, short)]
         moonlight: Option<PathBuf>,
+
+        /// How to put moonlight in front of app.asar. Defaults to whatever
+        /// the install was patched with last, or a folder
+        #[clap(long, short, value_enum)]
+        strategy: Option<PatchStrategy>,
+
+        /// Keep a copy of Discord's app.asar in the moonlight dir, which
+        /// unpatching puts back if the renamed one gets lost
+        #[clap(long, short)]
+        backup_asar: bool,
+
+        /// Close Discord gracefully first and reopen it afterwards
+        #[clap(long, short)]
+        restart: bool,
     },
 
     /// Unpatch a Discord install
-    Unpatch { exe: PathBuf },
+    Unpatch {
+        /// Path to the Discord executable, or an install ID from `list`
+        install: String,
+
+        /// Close Discord gracefully first and reopen it afterwards
+        #[clap(long, short)]
+        restart: bool,
+    },
+
+    /// Repair a half-patched Discord install
+    Repair {
+        /// Path to the Discord executable, or an install ID from `list`
+        install: String,
+
+        /// Path to a custom moonlight build
+        #[clap(long, short)]
+        moonlight: Option<PathBuf>,
+
+        /// Keep a copy of Discord's app.asar in the moonlight dir
+        #[clap(long, short)]
+        backup_asar: bool,
+
+        /// Close Discord gracefully first and reopen it afterwards
+        #[clap(long, short)]
+        restart: bool,
+    },
+
+    /// Regenerate the moonlight stub in a patched install
+    Refresh {
+        /// Path to the Discord executable, or an install ID from `list`
+        install: String,
+
+        /// Path to a custom moonlight build
+        #[clap(long, short)]
+        moonlight: Option<PathBuf>,
+
+        /// Close Discord gracefully first and reopen it afterwards
+        #[clap(long, short)]
+        restart: bool,
+    },
+
+    /// Move an install's moonlight config aside so moonlight starts fresh
+    ResetConfig {
+        /// Path to the Discord executable, or an install ID from `list`
+        install: String,
+    },
+
+    /// Repatch installs that moonlight was patched into before
+    Repatch {
+        /// Path to a custom moonlight build
+        #[clap(long, short)]
+        moonlight: Option<PathBuf>,
+    },
+
+    /// Show what patch operations have been run
+    History,
+
+    /// Revert a patch operation from `history`
+    Undo {
+        /// Entry to undo, defaults to the newest one that can be undone
+        entry: Option<u64>,
+    },
+
+    /// Watch Discord installs and repatch them after Discord updates itself
+    #[cfg(target_os = "linux")]
+    Watch {
+        /// Path to a custom moonlight build
+        #[clap(long, short)]
+        moonlight: Option<PathBuf>,
+    },
+
+    /// Manage the systemd user units that keep moonlight updated
+    #[cfg(target_os = "linux")]
+    Service {
+        #[clap(subcommand)]
+        command: service::ServiceCommand,
+    },
+
+    /// Manage Discord installs that weren't detected automatically
+    Installs {
+        #[clap(subcommand)]
+        command: InstallsCommand,
+    },
 
     /// Generate shell completions
     Completions {
@@ -41,53 +182,582 @@ pub enum Commands {
     },
 }
 
+#[derive(Subcommand, Debug)]
+pub enum InstallsCommand {
+    /// Add a Discord install by its executable
+    Add {
+        exe: PathBuf,
+
+        /// Use this branch instead of guessing
+        #[clap(long, short, value_enum)]
+        branch: Option<Branch>,
+
+        /// Name to show for this install
+        #[clap(long, short)]
+        label: Option<String>,
+    },
+
+    /// Remove a previously added install
+    Remove {
+        /// Path to the Discord executable, or an install ID from `list`
+        install: String,
+    },
+}
+
+// Accepts either a path to a Discord executable or an install ID
+fn resolve_install(installer: &Installer, target: &str) -> eyre::Result<Option<InstallInfo>> {
+    let path = PathBuf::from(target);
+    if path.exists() {
+        let exe = std::fs::canonicalize(&path)?;
+        return Ok(detect_install(&exe));
+    }
+
+    let id: InstallId = target.parse()?;
+    Ok(installer.find_install(&id).ok())
+}
+
+fn run_closed(
+    installer: &Installer,
+    install: &DetectedInstall,
+    restart: bool,
+    op: impl FnOnce(&Installer) -> libmoonlight::Result<()>,
+) -> libmoonlight::Result<()> {
+    if !restart {
+        return op(installer);
+    }
+
+    log::info!("Closing Discord {}", install.branch);
+    installer.with_discord_closed(install, DEFAULT_CLOSE_TIMEOUT, op)
+}
+
+pub fn print_plan(plan: &Plan, json: bool) {
+    if json {
+        println!("{}", plan.to_json());
+    } else {
+        print!("{plan}");
+    }
+}
+
+// Closing and reopening Discord goes around the rest of the plan
+fn with_restart(install: &InstallInfo, restart: bool, plan: Plan) -> Plan {
+    if !restart || !install.running {
+        return plan;
+    }
+
+    let mut full = Plan::from(vec![PlanStep::CloseDiscord {
+        path: install.install.path.clone(),
+        relaunch: true,
+    }]);
+    full.extend(plan);
+    full
+}
+
+// The overrides only cover what Flatpak can express, so say what's missing
+fn warn_flatpak_unsupported(install: &DetectedInstall) {
+    for path in get_flatpak_filesystems_for(install).unsupported {
+        log::warn!(
+            "The Flatpak can't be given access to {:?}, so moonlight may not load - move it somewhere under your home directory",
+            path
+        );
+    }
+}
+
+fn log_repatch_outcomes(outcomes: Vec<RepatchOutcome>) {
+    for outcome in outcomes {
+        match outcome.result {
+            Ok(()) => log::info!(
+                "Discord {} at {:?} was updated, {} it",
+                outcome.install.branch,
+                outcome.install.path,
+                outcome.action
+            ),
+            Err(err) => log::error!(
+                "Discord {} at {:?} was updated but couldn't be {}: {}",
+                outcome.install.branch,
+                outcome.install.path,
+                outcome.action,
+                err
+            ),
+        }
+    }
+}
+
 fn main() -> eyre::Result<()> {
     color_eyre::install()?;
     env_logger::init_from_env(env_logger::Env::new().filter_or("MOONLIGHT_LOG", "info"));
+
+    // Started again by ourselves through pkexec or sudo, see or_privileged
+    if std::env::args_os()
+        .nth(1)
+        .is_some_and(|arg| arg == HELPER_ARG)
+    {
+        return Ok(serve_privileged_helper()?);
+    }
     let cli = Cli::parse();
-    let installer = libmoonlight::Installer::new();
+    let installer = Installer::new();
+    let (dry_run, json) = (cli.dry_run, cli.json);
 
     match cli.command {
-        Commands::Install { branch } => {
+        Commands::Install {
+            branch,
+            if_outdated,
+        } => {
+            if if_outdated {
+                let latest = installer.get_latest_moonlight_version(branch)?;
+                if installer.get_downloaded_version()?.as_deref() == Some(latest.as_str()) {
+                    log::info!("moonlight {} is already up to date", latest);
+                    return Ok(());
+                }
+            }
+
+            if dry_run {
+                print_plan(&installer.plan_download_moonlight(branch), json);
+                return Ok(());
+            }
+
             log::info!("Downloading moonlight branch {}", branch);
             let ver = installer.download_moonlight(branch)?;
             installer.set_downloaded_version(&ver)?;
             log::info!("Downloaded version {}", ver);
         }
 
-        Commands::Patch { exe, moonlight } => {
-            let exe = std::fs::canonicalize(&exe)?;
-            log::info!("Patching install at {:?}", exe);
-            let install = detect_install(&exe);
+        Commands::Patch {
+            install: target,
+            moonlight,
+            strategy,
+            backup_asar,
+            restart,
+        } => {
+            let installer = Installer::new().with_asar_backups(backup_asar);
+            log::info!("Patching install {}", target);
+            let install = resolve_install(&installer, &target)?;
             if let Some(install) = install {
-                if install.patched {
-                    log::warn!("Client mod appears to already be patched - unpatching first");
-                    installer.unpatch_install(&install.install)?;
+                log::info!(
+                    "Detected Discord {} (version {})",
+                    install.install.branch,
+                    install.discord_version.as_deref().unwrap_or("unknown")
+                );
+
+                if install.running && !restart {
+                    log::warn!(
+                        "Discord is running - close it first or pass --restart if patching fails"
+                    );
+                }
+
+                let unpatch_first = match install.patch_state {
+                    PatchState::Unpatched => false,
+                    PatchState::OpenAsar => {
+                        log::info!("OpenAsar detected - moonlight will load on top of it");
+                        false
+                    }
+                    PatchState::Moonlight | PatchState::Vencord => {
+                        log::warn!(
+                            "{} appears to already be patched - unpatching first",
+                            install.patch_state
+                        );
+                        true
+                    }
+                    PatchState::BetterDiscord => {
+                        log::error!("BetterDiscord is installed - remove it with its own installer before patching");
+                        std::process::exit(1);
+                    }
+                    PatchState::Unknown => {
+                        log::error!("Install has an unknown modification - reinstall Discord before patching");
+                        std::process::exit(1);
+                    }
+                    PatchState::Broken(_) => {
+                        log::error!("Install is half-patched - run `moonlight-cli repair` instead");
+                        std::process::exit(1);
+                    }
+                };
+
+                // Unpatching takes the old stub config with it
+                let strategy =
+                    strategy.unwrap_or_else(|| installer.get_patch_strategy(&install.install));
+
+                if dry_run {
+                    let plan = if unpatch_first {
+                        installer.plan_replace_install(&install.install, moonlight, strategy)?
+                    } else {
+                        installer.plan_patch_install(&install.install, moonlight, strategy)?
+                    };
+                    print_plan(&with_restart(&install, restart, plan), json);
+                    return Ok(());
                 }
 
-                installer.patch_install(&install.install, moonlight)?;
-                log::info!("Patched install at {:?}", exe);
+                run_closed(&installer, &install.install, restart, |i| {
+                    // The helper's patch replaces by itself
+                    let result = if unpatch_first {
+                        i.replace_install(&install.install, moonlight.clone(), strategy)
+                    } else {
+                        i.patch_install(&install.install, moonlight.clone(), strategy)
+                    };
+                    i.or_privileged(result, || HelperOp::Patch {
+                        install: install.install.clone(),
+                        dist: moonlight,
+                        strategy,
+                    })
+                })?;
+                log::info!("Patched install {}", target);
+                warn_flatpak_unsupported(&install.install);
             } else {
-                log::error!("Failed to detect install at {:?}", exe);
+                log::error!("Failed to detect install {}", target);
                 std::process::exit(1);
             }
         }
 
-        Commands::Unpatch { exe } => {
-            let exe = std::fs::canonicalize(&exe)?;
-            log::info!("Unpatching install at {:?}", exe);
-            let install = detect_install(&exe);
+        Commands::Unpatch {
+            install: target,
+            restart,
+        } => {
+            log::info!("Unpatching install {}", target);
+            let install = resolve_install(&installer, &target)?;
             if let Some(install) = install {
-                if !install.patched {
-                    log::warn!("Install already unpatched");
+                match install.patch_state {
+                    PatchState::Moonlight | PatchState::Vencord => {}
+                    PatchState::Unpatched | PatchState::OpenAsar => {
+                        log::warn!("Install already unpatched");
+                        std::process::exit(0);
+                    }
+                    PatchState::Broken(BrokenState::MissingPatchedAsar) => {
+                        log::warn!(
+                            "_app.asar is missing - restoring it from the backup if there is one"
+                        );
+                    }
+                    PatchState::Broken(_) => {
+                        log::error!("Install is half-patched - run `moonlight-cli repair` first");
+                        std::process::exit(1);
+                    }
+                    PatchState::BetterDiscord | PatchState::Unknown => {
+                        log::error!(
+                            "Install is modified by {}, which moonlight can't unpatch",
+                            install.patch_state
+                        );
+                        std::process::exit(1);
+                    }
+                }
+
+                if dry_run {
+                    let plan = installer.plan_unpatch_install(&install.install)?;
+                    print_plan(&with_restart(&install, restart, plan), json);
+                    return Ok(());
+                }
+
+                run_closed(&installer, &install.install, restart, |i| {
+                    i.or_privileged(i.unpatch_install(&install.install), || HelperOp::Unpatch {
+                        install: install.install.clone(),
+                    })
+                })?;
+                log::info!("Unpatched install {}", target);
+            } else {
+                log::error!("Failed to detect install {}", target);
+                std::process::exit(1);
+            }
+        }
+
+        Commands::Repair {
+            install: target,
+            moonlight,
+            backup_asar,
+            restart,
+        } => {
+            let installer = Installer::new().with_asar_backups(backup_asar);
+            log::info!("Repairing install {}", target);
+            let install = resolve_install(&installer, &target)?;
+            if let Some(install) = install {
+                let PatchState::Broken(broken) = install.patch_state else {
+                    log::warn!("Install is not broken ({})", install.patch_state);
                     std::process::exit(0);
+                };
+
+                log::info!("{}", broken.description());
+                if dry_run {
+                    let plan = installer.plan_repair_install(&install.install, moonlight)?;
+                    print_plan(&with_restart(&install, restart, plan), json);
+                    return Ok(());
                 }
 
-                installer.unpatch_install(&install.install)?;
-                log::info!("Unpatched install at {:?}", exe);
+                run_closed(&installer, &install.install, restart, |i| {
+                    i.or_privileged(
+                        i.repair_install(&install.install, moonlight.clone()),
+                        || HelperOp::Repair {
+                            install: install.install.clone(),
+                            dist: moonlight,
+                        },
+                    )
+                })?;
+                log::info!("Repaired install {}", target);
+                warn_flatpak_unsupported(&install.install);
             } else {
-                log::error!("Failed to detect install at {:?}", exe);
+                log::error!("Failed to detect install {}", target);
+                std::process::exit(1);
+            }
+        }
+
+        Commands::Refresh {
+            install: target,
+            moonlight,
+            restart,
+        } => {
+            log::info!("Refreshing stub in install {}", target);
+            let Some(install) = resolve_install(&installer, &target)? else {
+                log::error!("Failed to detect install {}", target);
+                std::process::exit(1);
+            };
+
+            if install.patch_state != PatchState::Moonlight {
+                log::error!(
+                    "Install isn't patched with moonlight ({})",
+                    install.patch_state
+                );
+                std::process::exit(1);
+            }
+            if !install.stub_outdated() && moonlight.is_none() {
+                log::info!("Stub is already up to date");
+                return Ok(());
+            }
+
+            if dry_run {
+                let plan = installer.plan_refresh_stub(&install.install, moonlight)?;
+                print_plan(&with_restart(&install, restart, plan), json);
+                return Ok(());
+            }
+
+            run_closed(&installer, &install.install, restart, |i| {
+                i.or_privileged(i.refresh_stub(&install.install, moonlight.clone()), || {
+                    HelperOp::Refresh {
+                        install: install.install.clone(),
+                        dist: moonlight,
+                    }
+                })
+            })?;
+            log::info!("Refreshed stub in install {}", target);
+            warn_flatpak_unsupported(&install.install);
+        }
+
+        Commands::List => {
+            for info in installer.get_installs()? {
+                println!(
+                    "{}  {:<11} {:<9} {:<20} {}{}",
+                    info.install.id,
+                    info.install.branch.to_string(),
+                    info.discord_version.as_deref().unwrap_or("unknown"),
+                    info.patch_state.to_string(),
+                    info.install.path.display(),
+                    info.label
+                        .as_ref()
+                        .map(|l| format!(" ({l})"))
+                        .unwrap_or_default()
+                );
+                if info.stub_outdated() {
+                    println!(
+                        "  stub is outdated, run `moonlight-cli refresh {}`",
+                        info.install.id
+                    );
+                }
+            }
+        }
+
+        Commands::Status {
+            install: Some(target),
+            reset: true,
+        } => {
+            let Some(install) = resolve_install(&installer, &target)? else {
+                log::error!("Failed to detect install {}", target);
+                std::process::exit(1);
+            };
+
+            if dry_run {
+                let plan = LaunchState::path_for(&install.install)
+                    .filter(|path| path.exists())
+                    .map(|path| vec![PlanStep::Remove { path, backup: None }])
+                    .unwrap_or_default();
+                print_plan(&Plan::from(plan), json);
+                return Ok(());
+            }
+
+            installer.reset_launch_state(&install.install)?;
+            log::info!("moonlight will load again next time Discord starts");
+        }
+
+        Commands::Status { install, .. } => {
+            let installs = match install {
+                Some(target) => resolve_install(&installer, &target)?.into_iter().collect(),
+                None => installer.get_installs()?,
+            };
+
+            for info in installs {
+                if info.patch_state != PatchState::Moonlight {
+                    continue;
+                }
+
+                let status = match (&info.launch_state, info.safe_mode_crashes()) {
+                    (_, Some(crashes)) => {
+                        format!("moonlight disabled after {crashes} crashes")
+                    }
+                    (Some(state), None) if state.crashes > 1 => {
+                        format!("{} launches in a row didn't last", state.crashes)
+                    }
+                    (Some(_), None) => String::from("ok"),
+                    (None, None) => String::from("not started since patching"),
+                };
+                println!(
+                    "{}  {:<11} {}",
+                    info.install.id,
+                    info.install.branch.to_string(),
+                    status
+                );
+
+                if let Some(error) = info
+                    .launch_state
+                    .as_ref()
+                    .and_then(|state| state.last_error.as_deref())
+                {
+                    println!("  last error: {}", error.lines().next().unwrap_or_default());
+                }
+                if info.safe_mode_crashes().is_some() {
+                    println!(
+                        "  run `moonlight-cli status --reset {}` to try again",
+                        info.install.id
+                    );
+                }
+            }
+        }
+
+        Commands::Repatch { moonlight } if dry_run => {
+            print_plan(&installer.plan_repatch_installs(moonlight)?, json);
+        }
+
+        Commands::Repatch { moonlight } => {
+            let outcomes = installer.repatch_installs(moonlight)?;
+            if outcomes.is_empty() {
+                log::info!("Nothing to repatch");
+            }
+            log_repatch_outcomes(outcomes);
+        }
+
+        Commands::ResetConfig { install: target } => {
+            let Some(install) = resolve_install(&installer, &target)? else {
+                log::error!("Failed to detect install {}", target);
+                std::process::exit(1);
+            };
+
+            if dry_run {
+                print_plan(&installer.plan_reset_config(install.install.branch), json);
+                return Ok(());
+            }
+            if !install.has_config {
+                log::info!("Install {} has no config to reset", target);
+                return Ok(());
+            }
+
+            let backup = installer.reset_config(install.install.branch)?;
+            log::info!(
+                "Moved the {} config to {:?}",
+                install.install.branch,
+                backup
+            );
+        }
+
+        Commands::History => {
+            for entry in installer.get_history() {
+                println!(
+                    "{:>4}  {:<8} {:<12} {:<9} {}  {}{}",
+                    entry.id,
+                    entry.action.to_string(),
+                    entry.status.to_string(),
+                    libmoonlight::format_age(entry.age()),
+                    entry.install,
+                    entry.path.display(),
+                    entry.error.map(|e| format!(" ({e})")).unwrap_or_default()
+                );
+            }
+        }
+
+        Commands::Undo { entry } if dry_run => {
+            print_plan(&installer.plan_undo(entry)?, json);
+        }
+
+        Commands::Undo { entry } => {
+            let entry = installer.undo(entry)?;
+            log::info!(
+                "Undid {} of {:?} (entry {})",
+                entry.action,
+                entry.path,
+                entry.id
+            );
+        }
+
+        // Watching would only ever repatch, so show what that would do now
+        #[cfg(target_os = "linux")]
+        Commands::Watch { moonlight } if dry_run => {
+            print_plan(&installer.plan_repatch_installs(moonlight)?, json);
+        }
+
+        #[cfg(target_os = "linux")]
+        Commands::Watch { moonlight } => {
+            let mut watcher = libmoonlight::UpdateWatcher::new()?;
+            loop {
+                match installer.repatch_installs(moonlight.clone()) {
+                    Ok(outcomes) => log_repatch_outcomes(outcomes),
+                    Err(err) => log::error!("Failed to check installs: {}", err),
+                }
+
+                let installs = installer.get_installs().unwrap_or_default();
+                let paths =
+                    libmoonlight::get_watch_paths(&installs, &libmoonlight::DetectorEnv::current());
+                for (path, err) in watcher.set_paths(&paths) {
+                    log::warn!("Couldn't watch {:?}: {}", path, err);
+                }
+                if watcher.is_empty() {
+                    log::warn!("Nothing to watch, waiting anyway");
+                    std::thread::sleep(WATCH_IDLE);
+                    continue;
+                }
+
+                log::debug!("Watching {:?}", paths);
+                watcher.wait(WATCH_DEBOUNCE)?;
+            }
+        }
+
+        #[cfg(target_os = "linux")]
+        Commands::Service { command } => service::run(command, dry_run, json)?,
+
+        Commands::Installs {
+            command: InstallsCommand::Add { exe, branch, label },
+        } => {
+            let exe = std::fs::canonicalize(&exe)?;
+            let install = ManualInstall {
+                path: exe.clone(),
+                branch,
+                label,
+            };
+            if dry_run {
+                print_plan(&installer.plan_add_manual_install(&install)?, json);
+                return Ok(());
+            }
+
+            let id = installer.add_manual_install(install)?;
+            log::info!("Added install {} at {:?}", id, exe);
+        }
+
+        Commands::Installs {
+            command: InstallsCommand::Remove { install: target },
+        } => {
+            let Some(install) = resolve_install(&installer, &target)? else {
+                log::error!("Failed to detect install {}", target);
                 std::process::exit(1);
+            };
+            if dry_run {
+                let plan = installer.plan_remove_manual_install(&install.install.id)?;
+                print_plan(&plan, json);
+                return Ok(());
+            }
+
+            if installer.remove_manual_install(&install.install.id)? {
+                log::info!("Removed install {}", install.install.id);
+            } else {
+                log::warn!("Install {} was not added manually", install.install.id);
             }
         }
 
@@ -0,0 +1,164 @@
+use clap::Subcommand;
+use libmoonlight::types::MoonlightBranch;
+use libmoonlight::{
+    get_custom_moonlight_dir, get_self_command, host_command, to_host_path, Plan, PlanStep,
+    ServiceConfig, ServiceManager, SERVICE_NAME,
+};
+use std::path::PathBuf;
+
+#[derive(Subcommand, Debug)]
+pub enum ServiceCommand {
+    /// Write and enable the units
+    Install {
+        /// moonlight branch to keep updated
+        #[clap(default_value = "stable")]
+        branch: MoonlightBranch,
+
+        /// How often to run, as a systemd calendar expression
+        #[clap(long, default_value = "daily", value_parser = parse_on_calendar)]
+        on_calendar: String,
+
+        /// Write units here instead of ~/.config/systemd/user (skips systemctl)
+        #[clap(long)]
+        unit_dir: Option<PathBuf>,
+    },
+
+    /// Disable and remove the units
+    Uninstall {
+        #[clap(long)]
+        unit_dir: Option<PathBuf>,
+    },
+
+    /// Show whether the units are installed and active
+    Status {
+        #[clap(long)]
+        unit_dir: Option<PathBuf>,
+    },
+}
+
+pub fn run(command: ServiceCommand, dry_run: bool, json: bool) -> eyre::Result<()> {
+    match command {
+        ServiceCommand::Install { unit_dir, .. } if dry_run => {
+            let manager = manager(unit_dir.as_ref());
+            let mut plan = manager.plan_install();
+            if unit_dir.is_none() {
+                plan.push(systemctl_step(&["daemon-reload"]));
+                plan.push(systemctl_step(&["enable", "--now", &timer()]));
+            }
+            crate::print_plan(&plan, json);
+        }
+
+        ServiceCommand::Uninstall { unit_dir } if dry_run => {
+            let manager = manager(unit_dir.as_ref());
+            let mut plan = Plan::new();
+            if unit_dir.is_none() && manager.status().timer_installed {
+                plan.push(systemctl_step(&["disable", "--now", &timer()]));
+            }
+            let removals = manager.plan_uninstall();
+            let reload = unit_dir.is_none() && !removals.is_empty();
+            plan.extend(removals);
+            if reload {
+                plan.push(systemctl_step(&["daemon-reload"]));
+            }
+            crate::print_plan(&plan, json);
+        }
+
+        ServiceCommand::Install {
+            branch,
+            on_calendar,
+            unit_dir,
+        } => {
+            let manager = manager(unit_dir.as_ref());
+            let config = ServiceConfig {
+                command: get_self_command()?,
+                branch,
+                on_calendar,
+                moonlight_dir: get_custom_moonlight_dir().as_deref().map(to_host_path),
+            };
+
+            manager.install(&config)?;
+            log::info!("Wrote units to {:?}", manager.unit_dir());
+
+            if unit_dir.is_none() {
+                systemctl(&["daemon-reload"])?;
+                systemctl(&["enable", "--now", &timer()])?;
+                log::info!("Enabled {}", timer());
+            }
+        }
+
+        ServiceCommand::Uninstall { unit_dir } => {
+            let manager = manager(unit_dir.as_ref());
+
+            if unit_dir.is_none() && manager.status().timer_installed {
+                systemctl(&["disable", "--now", &timer()])?;
+            }
+
+            if manager.uninstall()? {
+                log::info!("Removed units from {:?}", manager.unit_dir());
+                if unit_dir.is_none() {
+                    systemctl(&["daemon-reload"])?;
+                }
+            } else {
+                log::warn!("Units weren't installed");
+            }
+        }
+
+        ServiceCommand::Status { unit_dir } => {
+            let manager = manager(unit_dir.as_ref());
+            let status = manager.status();
+            println!("service installed: {}", status.service_installed);
+            println!("timer installed: {}", status.timer_installed);
+
+            if unit_dir.is_none() && status.timer_installed {
+                let output = host_command("systemctl")
+                    .args(["--user", "is-active", &timer()])
+                    .output()?;
+                println!(
+                    "timer active: {}",
+                    String::from_utf8_lossy(&output.stdout).trim()
+                );
+            }
+        }
+    }
+
+    Ok(())
+}
+
+fn parse_on_calendar(expr: &str) -> Result<String, String> {
+    libmoonlight::validate_on_calendar(expr)
+        .map(|()| expr.to_string())
+        .map_err(|e| e.to_string())
+}
+
+fn manager(unit_dir: Option<&PathBuf>) -> ServiceManager {
+    unit_dir.map_or_else(ServiceManager::new, |dir| {
+        ServiceManager::with_unit_dir(dir.clone())
+    })
+}
+
+fn timer() -> String {
+    format!("{SERVICE_NAME}.timer")
+}
+
+fn systemctl_step(args: &[&str]) -> PlanStep {
+    PlanStep::Run {
+        command: ["systemctl", "--user"]
+            .iter()
+            .chain(args)
+            .map(ToString::to_string)
+            .collect(),
+    }
+}
+
+fn systemctl(args: &[&str]) -> eyre::Result<()> {
+    let status = host_command("systemctl")
+        .arg("--user")
+        .args(args)
+        .status()?;
+
+    if !status.success() {
+        eyre::bail!("systemctl --user {} failed with {}", args.join(" "), status);
+    }
+
+    Ok(())
+}
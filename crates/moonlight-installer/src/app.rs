@@ -1,8 +1,19 @@
 use crate::config::Config;
 use crate::logic::{app_logic_thread, LogicCommand, LogicResponse};
-use libmoonlight::types::{Branch, InstallInfo, MoonlightBranch};
-use libmoonlight::MoonlightError;
-use std::time::Duration;
+use libmoonlight::types::{InstallId, InstallInfo, MoonlightBranch, PatchState, PatchStrategy};
+use libmoonlight::{format_age, JournalEntry, ManualInstall, MoonlightError, STUB_VERSION};
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+
+#[derive(Debug, Clone, Copy)]
+enum PatchOp {
+    Patch(PatchStrategy),
+    Unpatch,
+    RemoveAndPatch(PatchStrategy),
+    Repair,
+    RefreshStub,
+}
 
 #[derive(Debug, Default)]
 pub struct AppState {
@@ -15,7 +26,22 @@ pub struct AppState {
 
     patching: bool,
     patching_error: Option<MoonlightError>,
-    patching_branch: Option<Branch>,
+    patching_install: Option<InstallId>,
+    running_warning: Option<(InstallId, PatchOp)>,
+    last_running_check: Option<Instant>,
+    // So checks don't pile up behind a patch on the logic thread
+    running_check_pending: bool,
+    // Picked in the UI, otherwise whatever each install had last
+    strategies: HashMap<InstallId, PatchStrategy>,
+
+    adding_install: bool,
+    add_install_path: String,
+    add_install_label: String,
+    add_install_error: Option<MoonlightError>,
+
+    history: Option<Vec<JournalEntry>>,
+    undoing: bool,
+    undo_error: Option<MoonlightError>,
 }
 
 #[derive(serde::Deserialize, serde::Serialize, Default, Debug)]
@@ -31,12 +57,32 @@ pub struct App {
     state: AppState,
 }
 
+const RUNNING_CHECK_INTERVAL: Duration = Duration::from_secs(2);
+
 // https://github.com/rust-lang/rustfmt/issues/3863
 const PATCH_TOOLIP: &str = "Download moonlight first to patch a Discord installation.";
+const BETTERDISCORD_TOOLTIP: &str =
+    "BetterDiscord is installed. Remove it with BetterDiscord's installer before patching.";
+const UNKNOWN_MOD_TOOLTIP: &str =
+    "This installation was modified by something moonlight doesn't recognize. Reinstall Discord before patching.";
+const REMOVE_INSTALL_TOOLTIP: &str =
+    "Forgets this manually added installation. Nothing is deleted from disk.";
+const REFRESH_STUB_TOOLTIP: &str =
+    "This installation was patched by an older version of the installer. Updates the files moonlight uses to load without unpatching.";
+const SAFE_MODE_TOOLTIP: &str = "moonlight kept crashing Discord on startup, so Discord is being started without it. Re-enable it once moonlight is updated.";
+const FLATPAK_UNSUPPORTED: &str = "This Discord is a Flatpak, and Flatpak has no way of giving it access to these paths, so moonlight may not load. Move them somewhere under your home directory:";
+const STRATEGY_TOOLTIP: &str = "How moonlight is put in front of Discord. Unpatch first to change it for an installation that's already patched.";
 const RESET_CONFIG_TOOLTIP: &str =
     "Backs up and removes the moonlight config file for this Discord installation.";
 const WINDOWS_FILE_LOCK: &str = "Discord is currently open, which locks moonlight's ability to modify its files. Please completely close Discord and make sure it does not appear in the taskbar.\nAlternatively, click the button below to attempt to close Discord forcefully. This will disconnect you from any voice calls you are in and may cause issues.";
 const MACOS_NO_PERMISSION: &str = "moonlight is unable to modify your Discord installation. This is because your MacOS system privacy settings doesn't allow us to do so.\nYou can fix this via a pop-up you should've gotten, or by going to System Settings > Privacy & Security > App Management and allowing moonlight installer.";
+const BROKEN_INSTALL: &str = "This Discord installation is only partially patched, usually because Discord updated while moonlight was installed. Use the Repair button to fix it.";
+const DISCORD_RUNNING: &str = "This Discord installation is currently running. Patching it while it's open may fail or leave it in a broken state. Close Discord first, or continue anyway.";
+const RESTART_TOOLTIP: &str = "Asks Discord to close, waits for it to exit, then reopens it once done. Discord is only force closed if it doesn't exit on its own.";
+const INSTALL_NOT_FOUND: &str = "This Discord installation could not be found anymore. It may have been moved, updated or uninstalled.";
+const PERMISSION_DENIED: &str = "moonlight installer wasn't allowed to modify this Discord installation, even after asking for administrator access. Make sure the password prompt wasn't cancelled.";
+const SANDBOX_PERMISSION: &str = "moonlight installer is running as a Flatpak without access to what it needs to patch Discord. Grant it the permissions below and restart it.";
+const UNDO_TOOLTIP: &str = "Reverts the files this operation changed. Later operations on the same installation have to be undone first.";
 const NETWORK_FAILED: &str = "moonlight is unable to download required files, likely due to a network issue. Please check your internet connection and try again.";
 
 impl App {
@@ -61,6 +107,7 @@ impl App {
         app.send(LogicCommand::GetDownloadedVersion);
         app.send(LogicCommand::GetLatestVersion(app.config.branch));
         app.send(LogicCommand::GetInstalls);
+        app.send(LogicCommand::GetHistory);
 
         app
     }
@@ -75,6 +122,15 @@ impl App {
                     self.state.installs = Some(installs);
                 }
 
+                LogicResponse::Running(running) => {
+                    self.state.running_check_pending = false;
+                    if let Some(installs) = &mut self.state.installs {
+                        for i in installs.iter_mut() {
+                            i.running = running.contains(&i.install.id);
+                        }
+                    }
+                }
+
                 LogicResponse::DownloadedVersion(version) => {
                     log::info!("Downloaded version: {:?}", version);
                     self.state.downloaded_version = Some(version);
@@ -103,39 +159,104 @@ impl App {
                     self.state.downloading = false;
                 }
 
-                LogicResponse::PatchComplete(install_path) => {
-                    log::info!("Patch complete: {:?}", install_path);
-                    if let Ok(install_path) = install_path {
+                LogicResponse::PatchComplete(install_id) => {
+                    log::info!("Patch complete: {:?}", install_id);
+                    if let Ok(id) = install_id {
+                        if let Some(installs) = &mut self.state.installs {
+                            for i in installs.iter_mut() {
+                                if i.install.id == id {
+                                    i.patch_state = PatchState::Moonlight;
+                                    i.stub_version = Some(STUB_VERSION);
+                                    if let Some(&strategy) = self.state.strategies.get(&id) {
+                                        i.patch_strategy = strategy;
+                                    }
+                                    break;
+                                }
+                            }
+                        }
+                        self.state.patching_error = None;
+                    } else {
+                        self.state.patching_error = install_id.err();
+                    }
+
+                    self.state.patching = false;
+                }
+
+                LogicResponse::RepairComplete(install_id) => {
+                    log::info!("Repair complete: {:?}", install_id);
+                    if let Ok(id) = install_id {
                         if let Some(installs) = &mut self.state.installs {
                             for i in installs.iter_mut() {
-                                if i.install.path == install_path {
-                                    i.patched = true;
+                                if i.install.id == id {
+                                    i.patch_state = PatchState::Moonlight;
+                                    i.stub_version = Some(STUB_VERSION);
                                     break;
                                 }
                             }
                         }
                         self.state.patching_error = None;
                     } else {
-                        self.state.patching_error = install_path.err();
+                        self.state.patching_error = install_id.err();
                     }
 
                     self.state.patching = false;
                 }
 
-                LogicResponse::UnpatchComplete(install_path) => {
-                    log::info!("Unpatch complete: {:?}", install_path);
-                    if let Ok(install_path) = install_path {
+                LogicResponse::RefreshComplete(install_id) => {
+                    log::info!("Refresh complete: {:?}", install_id);
+                    if let Ok(id) = install_id {
+                        if let Some(installs) = &mut self.state.installs {
+                            for i in installs.iter_mut() {
+                                if i.install.id == id {
+                                    i.stub_version = Some(STUB_VERSION);
+                                    break;
+                                }
+                            }
+                        }
+                        self.state.patching_error = None;
+                    } else {
+                        self.state.patching_error = install_id.err();
+                    }
+
+                    self.state.patching = false;
+                }
+
+                LogicResponse::AddInstallComplete(result) => {
+                    log::info!("Add install complete: {:?}", result);
+                    if result.is_ok() {
+                        self.state.adding_install = false;
+                        self.state.add_install_path.clear();
+                        self.state.add_install_label.clear();
+                        self.state.add_install_error = None;
+                    } else {
+                        self.state.add_install_error = result.err();
+                    }
+                }
+
+                LogicResponse::History(history) => {
+                    self.state.history = Some(history);
+                }
+
+                LogicResponse::UndoComplete(result) => {
+                    log::info!("Undo complete: {:?}", result);
+                    self.state.undo_error = result.err();
+                    self.state.undoing = false;
+                }
+
+                LogicResponse::UnpatchComplete(install_id) => {
+                    log::info!("Unpatch complete: {:?}", install_id);
+                    if let Ok(id) = install_id {
                         if let Some(installs) = &mut self.state.installs {
                             for i in installs.iter_mut() {
-                                if i.install.path == install_path {
-                                    i.patched = false;
+                                if i.install.id == id {
+                                    i.patch_state = PatchState::Unpatched;
                                     break;
                                 }
                             }
                         }
                         self.state.patching_error = None;
                     } else {
-                        self.state.patching_error = install_path.err();
+                        self.state.patching_error = install_id.err();
                     }
 
                     self.state.patching = false;
@@ -158,8 +279,8 @@ impl App {
                 ui.label(WINDOWS_FILE_LOCK);
 
                 if ui.button("Force close Discord").clicked() {
-                    if let Some(branch) = self.state.patching_branch {
-                        self.send(LogicCommand::KillDiscord(branch));
+                    if let Some(id) = &self.state.patching_install {
+                        self.send(LogicCommand::KillDiscord(id.clone()));
                     }
                 }
             }
@@ -172,6 +293,25 @@ impl App {
                 ui.label(NETWORK_FAILED);
             }
 
+            MoonlightError::BrokenInstall(msg) => {
+                ui.label(BROKEN_INSTALL);
+                ui.label(msg);
+            }
+
+            MoonlightError::InstallNotFound(_) => {
+                ui.label(INSTALL_NOT_FOUND);
+            }
+
+            MoonlightError::PermissionDenied(msg) => {
+                ui.label(PERMISSION_DENIED);
+                ui.label(msg);
+            }
+
+            MoonlightError::SandboxPermission(msg) => {
+                ui.label(SANDBOX_PERMISSION);
+                ui.label(msg);
+            }
+
             MoonlightError::Unknown(msg) => {
                 ui.label("An unknown error occurred. Please report this.");
                 ui.label(msg);
@@ -182,6 +322,147 @@ impl App {
     }
 }
 
+impl App {
+    fn start_patch_op(&mut self, id: InstallId, op: PatchOp, restart: bool) {
+        self.state.patching = true;
+        self.state.patching_install = Some(id.clone());
+        self.state.patching_error = None;
+
+        self.send(match op {
+            PatchOp::Patch(strategy) => LogicCommand::PatchInstall {
+                id,
+                restart,
+                strategy,
+            },
+            PatchOp::Unpatch => LogicCommand::UnpatchInstall { id, restart },
+            PatchOp::RemoveAndPatch(strategy) => LogicCommand::ReplaceInstall {
+                id,
+                restart,
+                strategy,
+            },
+            PatchOp::Repair => LogicCommand::RepairInstall { id, restart },
+            PatchOp::RefreshStub => LogicCommand::RefreshStub { id, restart },
+        });
+    }
+
+    fn draw_running_warning(&mut self, ui: &mut egui::Ui) {
+        let Some((id, op)) = self.state.running_warning.clone() else {
+            return;
+        };
+
+        ui.separator();
+        ui.label(egui::RichText::new(DISCORD_RUNNING).color(egui::Color32::YELLOW));
+        ui.horizontal(|ui| {
+            if ui
+                .button("Close, patch and restart")
+                .on_hover_text(RESTART_TOOLTIP)
+                .clicked()
+            {
+                self.state.running_warning = None;
+                self.start_patch_op(id.clone(), op, true);
+            }
+            if ui.button("Continue anyway").clicked() {
+                self.state.running_warning = None;
+                self.start_patch_op(id, op, false);
+            }
+            if ui.button("Cancel").clicked() {
+                self.state.running_warning = None;
+            }
+        });
+    }
+
+    fn draw_add_install(&mut self, ui: &mut egui::Ui) {
+        if !self.state.adding_install {
+            if ui.button("Add installation...").clicked() {
+                self.state.adding_install = true;
+            }
+            return;
+        }
+
+        ui.separator();
+
+        if let Some(err) = &self.state.add_install_error {
+            self.draw_error(ui, err);
+        }
+
+        egui::Grid::new("add_install_grid").show(ui, |ui| {
+            ui.label("Discord executable:");
+            ui.text_edit_singleline(&mut self.state.add_install_path);
+            ui.end_row();
+
+            ui.label("Label (optional):");
+            ui.text_edit_singleline(&mut self.state.add_install_label);
+            ui.end_row();
+        });
+
+        ui.horizontal(|ui| {
+            let can_add = !self.state.add_install_path.trim().is_empty();
+            if ui.add_enabled(can_add, egui::Button::new("Add")).clicked() {
+                let label = self.state.add_install_label.trim();
+                self.send(LogicCommand::AddInstall(ManualInstall {
+                    path: PathBuf::from(self.state.add_install_path.trim()),
+                    branch: None,
+                    label: (!label.is_empty()).then(|| label.to_string()),
+                }));
+            }
+
+            if ui.button("Cancel").clicked() {
+                self.state.adding_install = false;
+                self.state.add_install_error = None;
+            }
+        });
+    }
+
+    fn draw_history(&mut self, ui: &mut egui::Ui) {
+        if let Some(err) = &self.state.undo_error {
+            self.draw_error(ui, err);
+        }
+
+        let Some(history) = &self.state.history else {
+            ui.spinner();
+            return;
+        };
+
+        if history.is_empty() {
+            ui.label("Nothing has been patched yet.");
+            return;
+        }
+
+        let mut should_undo = None;
+        egui::Grid::new("history_grid").show(ui, |ui| {
+            for entry in history.iter().rev() {
+                ui.label(format_age(entry.age()));
+                ui.label(entry.action.to_string());
+                ui.label(entry.path.to_string_lossy());
+
+                let status = ui.label(entry.status.to_string());
+                if let Some(error) = &entry.error {
+                    status.on_hover_text(error);
+                }
+
+                if entry.can_undo() {
+                    let can_undo = !self.state.patching && !self.state.undoing;
+                    if ui
+                        .add_enabled(can_undo, egui::Button::new("Undo"))
+                        .on_hover_text(UNDO_TOOLTIP)
+                        .clicked()
+                    {
+                        should_undo = Some(entry.id);
+                    }
+                }
+
+                ui.end_row();
+            }
+        });
+
+        if let Some(id) = should_undo {
+            self.state.undoing = true;
+            self.state.undo_error = None;
+            self.send(LogicCommand::Undo(id));
+        }
+    }
+}
+
 impl eframe::App for App {
     fn save(&mut self, storage: &mut dyn eframe::Storage) {
         eframe::set_value(storage, eframe::APP_KEY, self);
@@ -293,37 +574,170 @@ impl eframe::App for App {
 
                                 // lmao this is so jank I hate the borrow checker
                                 let mut should_patch = Vec::new();
-                                let mut should_unpatch = Vec::new();
                                 let mut should_reset_config = Vec::new();
+                                let mut should_remove = Vec::new();
+                                let mut should_reenable = Vec::new();
 
                                 egui::Grid::new("install_grid").show(ui, |ui| {
                                     for install in self.state.installs.as_ref().unwrap() {
-                                        let patch_button = egui::Button::new(if install.patched {
-                                            "Unpatch"
-                                        } else {
-                                            "Patch"
+                                        let state = install.patch_state;
+                                        let patch_button = egui::Button::new(match state {
+                                            PatchState::Moonlight => "Unpatch",
+                                            PatchState::Vencord => "Remove and patch",
+                                            PatchState::Broken(_) => "Repair",
+                                            _ => "Patch",
                                         });
+                                        let downloaded = self.state.downloaded_version.is_some()
+                                            && self.state.downloaded_version != Some(None);
                                         let can_patch = !self.state.patching
-                                            && (self.state.downloaded_version.is_some()
-                                                && self.state.downloaded_version != Some(None));
+                                            && match state {
+                                                PatchState::Moonlight => true,
+                                                PatchState::BetterDiscord | PatchState::Unknown => {
+                                                    false
+                                                }
+                                                _ => downloaded,
+                                            };
 
                                         let reset_config_button = egui::Button::new("Reset config");
                                         let can_reset_config = install.has_config;
 
-                                        ui.label(format!("{:?}", install.install.branch))
+                                        ui.horizontal(|ui| {
+                                            ui.label(install.label.clone().unwrap_or_else(|| {
+                                                format!("{:?}", install.install.branch)
+                                            }))
                                             .on_hover_text(install.install.path.to_string_lossy());
 
+                                            if install.running {
+                                                ui.label(
+                                                    egui::RichText::new("running")
+                                                        .small()
+                                                        .color(egui::Color32::LIGHT_GREEN),
+                                                )
+                                                .on_hover_text("Discord is currently open");
+                                            }
+
+                                            if let Some(crashes) = install.safe_mode_crashes() {
+                                                let error = install
+                                                    .launch_state
+                                                    .as_ref()
+                                                    .and_then(|s| s.last_error.as_deref())
+                                                    .unwrap_or(SAFE_MODE_TOOLTIP);
+                                                ui.label(
+                                                    egui::RichText::new(format!(
+                                                        "moonlight disabled after {crashes} crashes"
+                                                    ))
+                                                    .small()
+                                                    .color(egui::Color32::RED),
+                                                )
+                                                .on_hover_text(error);
+
+                                                if ui
+                                                    .small_button("Re-enable")
+                                                    .on_hover_text(SAFE_MODE_TOOLTIP)
+                                                    .clicked()
+                                                {
+                                                    should_reenable
+                                                        .push(install.install.id.clone());
+                                                }
+                                            }
+
+                                            if !install.flatpak_unsupported.is_empty() {
+                                                let paths = install
+                                                    .flatpak_unsupported
+                                                    .iter()
+                                                    .map(|path| path.to_string_lossy())
+                                                    .collect::<Vec<_>>()
+                                                    .join("\n");
+                                                ui.label(
+                                                    egui::RichText::new(
+                                                        "Flatpak can't access moonlight",
+                                                    )
+                                                    .small()
+                                                    .color(egui::Color32::YELLOW),
+                                                )
+                                                .on_hover_text(format!(
+                                                    "{FLATPAK_UNSUPPORTED}\n{paths}"
+                                                ));
+                                            }
+                                        });
+                                        ui.label(
+                                            install.discord_version.as_deref().unwrap_or("Unknown"),
+                                        )
+                                        .on_hover_text("Discord version");
+
+                                        let state_label = ui.label(state.name());
+                                        if let PatchState::Broken(broken) = state {
+                                            state_label.on_hover_text(broken.description());
+                                        }
+
+                                        // Only matters when patching, anything else
+                                        // shows what it was patched with
+                                        let can_pick_strategy = matches!(
+                                            state,
+                                            PatchState::Unpatched
+                                                | PatchState::OpenAsar
+                                                | PatchState::Vencord
+                                        );
+                                        let mut strategy = if can_pick_strategy {
+                                            *self
+                                                .state
+                                                .strategies
+                                                .entry(install.install.id.clone())
+                                                .or_insert(install.patch_strategy)
+                                        } else {
+                                            install.patch_strategy
+                                        };
+                                        ui.add_enabled_ui(
+                                            can_pick_strategy && !self.state.patching,
+                                            |ui| {
+                                                egui::ComboBox::from_id_salt(&install.install.id)
+                                                    .selected_text(strategy.name())
+                                                    .show_ui(ui, |ui| {
+                                                        for option in [
+                                                            PatchStrategy::Folder,
+                                                            PatchStrategy::Asar,
+                                                        ] {
+                                                            ui.selectable_value(
+                                                                &mut strategy,
+                                                                option,
+                                                                option.name(),
+                                                            )
+                                                            .on_hover_text(option.description());
+                                                        }
+                                                    })
+                                                    .response
+                                                    .on_hover_text(STRATEGY_TOOLTIP);
+                                            },
+                                        );
+                                        if can_pick_strategy {
+                                            self.state
+                                                .strategies
+                                                .insert(install.install.id.clone(), strategy);
+                                        }
+
                                         let patch_clicked = ui
                                             .add_enabled(can_patch, patch_button)
-                                            .on_disabled_hover_text(PATCH_TOOLIP)
+                                            .on_disabled_hover_text(match state {
+                                                PatchState::BetterDiscord => BETTERDISCORD_TOOLTIP,
+                                                PatchState::Unknown => UNKNOWN_MOD_TOOLTIP,
+                                                _ => PATCH_TOOLIP,
+                                            })
                                             .clicked();
 
                                         if patch_clicked {
-                                            if install.patched {
-                                                should_unpatch.push(install.install.clone());
-                                            } else {
-                                                should_patch.push(install.install.clone());
-                                            }
+                                            let op = match state {
+                                                PatchState::Moonlight => PatchOp::Unpatch,
+                                                PatchState::Vencord => {
+                                                    PatchOp::RemoveAndPatch(strategy)
+                                                }
+                                                PatchState::Broken(_) => PatchOp::Repair,
+                                                _ => PatchOp::Patch(strategy),
+                                            };
+                                            should_patch.push((
+                                                install.install.id.clone(),
+                                                op,
+                                                install.running,
+                                            ));
                                         }
 
                                         let reset_config_clicked = ui
@@ -331,41 +745,93 @@ impl eframe::App for App {
                                             .on_hover_text(RESET_CONFIG_TOOLTIP)
                                             .clicked();
                                         if reset_config_clicked {
-                                            should_reset_config.push(install.install.branch);
+                                            should_reset_config.push(install.install.id.clone());
+                                        }
+
+                                        if install.manually_added
+                                            && ui
+                                                .button("Remove")
+                                                .on_hover_text(REMOVE_INSTALL_TOOLTIP)
+                                                .clicked()
+                                        {
+                                            should_remove.push(install.install.id.clone());
+                                        }
+
+                                        if install.stub_outdated()
+                                            && ui
+                                                .add_enabled(
+                                                    !self.state.patching,
+                                                    egui::Button::new("Update stub"),
+                                                )
+                                                .on_hover_text(REFRESH_STUB_TOOLTIP)
+                                                .clicked()
+                                        {
+                                            should_patch.push((
+                                                install.install.id.clone(),
+                                                PatchOp::RefreshStub,
+                                                install.running,
+                                            ));
                                         }
 
                                         ui.end_row();
                                     }
                                 });
 
-                                for install in should_patch {
-                                    self.state.patching = true;
-                                    self.state.patching_branch = Some(install.branch);
-                                    self.state.patching_error = None;
-                                    self.send(LogicCommand::PatchInstall(install));
+                                for (id, op, running) in should_patch {
+                                    if running {
+                                        self.state.running_warning = Some((id, op));
+                                    } else {
+                                        self.start_patch_op(id, op, false);
+                                    }
                                 }
-                                for install in should_unpatch {
-                                    self.state.patching = true;
-                                    self.state.patching_branch = Some(install.branch);
-                                    self.state.patching_error = None;
-                                    self.send(LogicCommand::UnpatchInstall(install));
+                                for id in should_reset_config {
+                                    let installs = self.state.installs.as_mut().unwrap();
+                                    // Configs are per branch, so this resets every
+                                    // install sharing it
+                                    if let Some(branch) = installs
+                                        .iter()
+                                        .find(|i| i.install.id == id)
+                                        .map(|i| i.install.branch)
+                                    {
+                                        for i in installs.iter_mut() {
+                                            if i.install.branch == branch {
+                                                i.has_config = false;
+                                            }
+                                        }
+                                    }
+                                    self.send(LogicCommand::ResetConfig(id));
                                 }
-                                for branch in should_reset_config {
-                                    self.send(LogicCommand::ResetConfig(branch));
-                                    self.state
-                                        .installs
-                                        .as_mut()
-                                        .unwrap()
-                                        .iter_mut()
-                                        .find(|i| i.install.branch == branch)
-                                        .unwrap()
-                                        .has_config = false;
+                                for id in should_remove {
+                                    self.send(LogicCommand::RemoveInstall(id));
                                 }
+                                for id in should_reenable {
+                                    self.send(LogicCommand::ResetLaunchState(id));
+                                }
+
+                                self.draw_running_warning(ui);
+                                self.draw_add_install(ui);
                             });
                         });
+
+                    egui::CollapsingHeader::new("History").show(ui, |ui| {
+                        self.draw_history(ui);
+                    });
                 });
         });
 
+        let check_running = self
+            .state
+            .last_running_check
+            .is_none_or(|last| last.elapsed() >= RUNNING_CHECK_INTERVAL);
+        if check_running && !self.state.running_check_pending {
+            if let Some(installs) = &self.state.installs {
+                let installs = installs.iter().map(|i| i.install.clone()).collect();
+                self.state.last_running_check = Some(Instant::now());
+                self.state.running_check_pending = true;
+                self.send(LogicCommand::GetRunning(installs));
+            }
+        }
+
         // Since we're receiving messages on the UI thread, we need to be
         // repainting at least sometimes so the UI can update
         self.handle_messages();
@@ -1,25 +1,65 @@
-use libmoonlight::types::{Branch, DetectedInstall, InstallInfo, MoonlightBranch};
-use libmoonlight::Installer;
-use std::path::PathBuf;
+use libmoonlight::types::{
+    DetectedInstall, InstallId, InstallInfo, MoonlightBranch, PatchStrategy,
+};
+use libmoonlight::{
+    close_discord, HelperOp, Installer, JournalEntry, ManualInstall, ProcessInspector,
+    DEFAULT_CLOSE_TIMEOUT,
+};
+use std::time::Duration;
 
 pub enum LogicCommand {
     GetInstalls,
+    // Only looks at the installs we already know about
+    GetRunning(Vec<DetectedInstall>),
     GetDownloadedVersion,
     GetLatestVersion(MoonlightBranch),
     UpdateMoonlight(MoonlightBranch),
-    PatchInstall(DetectedInstall),
-    UnpatchInstall(DetectedInstall),
-    KillDiscord(Branch),
-    ResetConfig(Branch),
+    // restart closes Discord gracefully first and reopens it afterwards
+    PatchInstall {
+        id: InstallId,
+        restart: bool,
+        strategy: PatchStrategy,
+    },
+    UnpatchInstall {
+        id: InstallId,
+        restart: bool,
+    },
+    // Unpatches another client mod, then patches moonlight in
+    ReplaceInstall {
+        id: InstallId,
+        restart: bool,
+        strategy: PatchStrategy,
+    },
+    RepairInstall {
+        id: InstallId,
+        restart: bool,
+    },
+    RefreshStub {
+        id: InstallId,
+        restart: bool,
+    },
+    KillDiscord(InstallId),
+    ResetConfig(InstallId),
+    ResetLaunchState(InstallId),
+    AddInstall(ManualInstall),
+    RemoveInstall(InstallId),
+    GetHistory,
+    Undo(u64),
 }
 
 pub enum LogicResponse {
     Installs(Vec<InstallInfo>),
+    Running(Vec<InstallId>),
     DownloadedVersion(Option<String>),
     LatestVersion(libmoonlight::Result<String>),
     UpdateComplete(libmoonlight::Result<String>),
-    PatchComplete(libmoonlight::Result<PathBuf>),
-    UnpatchComplete(libmoonlight::Result<PathBuf>),
+    PatchComplete(libmoonlight::Result<InstallId>),
+    UnpatchComplete(libmoonlight::Result<InstallId>),
+    RepairComplete(libmoonlight::Result<InstallId>),
+    RefreshComplete(libmoonlight::Result<InstallId>),
+    AddInstallComplete(libmoonlight::Result<InstallId>),
+    History(Vec<JournalEntry>),
+    UndoComplete(libmoonlight::Result<JournalEntry>),
 }
 
 pub fn app_logic_thread(
@@ -45,6 +85,11 @@ pub fn app_logic_thread(
                 tx.send(LogicResponse::Installs(installs))?;
             }
 
+            LogicCommand::GetRunning(installs) => {
+                let running = ProcessInspector::new().find_running(&installs);
+                tx.send(LogicResponse::Running(running))?;
+            }
+
             LogicCommand::UpdateMoonlight(branch) => {
                 let err = installer.download_moonlight(branch);
                 if let Ok(ref version) = err {
@@ -53,25 +98,180 @@ pub fn app_logic_thread(
                 tx.send(LogicResponse::UpdateComplete(err))?;
             }
 
-            LogicCommand::PatchInstall(install) => {
+            LogicCommand::PatchInstall {
+                id,
+                restart,
+                strategy,
+            } => {
                 let resp = installer
-                    .patch_install(&install, None)
-                    .map(|()| install.path);
+                    .find_install(&id)
+                    .and_then(|info| {
+                        run_closed(&installer, &info.install, restart, |i| {
+                            i.or_privileged(i.patch_install(&info.install, None, strategy), || {
+                                HelperOp::Patch {
+                                    install: info.install.clone(),
+                                    dist: None,
+                                    strategy,
+                                }
+                            })
+                        })
+                    })
+                    .map(|()| id);
                 tx.send(LogicResponse::PatchComplete(resp))?;
+                tx.send(LogicResponse::History(installer.get_history()))?;
             }
 
-            LogicCommand::UnpatchInstall(install) => {
-                let resp = installer.unpatch_install(&install).map(|()| install.path);
+            LogicCommand::UnpatchInstall { id, restart } => {
+                let resp = installer
+                    .find_install(&id)
+                    .and_then(|info| {
+                        run_closed(&installer, &info.install, restart, |i| {
+                            i.or_privileged(i.unpatch_install(&info.install), || {
+                                HelperOp::Unpatch {
+                                    install: info.install.clone(),
+                                }
+                            })
+                        })
+                    })
+                    .map(|()| id);
                 tx.send(LogicResponse::UnpatchComplete(resp))?;
+                tx.send(LogicResponse::History(installer.get_history()))?;
             }
 
-            LogicCommand::KillDiscord(branch) => {
-                let _ = branch.kill_discord();
+            LogicCommand::ReplaceInstall {
+                id,
+                restart,
+                strategy,
+            } => {
+                let resp = installer
+                    .find_install(&id)
+                    .and_then(|info| {
+                        run_closed(&installer, &info.install, restart, |i| {
+                            // The helper's patch replaces by itself
+                            let result = i.replace_install(&info.install, None, strategy);
+                            i.or_privileged(result, || HelperOp::Patch {
+                                install: info.install.clone(),
+                                dist: None,
+                                strategy,
+                            })
+                        })
+                    })
+                    .map(|()| id);
+                tx.send(LogicResponse::PatchComplete(resp))?;
+                tx.send(LogicResponse::History(installer.get_history()))?;
+            }
+
+            LogicCommand::RepairInstall { id, restart } => {
+                let resp = installer
+                    .find_install(&id)
+                    .and_then(|info| {
+                        run_closed(&installer, &info.install, restart, |i| {
+                            i.or_privileged(i.repair_install(&info.install, None), || {
+                                HelperOp::Repair {
+                                    install: info.install.clone(),
+                                    dist: None,
+                                }
+                            })
+                        })
+                    })
+                    .map(|()| id);
+                tx.send(LogicResponse::RepairComplete(resp))?;
+                tx.send(LogicResponse::History(installer.get_history()))?;
+            }
+
+            LogicCommand::RefreshStub { id, restart } => {
+                let resp = installer
+                    .find_install(&id)
+                    .and_then(|info| {
+                        run_closed(&installer, &info.install, restart, |i| {
+                            i.or_privileged(i.refresh_stub(&info.install, None), || {
+                                HelperOp::Refresh {
+                                    install: info.install.clone(),
+                                    dist: None,
+                                }
+                            })
+                        })
+                    })
+                    .map(|()| id);
+                tx.send(LogicResponse::RefreshComplete(resp))?;
+                tx.send(LogicResponse::History(installer.get_history()))?;
             }
 
-            LogicCommand::ResetConfig(branch) => {
-                installer.reset_config(branch);
+            LogicCommand::KillDiscord(id) => {
+                if let Ok(info) = installer.find_install(&id) {
+                    // Fall back to killing by name where we can't see processes
+                    if !matches!(close_discord(&info.install, Duration::ZERO), Ok(Some(_))) {
+                        let _ = info.install.branch.kill_discord();
+                    }
+                }
+            }
+
+            LogicCommand::ResetConfig(id) => {
+                if let Err(err) = installer
+                    .find_install(&id)
+                    .and_then(|info| installer.reset_config(info.install.branch))
+                {
+                    log::error!("Failed to reset config for {}: {:?}", id, err);
+                }
+            }
+
+            LogicCommand::ResetLaunchState(id) => {
+                if let Err(err) = installer
+                    .find_install(&id)
+                    .and_then(|info| installer.reset_launch_state(&info.install))
+                {
+                    log::error!("Failed to re-enable moonlight for {}: {:?}", id, err);
+                }
+
+                let installs = installer.get_installs().unwrap_or_default();
+                tx.send(LogicResponse::Installs(installs))?;
+            }
+
+            LogicCommand::AddInstall(install) => {
+                let resp = installer.add_manual_install(install);
+                let added = resp.is_ok();
+                tx.send(LogicResponse::AddInstallComplete(resp))?;
+
+                if added {
+                    let installs = installer.get_installs().unwrap_or_default();
+                    tx.send(LogicResponse::Installs(installs))?;
+                }
+            }
+
+            LogicCommand::RemoveInstall(id) => {
+                if let Err(err) = installer.remove_manual_install(&id) {
+                    log::error!("Failed to remove install {}: {:?}", id, err);
+                }
+
+                let installs = installer.get_installs().unwrap_or_default();
+                tx.send(LogicResponse::Installs(installs))?;
+            }
+
+            LogicCommand::GetHistory => {
+                tx.send(LogicResponse::History(installer.get_history()))?;
+            }
+
+            LogicCommand::Undo(entry) => {
+                let resp = installer.undo(Some(entry));
+                tx.send(LogicResponse::UndoComplete(resp))?;
+
+                let installs = installer.get_installs().unwrap_or_default();
+                tx.send(LogicResponse::Installs(installs))?;
+                tx.send(LogicResponse::History(installer.get_history()))?;
             }
         }
     }
 }
+
+fn run_closed<T>(
+    installer: &Installer,
+    install: &DetectedInstall,
+    restart: bool,
+    op: impl FnOnce(&Installer) -> libmoonlight::Result<T>,
+) -> libmoonlight::Result<T> {
+    if restart {
+        installer.with_discord_closed(install, DEFAULT_CLOSE_TIMEOUT, op)
+    } else {
+        op(installer)
+    }
+}
@@ -7,6 +7,18 @@ pub mod logic;
 fn main() -> eframe::Result {
     env_logger::init();
 
+    // Started again by ourselves through pkexec to patch a root-owned install
+    if std::env::args_os()
+        .nth(1)
+        .is_some_and(|arg| arg == libmoonlight::HELPER_ARG)
+    {
+        if let Err(err) = libmoonlight::serve_privileged_helper() {
+            log::error!("{err}");
+            std::process::exit(1);
+        }
+        return Ok(());
+    }
+
     let native_options = eframe::NativeOptions {
         viewport: egui::ViewportBuilder::default()
             .with_inner_size([500.0, 250.0])